@@ -0,0 +1 @@
+ALTER TABLE transactions ADD voided BOOLEAN DEFAULT 0 NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE transactions DROP COLUMN category;
+DROP TABLE transaction_tags;
+DROP TABLE categories;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS categories (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    parent INTEGER REFERENCES categories (id) ON DELETE SET NULL,
+    name VARCHAR NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS transaction_tags (
+    transaction_id INTEGER REFERENCES transactions (id) ON DELETE CASCADE,
+    tag VARCHAR NOT NULL,
+    PRIMARY KEY (transaction_id, tag)
+);
+
+ALTER TABLE transactions ADD category INTEGER REFERENCES categories (id) ON DELETE SET NULL;
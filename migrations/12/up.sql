@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS budgets (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    category INTEGER REFERENCES categories (id) ON DELETE CASCADE,
+    account INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    amount BIGINT NOT NULL,
+    rollover BOOLEAN DEFAULT 0 NOT NULL,
+    start DATE NOT NULL
+);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS scheduled_transactions (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    f INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    t INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    amount BIGINT NOT NULL,
+    reason TEXT,
+    category INTEGER REFERENCES categories (id) ON DELETE SET NULL,
+    tags VARCHAR DEFAULT '' NOT NULL,
+    recurrence INTEGER NOT NULL,
+    start_date DATE NOT NULL,
+    end_date DATE,
+    occurrences INTEGER DEFAULT 0 NOT NULL,
+    next_date DATE
+);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS import_profiles (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    delimiter VARCHAR DEFAULT ',' NOT NULL,
+    has_header BOOLEAN DEFAULT 1 NOT NULL,
+    date_column INTEGER NOT NULL,
+    date_format VARCHAR NOT NULL,
+    amount_column INTEGER NOT NULL,
+    sign INTEGER NOT NULL,
+    credit_column INTEGER,
+    description_column INTEGER NOT NULL,
+    balance_column INTEGER
+);
+
+ALTER TABLE transactions ADD import_hash VARCHAR;
+CREATE INDEX IF NOT EXISTS transactions_import_hash ON transactions (import_hash);
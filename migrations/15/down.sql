@@ -0,0 +1,5 @@
+DROP TABLE exchange_rates;
+ALTER TABLE transactions DROP COLUMN to_amount;
+ALTER TABLE transactions DROP COLUMN currency;
+ALTER TABLE accounts DROP COLUMN currency;
+ALTER TABLE users DROP COLUMN home_currency;
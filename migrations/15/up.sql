@@ -0,0 +1,14 @@
+ALTER TABLE users ADD home_currency VARCHAR DEFAULT 'USD' NOT NULL;
+ALTER TABLE accounts ADD currency VARCHAR DEFAULT 'USD' NOT NULL;
+ALTER TABLE transactions ADD currency VARCHAR DEFAULT 'USD' NOT NULL;
+ALTER TABLE transactions ADD to_amount INTEGER;
+
+CREATE TABLE IF NOT EXISTS exchange_rates (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    base VARCHAR NOT NULL,
+    quote VARCHAR NOT NULL,
+    date DATE NOT NULL,
+    rate REAL NOT NULL,
+    UNIQUE (owner, base, quote, date)
+);
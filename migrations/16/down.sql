@@ -0,0 +1,4 @@
+DROP TRIGGER balance_snapshots_delete;
+DROP TRIGGER balance_snapshots_update;
+DROP TRIGGER balance_snapshots_insert;
+DROP TABLE balance_snapshots;
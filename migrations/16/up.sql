@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS balance_snapshots (
+    account INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    date DATE NOT NULL,
+    balance INTEGER NOT NULL,
+    PRIMARY KEY (account, date)
+);
+
+-- Snapshots are sums of transactions, so any change to a transaction
+-- invalidates them from its date on
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_insert AFTER INSERT ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE account IN (NEW.f, NEW.t) AND date >= date(NEW.time);
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_update AFTER UPDATE ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE (account IN (OLD.f, OLD.t) AND date >= date(OLD.time)) OR (account IN (NEW.f, NEW.t) AND date >= date(NEW.time));
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_delete AFTER DELETE ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE account IN (OLD.f, OLD.t) AND date >= date(OLD.time);
+END;
@@ -0,0 +1,7 @@
+DROP TRIGGER balance_snapshots_update;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_update AFTER UPDATE ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE (account IN (OLD.f, OLD.t) AND date >= date(OLD.time)) OR (account IN (NEW.f, NEW.t) AND date >= date(NEW.time));
+END;
+
+ALTER TABLE transactions DROP COLUMN t_status;
+ALTER TABLE transactions DROP COLUMN f_status;
@@ -0,0 +1,9 @@
+-- Each side of a transaction is checked against its own account's statements
+ALTER TABLE transactions ADD f_status INTEGER DEFAULT 0 NOT NULL;
+ALTER TABLE transactions ADD t_status INTEGER DEFAULT 0 NOT NULL;
+
+-- Marking transactions cleared doesn't change balances
+DROP TRIGGER balance_snapshots_update;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_update AFTER UPDATE OF f, t, amount, to_amount, voided, time ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE (account IN (OLD.f, OLD.t) AND date >= date(OLD.time)) OR (account IN (NEW.f, NEW.t) AND date >= date(NEW.time));
+END;
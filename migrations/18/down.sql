@@ -0,0 +1,54 @@
+-- Split transactions keep only their first leg out and first leg in
+DROP TRIGGER balance_snapshots_transaction;
+DROP TRIGGER balance_snapshots_delete;
+DROP TRIGGER balance_snapshots_update;
+DROP TRIGGER balance_snapshots_insert;
+DROP VIEW transaction_summaries;
+DELETE FROM balance_snapshots;
+
+ALTER TABLE accounts ADD amount BIGINT DEFAULT 0 NOT NULL;
+UPDATE accounts SET amount = (SELECT balance FROM account_balances WHERE account = accounts.id);
+DROP VIEW account_balances;
+
+CREATE TABLE transactions_old (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    f INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    t INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    amount BIGINT NOT NULL,
+    reason TEXT,
+    time DATETIME NOT NULL,
+    voided BOOLEAN DEFAULT 0 NOT NULL,
+    category INTEGER REFERENCES categories (id) ON DELETE SET NULL,
+    import_hash VARCHAR,
+    currency VARCHAR DEFAULT 'USD' NOT NULL,
+    to_amount INTEGER,
+    f_status INTEGER DEFAULT 0 NOT NULL,
+    t_status INTEGER DEFAULT 0 NOT NULL
+);
+INSERT INTO transactions_old (id, owner, f, t, amount, reason, time, voided, category, import_hash, currency, to_amount, f_status, t_status)
+    SELECT transactions.id, transactions.owner, fp.account, tp.account, -fp.amount, transactions.reason, transactions.time, transactions.voided, COALESCE(tp.category, fp.category), transactions.import_hash, fp.currency, CASE WHEN fp.currency = tp.currency THEN NULL ELSE tp.amount END, fp.status, tp.status
+    FROM transactions
+    JOIN postings fp ON fp.id = (SELECT id FROM postings WHERE transaction_id = transactions.id ORDER BY amount, id LIMIT 1)
+    JOIN postings tp ON tp.id = (SELECT id FROM postings WHERE transaction_id = transactions.id ORDER BY amount DESC, id LIMIT 1);
+-- As on the way up, nothing that cascades may reference the table being dropped
+DROP INDEX postings_account;
+DROP INDEX postings_transaction;
+DROP TABLE postings;
+CREATE TEMP TABLE migrated_tags AS SELECT transaction_id, tag FROM transaction_tags;
+DROP TABLE transactions;
+ALTER TABLE transactions_old RENAME TO transactions;
+CREATE INDEX IF NOT EXISTS transactions_import_hash ON transactions (import_hash);
+DELETE FROM transaction_tags;
+INSERT INTO transaction_tags (transaction_id, tag) SELECT transaction_id, tag FROM migrated_tags WHERE transaction_id IN (SELECT id FROM transactions);
+DROP TABLE migrated_tags;
+
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_insert AFTER INSERT ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE account IN (NEW.f, NEW.t) AND date >= date(NEW.time);
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_update AFTER UPDATE OF f, t, amount, to_amount, voided, time ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE (account IN (OLD.f, OLD.t) AND date >= date(OLD.time)) OR (account IN (NEW.f, NEW.t) AND date >= date(NEW.time));
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_delete AFTER DELETE ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE account IN (OLD.f, OLD.t) AND date >= date(OLD.time);
+END;
@@ -0,0 +1,95 @@
+-- Migrations run inside a transaction, where PRAGMA foreign_keys can't be
+-- changed. So that dropping the old transactions table cascades into nothing
+-- whether or not foreign keys are enforced, the legs are staged and the tags
+-- set aside until the rebuilt table is in place.
+CREATE TEMP TABLE migrated_legs (
+    transaction_id INTEGER,
+    account INTEGER,
+    amount BIGINT,
+    currency VARCHAR,
+    category INTEGER,
+    status INTEGER
+);
+INSERT INTO migrated_legs (transaction_id, account, amount, currency, category, status)
+    SELECT id, f, -amount, currency, category, f_status FROM transactions;
+INSERT INTO migrated_legs (transaction_id, account, amount, currency, category, status)
+    SELECT transactions.id, transactions.t, COALESCE(transactions.to_amount, transactions.amount), CASE WHEN transactions.to_amount IS NULL THEN transactions.currency ELSE accounts.currency END, transactions.category, transactions.t_status
+    FROM transactions JOIN accounts ON accounts.id = transactions.t;
+CREATE TEMP TABLE migrated_tags AS SELECT transaction_id, tag FROM transaction_tags;
+
+-- The accounts, amounts and categories now live in the postings
+DROP TRIGGER balance_snapshots_insert;
+DROP TRIGGER balance_snapshots_update;
+DROP TRIGGER balance_snapshots_delete;
+CREATE TABLE transactions_new (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    reason TEXT,
+    time DATETIME NOT NULL,
+    voided BOOLEAN DEFAULT 0 NOT NULL,
+    import_hash VARCHAR
+);
+INSERT INTO transactions_new (id, owner, reason, time, voided, import_hash)
+    SELECT id, owner, reason, time, voided, import_hash FROM transactions;
+DROP TABLE transactions;
+ALTER TABLE transactions_new RENAME TO transactions;
+CREATE INDEX IF NOT EXISTS transactions_import_hash ON transactions (import_hash);
+
+DELETE FROM transaction_tags;
+INSERT INTO transaction_tags (transaction_id, tag) SELECT transaction_id, tag FROM migrated_tags WHERE transaction_id IN (SELECT id FROM transactions);
+DROP TABLE migrated_tags;
+
+-- Each leg of a transaction moves money into (positive) or out of (negative)
+-- one account, and a transaction's legs balance. Legs with the outside money
+-- account carry the category of what was spent or earned.
+CREATE TABLE IF NOT EXISTS postings (
+    id INTEGER PRIMARY KEY,
+    transaction_id INTEGER REFERENCES transactions (id) ON DELETE CASCADE,
+    account INTEGER REFERENCES accounts (id) ON DELETE CASCADE,
+    amount BIGINT NOT NULL,
+    currency VARCHAR NOT NULL,
+    category INTEGER REFERENCES categories (id) ON DELETE SET NULL,
+    status INTEGER DEFAULT 0 NOT NULL
+);
+CREATE INDEX IF NOT EXISTS postings_transaction ON postings (transaction_id);
+CREATE INDEX IF NOT EXISTS postings_account ON postings (account);
+INSERT INTO postings (transaction_id, account, amount, currency, category, status)
+    SELECT transaction_id, account, amount, currency, category, status FROM migrated_legs ORDER BY rowid;
+DROP TABLE migrated_legs;
+
+ALTER TABLE accounts DROP COLUMN amount;
+
+CREATE VIEW IF NOT EXISTS account_balances AS
+    SELECT accounts.id AS account, COALESCE(SUM(postings.amount), 0) AS balance
+    FROM accounts LEFT JOIN postings ON postings.account = accounts.id AND postings.transaction_id IN (SELECT id FROM transactions WHERE voided = 0)
+    GROUP BY accounts.id;
+
+-- How transactions are listed: where the money came from and went, how much
+-- left in which currency, how much arrived in which currency and the
+-- categories of the legs
+CREATE VIEW IF NOT EXISTS transaction_summaries AS
+    WITH RECURSIVE paths(id, path) AS (SELECT id, name FROM categories WHERE parent IS NULL UNION ALL SELECT categories.id, paths.path || ':' || categories.name FROM categories JOIN paths ON categories.parent = paths.id)
+    SELECT transactions.id AS id,
+        (SELECT GROUP_CONCAT(CASE WHEN accounts.name = '__none' THEN 'PAYMENT' ELSE accounts.name END, ', ') FROM postings JOIN accounts ON accounts.id = postings.account WHERE postings.transaction_id = transactions.id AND postings.amount < 0) AS sources,
+        (SELECT GROUP_CONCAT(CASE WHEN accounts.name = '__none' THEN 'EXPENSE' ELSE accounts.name END, ', ') FROM postings JOIN accounts ON accounts.id = postings.account WHERE postings.transaction_id = transactions.id AND postings.amount >= 0) AS destinations,
+        (SELECT COALESCE(-SUM(amount), 0) FROM postings WHERE transaction_id = transactions.id AND amount < 0) AS amount,
+        (SELECT currency FROM postings WHERE transaction_id = transactions.id ORDER BY amount, id LIMIT 1) AS currency,
+        (SELECT COALESCE(SUM(amount), 0) FROM postings WHERE transaction_id = transactions.id AND amount >= 0) AS received,
+        (SELECT currency FROM postings WHERE transaction_id = transactions.id ORDER BY amount DESC, id LIMIT 1) AS received_currency,
+        (SELECT GROUP_CONCAT(DISTINCT paths.path) FROM postings JOIN paths ON paths.id = postings.category WHERE postings.transaction_id = transactions.id) AS categories
+    FROM transactions;
+
+-- Snapshots are sums of postings, so any change to a posting or to whether
+-- and when its transaction happened invalidates them from its date on
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_insert AFTER INSERT ON postings BEGIN
+    DELETE FROM balance_snapshots WHERE account = NEW.account AND date >= COALESCE((SELECT date(time) FROM transactions WHERE id = NEW.transaction_id), '');
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_update AFTER UPDATE OF account, amount ON postings BEGIN
+    DELETE FROM balance_snapshots WHERE account IN (OLD.account, NEW.account) AND date >= COALESCE((SELECT date(time) FROM transactions WHERE id = NEW.transaction_id), '');
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_delete AFTER DELETE ON postings BEGIN
+    DELETE FROM balance_snapshots WHERE account = OLD.account AND date >= COALESCE((SELECT date(time) FROM transactions WHERE id = OLD.transaction_id), '');
+END;
+CREATE TRIGGER IF NOT EXISTS balance_snapshots_transaction AFTER UPDATE OF voided, time ON transactions BEGIN
+    DELETE FROM balance_snapshots WHERE account IN (SELECT account FROM postings WHERE transaction_id = NEW.id) AND date >= min(date(OLD.time), date(NEW.time));
+END;
@@ -0,0 +1,6 @@
+-- Pending flash messages, keyed by the session (or anonymous flash key) they belong to
+CREATE TABLE IF NOT EXISTS flashes (
+    key TEXT PRIMARY KEY NOT NULL,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL
+);
@@ -0,0 +1,6 @@
+DROP TABLE flashes;
+CREATE TABLE IF NOT EXISTS flashes (
+    key TEXT PRIMARY KEY NOT NULL,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Flashes are keyed by a hash of the session or flash key, so the table never
+-- holds a live session key, and carry their creation time so abandoned ones
+-- can be deleted. Pending flashes are only a page view from being shown, so
+-- they are dropped rather than moved over.
+DROP TABLE flashes;
+CREATE TABLE IF NOT EXISTS flashes (
+    key TEXT PRIMARY KEY NOT NULL,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at DATETIME NOT NULL
+);
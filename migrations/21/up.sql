@@ -0,0 +1,13 @@
+-- Names a login proxy may log users in as. A proxy name only maps onto the
+-- user provisioned for it or the user who linked it from settings, never onto
+-- whoever happens to hold the same username.
+CREATE TABLE IF NOT EXISTS proxy_identities (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL UNIQUE
+);
+
+-- Users the proxy provisioned so far have no password, and no SSO login
+INSERT INTO proxy_identities (owner, name)
+    SELECT id, username FROM users
+    WHERE password = '' AND id NOT IN (SELECT owner FROM oidc_identities WHERE owner IS NOT NULL);
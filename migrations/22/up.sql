@@ -0,0 +1,2 @@
+-- The decimal separator of a profile's amounts, '.' or ','
+ALTER TABLE import_profiles ADD decimal VARCHAR DEFAULT '.' NOT NULL;
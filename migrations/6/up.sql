@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS oidc_identities (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    UNIQUE (issuer, subject)
+);
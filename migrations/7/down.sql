@@ -0,0 +1,5 @@
+ALTER TABLE reminders DROP COLUMN household;
+ALTER TABLE inventory DROP COLUMN household;
+ALTER TABLE accounts DROP COLUMN household;
+DROP TABLE household_members;
+DROP TABLE households;
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS households (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS household_members (
+    household INTEGER REFERENCES households (id) ON DELETE CASCADE,
+    member INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    role INTEGER NOT NULL,
+    PRIMARY KEY (household, member)
+);
+
+ALTER TABLE accounts ADD household INTEGER REFERENCES households (id) ON DELETE CASCADE;
+ALTER TABLE inventory ADD household INTEGER REFERENCES households (id) ON DELETE CASCADE;
+ALTER TABLE reminders ADD household INTEGER REFERENCES households (id) ON DELETE CASCADE;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD display_name VARCHAR;
+ALTER TABLE users ADD email VARCHAR;
@@ -0,0 +1 @@
+ALTER TABLE accounts ADD archived BOOLEAN DEFAULT 0 NOT NULL;
@@ -1,21 +1,51 @@
 use Result;
+use money::Money;
 use types::*;
 use super::Pool;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
 
-pub fn user(pool: &Pool, user: &Login, default_bang: String) -> Result<()> {
+pub fn user(pool: &Pool, user: &Login, default_bang: String) -> Result<i32> {
     let conn = pool.get()?;
-    conn.execute(query!("INSERT INTO users (username, password, api_key, default_uses, bang_uses) VALUES (?1, ?2, ?3, 0, 0)"),
-        params![user.username, user.password, user.api_key])?;
-    let owner = super::read::user_id(pool, &user.username)?;
+    let owner: i32 = conn.query_row(query!("INSERT INTO users (username, password, api_key, default_uses, bang_uses) VALUES (?1, ?2, ?3, 0, 0) RETURNING id"),
+        params![user.username, user.password, user.api_key],
+        |row| row.get(0))?;
     let def_bang = NewBang {
         owner: owner,
         bang: String::from("default"),
         value: default_bang,
     };
     bang(pool, &def_bang)?;
-    account(pool, NewAccount { name: "__none".to_string(), owner: owner })
+    account(pool, NewAccount { name: "__none".to_string(), owner: owner, household: None, currency: "USD".to_string() })?;
+    Ok(owner)
+}
+
+pub fn oidc_identity(pool: &Pool, owner: i32, issuer: &str, subject: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO oidc_identities (owner, issuer, subject) VALUES (?1, ?2, ?3)"),
+        params![owner, issuer, subject])?;
+    Ok(())
+}
+
+/// Links a login proxy name to a user. Returns false if the name is already
+/// linked to someone.
+pub fn proxy_identity(pool: &Pool, owner: i32, name: &str) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("INSERT INTO proxy_identities (owner, name) VALUES (?1, ?2) ON CONFLICT (name) DO NOTHING"),
+        params![owner, name])?;
+    Ok(n == 1)
+}
+
+/// Stores the message to show on the next page rendered for `key`, replacing any pending one.
+/// Flashes nobody came back for within an hour are deleted.
+pub fn flash(pool: &Pool, key: &str, kind: &str, message: &str) -> Result<()> {
+    let conn = pool.get()?;
+    let now = Utc::now();
+    conn.execute(query!("DELETE FROM flashes WHERE created_at < ?1"),
+        params![now - Duration::hours(1)])?;
+    conn.execute(query!("INSERT OR REPLACE INTO flashes (key, kind, message, created_at) VALUES (?1, ?2, ?3, ?4)"),
+        params![key, kind, message, now])?;
+    Ok(())
 }
 
 pub fn bang(pool: &Pool, bang: &NewBang) -> Result<()> {
@@ -32,42 +62,328 @@ pub fn quick_link(pool: &Pool, link: &NewLink) -> Result<()> {
     Ok(())
 }
 
-pub fn account(pool: &Pool, account: NewAccount) -> Result<()> {
+pub fn household(pool: &Pool, owner: i32, name: &str) -> Result<i64> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    let id: i64 = tx.query_row(query!("INSERT INTO households (name) VALUES (?1) RETURNING id"),
+        params![name], |row| row.get(0))?;
+    tx.execute(query!("INSERT INTO household_members (household, member, role) VALUES (?1, ?2, ?3)"),
+        params![id, owner, Role::Owner as i32])?;
+    tx.commit()?;
+    Ok(id)
+}
+
+/// Adds a member to a household, or changes their role if they are already one.
+/// Returns false unless `owner` owns the household and the user exists, or if
+/// it would demote the household's last owner.
+pub fn household_member(pool: &Pool, owner: i32, household: i64, member: &NewMember) -> Result<bool> {
     let conn = pool.get()?;
-    conn.execute(query!("INSERT INTO accounts (owner, name) VALUES (?1, ?2)"),
-        params![account.owner, account.name])?;
-    Ok(())
+    let n = conn.execute(query!("INSERT OR REPLACE INTO household_members (household, member, role) SELECT ?2, users.id, ?4 FROM users WHERE users.username = ?3 AND EXISTS (SELECT 1 FROM household_members WHERE household = ?2 AND member = ?1 AND role = ?5) AND NOT (?4 != ?5 AND EXISTS (SELECT 1 FROM household_members WHERE household = ?2 AND member = users.id AND role = ?5) AND (SELECT COUNT(*) FROM household_members WHERE household = ?2 AND role = ?5) = 1)"),
+        params![owner, household, member.username, member.role as i32, Role::Owner as i32])?;
+    Ok(n == 1)
+}
+
+/// Returns false if the account belongs to a household the owner can't edit.
+pub fn account(pool: &Pool, account: NewAccount) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("INSERT INTO accounts (owner, household, name, currency) SELECT ?1, ?2, ?3, ?4 WHERE ?2 IS NULL OR EXISTS (SELECT 1 FROM household_members WHERE household = ?2 AND member = ?1 AND role >= ?5)"),
+        params![account.owner, account.household, account.name, account.currency, Role::Editor as i32])?;
+    Ok(n == 1)
 }
 
-pub fn transaction(pool: &Pool, transaction: NewTransaction) -> Result<()> {
-    let from = super::read::account_id(pool, transaction.owner, &transaction.from)?;
-    let to = super::read::account_id(pool, transaction.owner, &transaction.to)?;
-    let time: DateTime<Utc> = Utc::now();
+/// Returns false if either account can't be posted to by the owner.
+pub fn transaction(pool: &Pool, transaction: NewTransaction) -> Result<bool> {
+    let from = super::read::writable_account(pool, transaction.owner, &transaction.from)?;
+    let to = super::read::writable_account(pool, transaction.owner, &transaction.to)?;
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Ok(false),
+    };
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    post(&tx, &transaction, from, to, None)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Posts the next occurrence of a scheduled transaction and moves the schedule
+/// on in one transaction, so an occurrence that can't be posted stays due.
+/// `owner` restricts this to a user's own schedules and `time` replaces the
+/// date the occurrence was due.
+///
+/// Returns None if nothing is scheduled, and false if the owner can't post to
+/// the accounts. Fails with `Error::NoExchangeRate` like `post`.
+pub fn scheduled_occurrence(pool: &Pool, id: i64, owner: Option<i32>, time: Option<DateTime<Utc>>) -> Result<Option<bool>> {
     let mut conn = pool.get()?;
     let tx = conn.transaction()?;
+    let mut transaction = match super::advance_schedule(&tx, id, owner)? {
+        Some(transaction) => transaction,
+        None => return Ok(None),
+    };
+    if let Some(time) = time {
+        transaction.time = time;
+    }
+    let from = super::writable_account(&tx, transaction.owner, &transaction.from)?;
+    let to = super::writable_account(&tx, transaction.owner, &transaction.to)?;
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Ok(Some(false)),
+    };
+    post(&tx, &transaction, from, to, None)?;
+    tx.commit()?;
+    Ok(Some(true))
+}
 
-    let txid: u32 = tx.query_row(query!("INSERT INTO transactions (owner, f, t, amount, reason, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id"),
-        params![transaction.owner, from, to, transaction.dollars*100 + transaction.cents as i64, transaction.reason, time],
+/// Inserts a transaction between accounts already checked to be writable as
+/// a leg out of `from` and one into `to`, returning its id. Fails with
+/// `Error::NoExchangeRate` for transfers between currencies without a received
+/// amount or rate.
+fn post(tx: &rusqlite::Transaction, transaction: &NewTransaction, from: i64, to: i64, import_hash: Option<&str>) -> Result<i64> {
+    let legs = super::transfer_legs(tx, transaction.owner, from, to, transaction.amount, transaction.to_amount, transaction.category, transaction.time.date_naive())?;
+    let txid: i64 = tx.query_row(query!("INSERT INTO transactions (owner, reason, time, import_hash) VALUES (?1, ?2, ?3, ?4) RETURNING id"),
+        params![transaction.owner, transaction.reason, transaction.time, import_hash],
         |row| row.get(0))?;
-    {
-        tx.execute(query!("UPDATE accounts SET amount = amount - (SELECT amount FROM transactions WHERE id = ?1) WHERE id = ?2"),
-            params![txid, from])?;
-        tx.execute(query!("UPDATE accounts SET amount = amount + (SELECT amount FROM transactions WHERE id = ?1) WHERE id = ?2"),
-            params![txid, to])?;
+    super::set_postings(tx, txid, transaction.owner, &legs)?;
+    super::set_tags(tx, txid, &transaction.tags)?;
+    Ok(txid)
+}
+
+/// Returns false if any of the accounts can't be posted to by the owner. Fails
+/// with `Error::MixedCurrencies` unless the accounts share a currency.
+pub fn split(pool: &Pool, split: NewSplit) -> Result<bool> {
+    let postings = match super::read::split_accounts(pool, split.owner, &split.postings)? {
+        Some(postings) => postings,
+        None => return Ok(false),
+    };
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    post_split(&tx, split.owner, &split.reason, split.time, &split.tags, &postings, None)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Posts a split whose postings have been resolved to account ids.
+fn post_split(tx: &rusqlite::Transaction, owner: i32, reason: &str, time: DateTime<Utc>, tags: &[String], postings: &[(i64, Money, Option<i64>)], import_hash: Option<&str>) -> Result<i64> {
+    let legs = super::split_legs(tx, postings)?;
+    let txid: i64 = tx.query_row(query!("INSERT INTO transactions (owner, reason, time, import_hash) VALUES (?1, ?2, ?3, ?4) RETURNING id"),
+        params![owner, reason, time, import_hash],
+        |row| row.get(0))?;
+    super::set_postings(tx, txid, owner, &legs)?;
+    super::set_tags(tx, txid, tags)?;
+    Ok(txid)
+}
+
+/// Posts statement rows to an account in one transaction, money out going to
+/// and money in coming from outside money. Returns false if the user can't
+/// post to the account.
+pub fn import(pool: &Pool, user: i32, account: i64, rows: &[ImportRow]) -> Result<bool> {
+    let account = super::read::writable_account(pool, user, &account.to_string())?;
+    let outside = super::read::writable_account(pool, user, "__none")?;
+    let (account, outside) = match (account, outside) {
+        (Some(account), Some(outside)) => (account, outside),
+        _ => return Ok(false),
+    };
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+
+    for row in rows {
+        let (from, to) = if row.amount.is_negative() { (account, outside) } else { (outside, account) };
+        let transaction = NewTransaction {
+            owner: user,
+            from: from.to_string(),
+            to: to.to_string(),
+            amount: row.amount.abs(),
+            to_amount: None,
+            reason: row.description.clone(),
+            category: None,
+            tags: Vec::new(),
+            time: Utc.from_utc_datetime(&row.date.and_hms_opt(0, 0, 0).unwrap()),
+        };
+        post(&tx, &transaction, from, to, Some(&row.hash))?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Posts journal entries in one transaction, creating the user's personal
+/// accounts and categories they name when missing. Entries already imported
+/// are skipped, and entries that can't be posted are skipped and added to
+/// `problems`. Returns the number posted and skipped as already imported.
+/// Fails with `Error::MixedCurrencies` if a split's accounts don't share a
+/// currency.
+pub fn journal(pool: &Pool, user: i32, entries: &[JournalEntry], problems: &mut Vec<String>) -> Result<(usize, usize)> {
+    let outside = match super::read::writable_account(pool, user, "__none")? {
+        Some(outside) => outside,
+        None => return Ok((0, entries.len())),
+    };
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+
+    let (mut posted, mut skipped) = (0, 0);
+    'entries: for entry in entries {
+        let duplicate: bool = tx.query_row(query!("SELECT EXISTS (SELECT 1 FROM transactions WHERE owner = ?1 AND import_hash = ?2)"),
+            params![user, entry.hash],
+            |row| row.get(0))?;
+        if duplicate {
+            skipped += 1;
+            continue;
+        }
+
+        let mut postings = Vec::with_capacity(entry.postings.len());
+        for posting in &entry.postings {
+            let account = match posting.account {
+                Some(ref name) => match journal_account(&tx, user, name, &entry.currency)? {
+                    Ok(account) => account,
+                    Err(problem) => {
+                        problems.push(format!("Line {}: {}", entry.line, problem));
+                        continue 'entries;
+                    }
+                },
+                None => outside,
+            };
+            let category = match posting.category {
+                Some(ref path) => Some(journal_category(&tx, user, path)?),
+                None => None,
+            };
+            postings.push((account, posting.amount, category));
+        }
+        let time = Utc.from_utc_datetime(&entry.date.and_hms_opt(0, 0, 0).unwrap());
+        if let [a, b] = postings[..] {
+            // Money moves from the negative posting to the positive one
+            let ((from, _, from_category), (to, amount, to_category)) = if a.1 <= Money::ZERO { (a, b) } else { (b, a) };
+            let transaction = NewTransaction {
+                owner: user,
+                from: from.to_string(),
+                to: to.to_string(),
+                amount,
+                to_amount: None,
+                reason: entry.reason.clone(),
+                category: to_category.or(from_category),
+                tags: entry.tags.clone(),
+                time,
+            };
+            post(&tx, &transaction, from, to, Some(&entry.hash))?;
+        } else {
+            post_split(&tx, user, &entry.reason, time, &entry.tags, &postings, Some(&entry.hash))?;
+        }
+        posted += 1;
+    }
+    tx.commit()?;
+    Ok((posted, skipped))
+}
+
+/// Finds or creates the user's personal account named `name` in `currency`.
+/// Archived accounts take no new postings and accounts keep their currency,
+/// so those give the reason the account can't be posted to.
+fn journal_account(tx: &rusqlite::Transaction, user: i32, name: &str, currency: &str) -> Result<::std::result::Result<i64, String>> {
+    let existing: rusqlite::Result<(i64, bool, String)> = tx.query_row(query!("SELECT id, archived, currency FROM accounts WHERE owner = ?1 AND household IS NULL AND name = ?2 ORDER BY archived LIMIT 1"),
+        params![user, name],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
+    match existing {
+        Ok((_, true, _)) => Ok(Err(format!("the account {} is archived", name))),
+        Ok((_, false, ref existing)) if existing != currency => {
+            Ok(Err(format!("the account {} is in {}, not {}", name, existing, currency)))
+        }
+        Ok((id, false, _)) => Ok(Ok(id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Ok(Ok(tx.query_row(query!("INSERT INTO accounts (owner, name, currency) VALUES (?1, ?2, ?3) RETURNING id"),
+                params![user, name, currency],
+                |row| row.get(0))?))
+        }
+        Err(e) => Err(e.into()),
     }
-    Ok(tx.commit()?)
 }
 
-pub fn reminder(pool: &Pool, owner: i32, reminder: Reminder) -> Result<()> {
+/// Finds or creates each level of a `Parent:Child` category path.
+fn journal_category(tx: &rusqlite::Transaction, user: i32, path: &str) -> Result<i64> {
+    let mut parent: Option<i64> = None;
+    for name in path.split(':').map(str::trim).filter(|n| !n.is_empty()) {
+        let existing = tx.query_row(query!("SELECT id FROM categories WHERE owner = ?1 AND parent IS ?2 AND name = ?3"),
+            params![user, parent, name],
+            |row| row.get(0));
+        parent = Some(match existing {
+            Ok(id) => id,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                tx.query_row(query!("INSERT INTO categories (owner, parent, name) VALUES (?1, ?2, ?3) RETURNING id"),
+                    params![user, parent, name],
+                    |row| row.get(0))?
+            }
+            Err(e) => return Err(e.into()),
+        });
+    }
+    Ok(parent.unwrap_or_default())
+}
+
+pub fn import_profile(pool: &Pool, owner: i32, profile: &NewImportProfile) -> Result<i64> {
     let conn = pool.get()?;
-    conn.execute(query!("INSERT INTO reminders (owner, recurrence, reason, date) VALUES (?1, ?2, ?3, ?4)"),
-        params![owner, reminder.recurrence as i32, reminder.reason, reminder.date])?;
-    Ok(())
+    let columns = &profile.columns;
+    Ok(conn.query_row(query!("INSERT INTO import_profiles (owner, name, delimiter, has_header, date_column, date_format, amount_column, sign, credit_column, description_column, balance_column, decimal) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12) RETURNING id"),
+        params![owner, profile.name, (columns.delimiter as char).to_string(), columns.has_header, columns.date as i64, columns.date_format, columns.amount as i64, columns.sign as i32, columns.credit.map(|c| c as i64), columns.description as i64, columns.balance.map(|c| c as i64), columns.decimal.to_string()],
+        |row| row.get(0))?)
 }
 
-pub fn inventory_item(pool: &Pool, item: Item) -> Result<()> {
+/// Returns false if either account can't be posted to by the owner.
+pub fn scheduled_transaction(pool: &Pool, schedule: NewSchedule) -> Result<bool> {
+    let transaction = schedule.transaction;
+    let from = super::read::writable_account(pool, transaction.owner, &transaction.from)?;
+    let to = super::read::writable_account(pool, transaction.owner, &transaction.to)?;
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Ok(false),
+    };
     let conn = pool.get()?;
-    conn.execute(query!("INSERT INTO inventory (owner, name, quantity, unit, low_reminder) VALUES (?1, ?2, ?3, ?4, ?5)"),
-        params![item.owner, item.name, item.quantity, item.unit, item.low_reminder])?;
-    Ok(())
+    conn.execute(query!("INSERT INTO scheduled_transactions (owner, f, t, amount, reason, category, tags, recurrence, start_date, end_date, next_date) VALUES (?1, ?2, ?3, ?4, ?5, (SELECT id FROM categories WHERE id = ?6 AND owner = ?1), ?7, ?8, ?9, ?10, ?9)"),
+        params![transaction.owner, from, to, transaction.amount, transaction.reason, transaction.category, transaction.tags.join(", "), schedule.recurrence as i32, schedule.start, schedule.end])?;
+    Ok(true)
+}
+
+/// Adds exchange rates, replacing any the owner has for the same pair and day.
+/// Returns how many were stored.
+pub fn exchange_rates(pool: &Pool, owner: i32, rates: &[NewExchangeRate]) -> Result<usize> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    for rate in rates {
+        tx.execute(query!("INSERT INTO exchange_rates (owner, base, quote, date, rate) VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT (owner, base, quote, date) DO UPDATE SET rate = excluded.rate"),
+            params![owner, rate.base, rate.quote, rate.date, rate.rate])?;
+    }
+    tx.commit()?;
+    Ok(rates.len())
+}
+
+/// Returns false if the parent category isn't the owner's.
+pub fn category(pool: &Pool, owner: i32, category: NewCategory) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("INSERT INTO categories (owner, parent, name) SELECT ?1, ?2, ?3 WHERE ?2 IS NULL OR EXISTS (SELECT 1 FROM categories WHERE id = ?2 AND owner = ?1)"),
+        params![owner, category.parent, category.name])?;
+    Ok(n == 1)
+}
+
+/// Returns false if the budget's category isn't the owner's or its account
+/// can't be read by the owner.
+pub fn budget(pool: &Pool, owner: i32, budget: NewBudget) -> Result<bool> {
+    let (category, account) = match budget.target {
+        BudgetTarget::Category(id) => (Some(id), None),
+        BudgetTarget::Account(id) => (None, Some(id)),
+    };
+    let start = Utc::now().date_naive().with_day(1).unwrap();
+    let conn = pool.get()?;
+    let n = conn.execute(query!("INSERT INTO budgets (owner, category, account, amount, rollover, start) SELECT ?1, ?2, ?3, ?4, ?5, ?6 WHERE EXISTS (SELECT 1 FROM categories WHERE id = ?2 AND owner = ?1) OR EXISTS (SELECT 1 FROM accounts WHERE id = ?3 AND ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)))"),
+        params![owner, category, account, budget.amount, budget.rollover, start])?;
+    Ok(n == 1)
+}
+
+/// Returns false if the reminder belongs to a household the owner can't edit.
+pub fn reminder(pool: &Pool, owner: i32, reminder: Reminder) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("INSERT INTO reminders (owner, household, recurrence, reason, date) SELECT ?1, ?2, ?3, ?4, ?5 WHERE ?2 IS NULL OR EXISTS (SELECT 1 FROM household_members WHERE household = ?2 AND member = ?1 AND role >= ?6)"),
+        params![owner, reminder.household, reminder.recurrence as i32, reminder.reason, reminder.date, Role::Editor as i32])?;
+    Ok(n == 1)
+}
+
+/// Returns false if the item belongs to a household the owner can't edit.
+pub fn inventory_item(pool: &Pool, item: Item) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("INSERT INTO inventory (owner, household, name, quantity, unit, low_reminder) SELECT ?1, ?2, ?3, ?4, ?5, ?6 WHERE ?2 IS NULL OR EXISTS (SELECT 1 FROM household_members WHERE household = ?2 AND member = ?1 AND role >= ?7)"),
+        params![item.owner, item.household, item.name, item.quantity, item.unit, item.low_reminder, Role::Editor as i32])?;
+    Ok(n == 1)
 }
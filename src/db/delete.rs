@@ -1,18 +1,158 @@
-use Result;
+use {Error, Result};
+use types::{DeleteAccount, Role, Status};
 use super::Pool;
 
-pub fn bang(pool: &Pool, username: &str, id: i64) -> Result<()> {
-    let owner = super::read::user_id(pool, username)?;
+pub fn bang(pool: &Pool, owner: i32, id: i64) -> Result<()> {
     let conn = pool.get()?;
     conn.execute(query!("DELETE FROM bangs WHERE owner = ?1 AND id = ?2"),
         params![owner, id])?;
     Ok(())
 }
 
-pub fn quick_link(pool: &Pool, username: &str, id: i64) -> Result<()> {
-    let owner = super::read::user_id(pool, username)?;
+pub fn quick_link(pool: &Pool, owner: i32, id: i64) -> Result<()> {
     let conn = pool.get()?;
     conn.execute(query!("DELETE FROM quick_links WHERE owner = ?1 AND id = ?2"),
         params![owner, id])?;
     Ok(())
 }
+
+/// Removes and returns the pending flash message for `key`, so it is only shown once.
+pub fn flash(pool: &Pool, key: &str) -> Result<Option<(String, String)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("DELETE FROM flashes WHERE key = ?1 RETURNING kind, message"))?;
+    match stmt.query_row(params![key], |row| Ok((row.get(0)?, row.get(1)?))) {
+        Ok(flash) => Ok(Some(flash)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Members may leave a household, and its owners may remove anyone but its last owner.
+pub fn household_member(pool: &Pool, user: i32, household: i64, member: i32) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("DELETE FROM household_members WHERE household = ?2 AND member = ?3 AND (member = ?1 OR EXISTS (SELECT 1 FROM household_members WHERE household = ?2 AND member = ?1 AND role = ?4)) AND NOT (role = ?4 AND (SELECT COUNT(*) FROM household_members WHERE household = ?2 AND role = ?4) = 1)"),
+        params![user, household, member, Role::Owner as i32])?;
+    Ok(n == 1)
+}
+
+/// Deletes an account the user can edit, handling its transactions as asked.
+/// Returns false if the account or the account to move to can't be edited, or
+/// if removing its transactions would touch reconciled legs or accounts the
+/// user can't edit.
+pub fn account(pool: &Pool, user: i32, account_id: i64, mode: DeleteAccount) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    if super::account_summary(&tx, user, account_id)?.is_none() {
+        return Ok(false);
+    }
+
+    match mode {
+        DeleteAccount::Move(target) => {
+            if target == account_id || super::account_summary(&tx, user, target)?.is_none() {
+                return Ok(false);
+            }
+            // Legs can't be merged across currencies
+            let same_currency: bool = tx.query_row(query!("SELECT (SELECT currency FROM accounts WHERE id = ?1) = (SELECT currency FROM accounts WHERE id = ?2)"),
+                params![account_id, target], |row| row.get(0))?;
+            if !same_currency {
+                return Ok(false);
+            }
+            tx.execute(query!("UPDATE postings SET account = ?2 WHERE account = ?1"),
+                params![account_id, target])?;
+            tx.execute(query!("UPDATE scheduled_transactions SET f = ?2 WHERE f = ?1"),
+                params![account_id, target])?;
+            tx.execute(query!("UPDATE scheduled_transactions SET t = ?2 WHERE t = ?1"),
+                params![account_id, target])?;
+            // Legs of a transaction on both accounts become one
+            tx.execute(query!("UPDATE postings SET amount = (SELECT SUM(p.amount) FROM postings p WHERE p.transaction_id = postings.transaction_id AND p.account = ?1) WHERE account = ?1 AND id = (SELECT MIN(p.id) FROM postings p WHERE p.transaction_id = postings.transaction_id AND p.account = ?1)"),
+                params![target])?;
+            tx.execute(query!("DELETE FROM postings WHERE account = ?1 AND id != (SELECT MIN(p.id) FROM postings p WHERE p.transaction_id = postings.transaction_id AND p.account = ?1)"),
+                params![target])?;
+            // Transfers between the two accounts now go nowhere
+            tx.execute(query!("DELETE FROM transaction_tags WHERE transaction_id IN (SELECT transaction_id FROM postings WHERE account = ?1) AND NOT EXISTS (SELECT 1 FROM postings WHERE transaction_id = transaction_tags.transaction_id AND amount != 0)"),
+                params![target])?;
+            tx.execute(query!("DELETE FROM transactions WHERE id IN (SELECT transaction_id FROM postings WHERE account = ?1) AND NOT EXISTS (SELECT 1 FROM postings WHERE transaction_id = transactions.id AND amount != 0)"),
+                params![target])?;
+            tx.execute(query!("DELETE FROM scheduled_transactions WHERE f = ?1 AND t = ?1"),
+                params![target])?;
+        }
+        DeleteAccount::Remove => {
+            // Removing a transaction takes its other legs with it
+            let locked: bool = tx.query_row(query!("SELECT EXISTS (SELECT 1 FROM postings WHERE transaction_id IN (SELECT transaction_id FROM postings WHERE account = ?1) AND (status = ?3 OR account NOT IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?2) OR household IN (SELECT household FROM household_members WHERE member = ?2 AND role >= ?4))))"),
+                params![account_id, user, Status::Reconciled as i32, Role::Editor as i32], |row| row.get(0))?;
+            if locked {
+                return Ok(false);
+            }
+            tx.execute(query!("DELETE FROM transaction_tags WHERE transaction_id IN (SELECT transaction_id FROM postings WHERE account = ?1)"),
+                params![account_id])?;
+            tx.execute(query!("DELETE FROM transactions WHERE id IN (SELECT transaction_id FROM postings WHERE account = ?1)"),
+                params![account_id])?;
+        }
+    }
+    tx.execute(query!("DELETE FROM postings WHERE transaction_id NOT IN (SELECT id FROM transactions)"),
+        params![])?;
+    tx.execute(query!("DELETE FROM balance_snapshots WHERE account = ?1"),
+        params![account_id])?;
+    tx.execute(query!("DELETE FROM scheduled_transactions WHERE f = ?1 OR t = ?1"),
+        params![account_id])?;
+    tx.execute(query!("DELETE FROM accounts WHERE id = ?1"),
+        params![account_id])?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Deletes a transaction and its legs. Returns false if the user can't post to
+/// all of its accounts or it's reconciled.
+pub fn transaction(pool: &Pool, user: i32, id: i64) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    if !super::unlocked_transaction(&tx, user, id)? {
+        return Ok(false);
+    }
+
+    super::set_tags(&tx, id, &[])?;
+    tx.execute(query!("DELETE FROM postings WHERE transaction_id = ?1"),
+        params![id])?;
+    tx.execute(query!("DELETE FROM transactions WHERE id = ?1"),
+        params![id])?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Deletes one of the owner's categories. Its subcategories move up a level
+/// and its transactions become uncategorized.
+pub fn category(pool: &Pool, owner: i32, id: i64) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+
+    tx.execute(query!("UPDATE categories SET parent = (SELECT parent FROM categories WHERE id = ?2) WHERE parent = ?2 AND EXISTS (SELECT 1 FROM categories WHERE id = ?2 AND owner = ?1)"),
+        params![owner, id])?;
+    tx.execute(query!("UPDATE postings SET category = NULL WHERE category = ?2 AND EXISTS (SELECT 1 FROM categories WHERE id = ?2 AND owner = ?1)"),
+        params![owner, id])?;
+    let n = tx.execute(query!("DELETE FROM categories WHERE id = ?2 AND owner = ?1"),
+        params![owner, id])?;
+    tx.commit()?;
+    Ok(n == 1)
+}
+
+pub fn budget(pool: &Pool, owner: i32, id: i64) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("DELETE FROM budgets WHERE id = ?2 AND owner = ?1"),
+        params![owner, id])?;
+    Ok(n == 1)
+}
+
+pub fn scheduled_transaction(pool: &Pool, owner: i32, id: i64) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("DELETE FROM scheduled_transactions WHERE id = ?2 AND owner = ?1"),
+        params![owner, id])?;
+    Ok(n == 1)
+}
+
+/// Returns false if the rate isn't the user's.
+pub fn exchange_rate(pool: &Pool, user: i32, id: i64) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("DELETE FROM exchange_rates WHERE id = ?1 AND owner = ?2"),
+        params![id, user])?;
+    Ok(n == 1)
+}
@@ -3,4 +3,432 @@ pub mod read;
 pub mod update;
 pub mod delete;
 
+use {Error, Result};
+use money::Money;
+use types::{parse_tags, ExchangeRate, NewTransaction, Rates, Recurrence, Role, Status};
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use rusqlite_migration::{M, Migrations};
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
+
+/// The schema migrations, oldest first.
+pub fn migrations() -> Migrations<'static> {
+    Migrations::new(vec![
+        M::up(include_str!("../../migrations/1/up.sql"))
+            .down(include_str!("../../migrations/1/down.sql")),
+        M::up(include_str!("../../migrations/2/up.sql"))
+            .down(include_str!("../../migrations/2/down.sql")),
+        M::up(include_str!("../../migrations/3/up.sql"))
+            .down(include_str!("../../migrations/3/down.sql")),
+        M::up(include_str!("../../migrations/4/up.sql"))
+            .down(include_str!("../../migrations/4/down.sql")),
+        M::up(include_str!("../../migrations/5/up.sql"))
+            .down(include_str!("../../migrations/5/down.sql")),
+        M::up(include_str!("../../migrations/6/up.sql"))
+            .down(include_str!("../../migrations/6/down.sql")),
+        M::up(include_str!("../../migrations/7/up.sql"))
+            .down(include_str!("../../migrations/7/down.sql")),
+        M::up(include_str!("../../migrations/8/up.sql"))
+            .down(include_str!("../../migrations/8/down.sql")),
+        M::up(include_str!("../../migrations/9/up.sql"))
+            .down(include_str!("../../migrations/9/down.sql")),
+        M::up(include_str!("../../migrations/10/up.sql"))
+            .down(include_str!("../../migrations/10/down.sql")),
+        M::up(include_str!("../../migrations/11/up.sql"))
+            .down(include_str!("../../migrations/11/down.sql")),
+        M::up(include_str!("../../migrations/12/up.sql"))
+            .down(include_str!("../../migrations/12/down.sql")),
+        M::up(include_str!("../../migrations/13/up.sql"))
+            .down(include_str!("../../migrations/13/down.sql")),
+        M::up(include_str!("../../migrations/14/up.sql"))
+            .down(include_str!("../../migrations/14/down.sql")),
+        M::up(include_str!("../../migrations/15/up.sql"))
+            .down(include_str!("../../migrations/15/down.sql")),
+        M::up(include_str!("../../migrations/16/up.sql"))
+            .down(include_str!("../../migrations/16/down.sql")),
+        M::up(include_str!("../../migrations/17/up.sql"))
+            .down(include_str!("../../migrations/17/down.sql")),
+        M::up(include_str!("../../migrations/18/up.sql"))
+            .down(include_str!("../../migrations/18/down.sql")),
+        M::up(include_str!("../../migrations/19/up.sql"))
+            .down(include_str!("../../migrations/19/down.sql")),
+        M::up(include_str!("../../migrations/20/up.sql"))
+            .down(include_str!("../../migrations/20/down.sql")),
+        M::up(include_str!("../../migrations/21/up.sql"))
+            .down(include_str!("../../migrations/21/down.sql")),
+        M::up(include_str!("../../migrations/22/up.sql"))
+            .down(include_str!("../../migrations/22/down.sql")),
+    ])
+}
+
+/// A leg of a transaction about to be written.
+struct Leg {
+    account: i64,
+    amount: Money,
+    currency: String,
+    category: Option<i64>,
+}
+
+/// The name and number of transactions of an account the user can edit.
+fn account_summary(conn: &rusqlite::Connection, user: i32, account_id: i64) -> Result<Option<(String, i64)>> {
+    let res = conn.query_row(query!("SELECT name, (SELECT COUNT(DISTINCT transaction_id) FROM postings WHERE account = ?1) FROM accounts WHERE id = ?1 AND name != '__none' AND ((household IS NULL AND owner = ?2) OR household IN (SELECT household FROM household_members WHERE member = ?2 AND role >= ?3))"),
+        params![account_id, user, Role::Editor as i32], |row| Ok((row.get(0)?, row.get(1)?)));
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Resolves an account from a form value, either an account id or `__none`,
+/// if the user may post transactions to it. Archived accounts take no new ones.
+fn writable_account(conn: &rusqlite::Connection, user: i32, account: &str) -> Result<Option<i64>> {
+    let res = if account == "__none" {
+        conn.query_row(query!("SELECT id FROM accounts WHERE owner = ?1 AND name = '__none'"),
+            params![user], |row| row.get(0))
+    } else {
+        let id: i64 = match account.parse() {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        conn.query_row(query!("SELECT id FROM accounts WHERE id = ?2 AND archived = 0 AND ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1 AND role >= ?3))"),
+            params![user, id, Role::Editor as i32], |row| row.get(0))
+    };
+    match res {
+        Ok(id) => Ok(Some(id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Whether the transaction exists, the user can post to all of its accounts and
+/// none of its legs are reconciled.
+fn unlocked_transaction(conn: &rusqlite::Connection, user: i32, id: i64) -> Result<bool> {
+    Ok(conn.query_row(query!("SELECT EXISTS (SELECT 1 FROM postings WHERE transaction_id = ?2) AND NOT EXISTS (SELECT 1 FROM postings WHERE transaction_id = ?2 AND (status = ?4 OR account NOT IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1 AND role >= ?3))))"),
+        params![user, id, Role::Editor as i32, Status::Reconciled as i32], |row| row.get(0))?)
+}
+
+/// Moves a scheduled transaction on to its next occurrence, returning the
+/// transaction for the occurrence passed over so it can be posted in the same
+/// transaction. `owner` restricts this to a user's own schedules.
+///
+/// Returns None if nothing is scheduled or another request advanced the
+/// schedule first, so an occurrence is never handed out twice.
+fn advance_schedule(tx: &rusqlite::Transaction, id: i64, owner: Option<i32>) -> Result<Option<NewTransaction>> {
+    let res = tx.query_row(query!("SELECT owner, f, t, amount, reason, category, tags, recurrence, start_date, end_date, occurrences, next_date FROM scheduled_transactions WHERE id = ?1 AND (?2 IS NULL OR owner = ?2) AND next_date IS NOT NULL"),
+        params![id, owner], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?,
+                row.get(7)?, row.get(8)?, row.get(9)?, row.get(10)?, row.get(11)?))
+        });
+    let (owner, f, t, amount, reason, category, tags, recurrence, start, end, occurrences, due):
+        (i32, i64, i64, Money, Option<String>, Option<i64>, String, i32, NaiveDate, Option<NaiveDate>, u32, NaiveDate) = match res {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+
+    let next = Recurrence::from_i32(recurrence)
+        .and_then(|r| r.nth(start, occurrences + 1))
+        .filter(|next| end.map_or(true, |end| *next <= end));
+    let n = tx.execute(query!("UPDATE scheduled_transactions SET occurrences = ?2, next_date = ?3 WHERE id = ?1 AND occurrences = ?4"),
+        params![id, occurrences + 1, next, occurrences])?;
+    if n != 1 {
+        return Ok(None);
+    }
+
+    Ok(Some(NewTransaction {
+        owner,
+        from: f.to_string(),
+        to: t.to_string(),
+        amount,
+        to_amount: None,
+        reason: reason.unwrap_or_default(),
+        category,
+        tags: parse_tags(&tags),
+        time: Utc.from_utc_datetime(&due.and_hms_opt(0, 0, 0).unwrap()),
+    }))
+}
+
+/// The currency of an account and whether it's outside money.
+fn account_currency(tx: &rusqlite::Transaction, id: i64) -> Result<(String, bool)> {
+    Ok(tx.query_row(query!("SELECT currency, name = '__none' FROM accounts WHERE id = ?1"),
+        params![id], |row| Ok((row.get(0)?, row.get(1)?)))?)
+}
+
+/// The legs of a transfer of `amount` from one account to another, both in
+/// the category. Outside money takes the currency of the other account. When
+/// `to` is in another currency it receives `to_amount`, or without one the
+/// amount converted at the owner's rate nearest `date`.
+fn transfer_legs(tx: &rusqlite::Transaction, owner: i32, from: i64, to: i64, amount: Money, to_amount: Option<Money>, category: Option<i64>, date: NaiveDate) -> Result<Vec<Leg>> {
+    let (from_currency, from_outside) = account_currency(tx, from)?;
+    let (to_currency, to_outside) = account_currency(tx, to)?;
+    let (from_currency, to_currency, received) = if from_outside {
+        (to_currency.clone(), to_currency, amount)
+    } else if to_outside || from_currency == to_currency {
+        (from_currency.clone(), from_currency, amount)
+    } else {
+        let received = match to_amount {
+            Some(to_amount) => to_amount,
+            None => match rates(tx, owner)?.convert(amount, &from_currency, &to_currency, date) {
+                Some(to_amount) => to_amount,
+                None => return Err(Error::NoExchangeRate(from_currency, to_currency)),
+            },
+        };
+        (from_currency, to_currency, received)
+    };
+    Ok(vec![
+        Leg { account: from, amount: -amount, currency: from_currency, category },
+        Leg { account: to, amount: received, currency: to_currency, category },
+    ])
+}
+
+/// The legs of a split transaction. Outside money takes the currency of the
+/// other legs, which must all share one.
+fn split_legs(tx: &rusqlite::Transaction, postings: &[(i64, Money, Option<i64>)]) -> Result<Vec<Leg>> {
+    let mut currency: Option<String> = None;
+    let mut outside = Vec::new();
+    for &(account, _, _) in postings {
+        let (account_currency, is_outside) = account_currency(tx, account)?;
+        if is_outside {
+            outside.push(account);
+            continue;
+        }
+        match currency {
+            Some(ref c) if *c != account_currency => return Err(Error::MixedCurrencies),
+            _ => currency = Some(account_currency),
+        }
+    }
+    // Only outside money, which is in the currency of the outside accounts
+    let currency = match currency {
+        Some(currency) => currency,
+        None => account_currency(tx, outside[0])?.0,
+    };
+    Ok(postings.iter()
+        .map(|&(account, amount, category)| Leg { account, amount, currency: currency.clone(), category })
+        .collect())
+}
+
+/// Replaces the legs of a transaction. Legs on accounts the transaction had a
+/// leg on before keep that leg's status.
+fn set_postings(tx: &rusqlite::Transaction, id: i64, owner: i32, legs: &[Leg]) -> Result<()> {
+    let mut statuses: HashMap<i64, i32> = HashMap::new();
+    {
+        let mut stmt = tx.prepare(query!("SELECT account, MIN(status) FROM postings WHERE transaction_id = ?1 GROUP BY account"))?;
+        let rows = stmt.query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for r in rows {
+            let (account, status) = r?;
+            statuses.insert(account, status);
+        }
+    }
+    tx.execute(query!("DELETE FROM postings WHERE transaction_id = ?1"),
+        params![id])?;
+    for leg in legs {
+        tx.execute(query!("INSERT INTO postings (transaction_id, account, amount, currency, category, status) VALUES (?1, ?2, ?3, ?4, (SELECT id FROM categories WHERE id = ?5 AND owner = ?6), ?7)"),
+            params![id, leg.account, leg.amount, leg.currency, leg.category, owner, statuses.get(&leg.account).cloned().unwrap_or(Status::Pending as i32)])?;
+    }
+    Ok(())
+}
+
+fn rates(conn: &rusqlite::Connection, owner: i32) -> Result<Rates> {
+    Ok(Rates::new(exchange_rates(conn, owner)?))
+}
+
+fn exchange_rates(conn: &rusqlite::Connection, owner: i32) -> Result<Vec<ExchangeRate>> {
+    let mut stmt = conn.prepare(query!("SELECT id, base, quote, date, rate FROM exchange_rates WHERE owner = ?1 ORDER BY date DESC, base, quote"))?;
+    let rows = stmt.query_map(params![owner], |row| {
+        Ok(ExchangeRate {
+            id: row.get(0)?,
+            base: row.get(1)?,
+            quote: row.get(2)?,
+            date: row.get(3)?,
+            rate: row.get(4)?,
+        })
+    })?;
+    let mut rates = Vec::new();
+    for r in rows {
+        rates.push(r?);
+    }
+    Ok(rates)
+}
+
+fn home_currency(conn: &rusqlite::Connection, user: i32) -> Result<String> {
+    Ok(conn.query_row(query!("SELECT home_currency FROM users WHERE id = ?1"),
+        params![user], |row| row.get(0))?)
+}
+
+/// Converts amounts grouped by key and currency into the user's home currency
+/// at the rates nearest `date`, summing them per key. Amounts in currencies
+/// without a rate to the home currency are left out.
+fn home_totals<K: Eq + Hash>(conn: &rusqlite::Connection, user: i32, totals: Vec<(K, String, Money)>, date: NaiveDate) -> Result<HashMap<K, Money>> {
+    let home = home_currency(conn, user)?;
+    let rates = rates(conn, user)?;
+    let mut converted = HashMap::new();
+    for (key, currency, amount) in totals {
+        if let Some(amount) = rates.convert(amount, &currency, &home, date) {
+            *converted.entry(key).or_insert(Money::ZERO) += amount;
+        }
+    }
+    Ok(converted)
+}
+
+/// Caches the end of day balance of the account, summed from its postings,
+/// for every day from its first transaction through `to`. Triggers on
+/// `postings` and `transactions` clear snapshots from a changed transaction's
+/// date on, so filling continues from the last one left.
+fn fill_snapshots(tx: &rusqlite::Transaction, account: i64, to: NaiveDate) -> Result<()> {
+    let last = tx.query_row(query!("SELECT date, balance FROM balance_snapshots WHERE account = ?1 ORDER BY date DESC LIMIT 1"),
+        params![account], |row| Ok((row.get(0)?, row.get(1)?)));
+    let (mut date, mut balance): (NaiveDate, Money) = match last {
+        Ok((date, balance)) => match date.succ_opt() {
+            Some(next) => (next, balance),
+            None => return Ok(()),
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            let first: Option<NaiveDate> = tx.query_row(query!("SELECT MIN(date(transactions.time)) FROM postings JOIN transactions ON transactions.id = postings.transaction_id WHERE postings.account = ?1"),
+                params![account], |row| row.get(0))?;
+            match first {
+                Some(first) => (first, Money::ZERO),
+                None => return Ok(()),
+            }
+        }
+        Err(e) => return Err(Error::from(e)),
+    };
+    if date > to {
+        return Ok(());
+    }
+
+    let mut stmt = tx.prepare(query!("SELECT date(transactions.time), SUM(postings.amount) FROM postings JOIN transactions ON transactions.id = postings.transaction_id WHERE postings.account = ?1 AND transactions.voided = 0 AND date(transactions.time) BETWEEN ?2 AND ?3 GROUP BY 1"))?;
+    let rows = stmt.query_map(params![account, date, to], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut changes: HashMap<NaiveDate, Money> = HashMap::new();
+    for r in rows {
+        let (day, change) = r?;
+        changes.insert(day, change);
+    }
+
+    let mut insert = tx.prepare(query!("INSERT OR REPLACE INTO balance_snapshots (account, date, balance) VALUES (?1, ?2, ?3)"))?;
+    while date <= to {
+        balance += changes.get(&date).cloned().unwrap_or_default();
+        insert.execute(params![account, date, balance])?;
+        date = match date.succ_opt() {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+/// End of day balances of the account, as summed from its postings, for
+/// every day from `from` through `to`.
+fn daily_balances(tx: &rusqlite::Transaction, account: i64, from: NaiveDate, to: NaiveDate) -> Result<Vec<(NaiveDate, Money)>> {
+    fill_snapshots(tx, account, to)?;
+    let mut stmt = tx.prepare(query!("SELECT date, balance FROM balance_snapshots WHERE account = ?1 AND date BETWEEN ?2 AND ?3"))?;
+    let rows = stmt.query_map(params![account, from, to], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut snapshots: HashMap<NaiveDate, Money> = HashMap::new();
+    for r in rows {
+        let (date, balance) = r?;
+        snapshots.insert(date, balance);
+    }
+
+    // Snapshots start at the first transaction, before which the balance was
+    // zero
+    Ok(from.iter_days()
+        .take_while(|&d| d <= to)
+        .map(|d| (d, snapshots.get(&d).cloned().unwrap_or_default()))
+        .collect())
+}
+
+/// Replaces the tags of a transaction.
+fn set_tags(tx: &rusqlite::Transaction, id: i64, tags: &[String]) -> Result<()> {
+    tx.execute(query!("DELETE FROM transaction_tags WHERE transaction_id = ?1"),
+        params![id])?;
+    for tag in tags {
+        tx.execute(query!("INSERT INTO transaction_tags (transaction_id, tag) VALUES (?1, ?2)"),
+            params![id, tag])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rusqlite::Connection;
+
+    /// Balances kept in `accounts.amount` before postings, matching the
+    /// non-voided transactions below.
+    const SEED_17: &str = "
+        INSERT INTO users (id, username, password, default_uses, bang_uses, api_key) VALUES (1, 'alice', '', 0, 0, 'key');
+        INSERT INTO accounts (id, owner, name, amount, currency) VALUES
+            (1, 1, '__none', -145750, 'USD'), (2, 1, 'Checking', 84750, 'USD'), (3, 1, 'Savings', 50000, 'USD'), (4, 1, 'Euro', 10000, 'EUR');
+        INSERT INTO categories (id, owner, parent, name) VALUES (1, 1, NULL, 'Food');
+        INSERT INTO transactions (id, owner, f, t, amount, reason, time, voided, category, currency, to_amount, f_status, t_status) VALUES
+            (1, 1, 2, 1, 4250, 'Groceries', '2024-01-03 00:00:00+00:00', 0, 1, 'USD', NULL, 2, 0),
+            (2, 1, 1, 2, 150000, 'Pay', '2024-01-15 00:00:00+00:00', 0, NULL, 'USD', NULL, 0, 1),
+            (3, 1, 2, 3, 50000, 'Save', '2024-01-20 00:00:00+00:00', 0, NULL, 'USD', NULL, 1, 1),
+            (4, 1, 2, 4, 11000, 'Exchange', '2024-01-22 00:00:00+00:00', 0, NULL, 'USD', 10000, 0, 0),
+            (5, 1, 2, 1, 9999, 'Refunded', '2024-01-25 00:00:00+00:00', 1, 1, 'USD', NULL, 0, 0);
+        INSERT INTO transaction_tags (transaction_id, tag) VALUES (1, 'weekly'), (3, 'goal');
+    ";
+
+    fn pairs(conn: &Connection, sql: &str) -> Vec<(i64, i64)> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    fn tags(conn: &Connection) -> Vec<(i64, String)> {
+        let mut stmt = conn.prepare("SELECT transaction_id, tag FROM transaction_tags ORDER BY transaction_id").unwrap();
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    fn check_postings_migration(foreign_keys: bool) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", foreign_keys).unwrap();
+        let migrations = migrations();
+        migrations.to_version(&mut conn, 17).unwrap();
+        conn.execute_batch(SEED_17).unwrap();
+        let amounts = pairs(&conn, "SELECT id, amount FROM accounts ORDER BY id");
+        let old_tags = tags(&conn);
+
+        migrations.to_version(&mut conn, 18).unwrap();
+        assert_eq!(pairs(&conn, "SELECT account, balance FROM account_balances ORDER BY account"), amounts);
+        assert_eq!(tags(&conn), old_tags);
+        assert_eq!(pairs(&conn, "SELECT transaction_id, COUNT(*) FROM postings GROUP BY transaction_id"),
+                   vec![(1, 2), (2, 2), (3, 2), (4, 2), (5, 2)]);
+        // Each leg keeps its own side's status, and the exchange keeps both amounts
+        assert_eq!(pairs(&conn, "SELECT account, status FROM postings WHERE transaction_id = 1 ORDER BY id"),
+                   vec![(2, 2), (1, 0)]);
+        assert_eq!(pairs(&conn, "SELECT amount, received FROM transaction_summaries WHERE id = 4"),
+                   vec![(11000, 10000)]);
+
+        migrations.to_version(&mut conn, 17).unwrap();
+        assert_eq!(pairs(&conn, "SELECT id, amount FROM accounts ORDER BY id"), amounts);
+        assert_eq!(tags(&conn), old_tags);
+        assert_eq!(pairs(&conn, "SELECT id, to_amount FROM transactions WHERE to_amount IS NOT NULL"),
+                   vec![(4, 10000)]);
+    }
+
+    #[test]
+    fn postings_migration_keeps_balances() {
+        check_postings_migration(false);
+    }
+
+    #[test]
+    fn postings_migration_keeps_balances_with_foreign_keys() {
+        check_postings_migration(true);
+    }
+
+    #[test]
+    fn latest_migrations_roll_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = migrations();
+        migrations.to_latest(&mut conn).unwrap();
+        migrations.to_version(&mut conn, 17).unwrap();
+        migrations.to_latest(&mut conn).unwrap();
+    }
+}
@@ -1,20 +1,26 @@
-use {Error, Result};
+use {password, Error, Result};
+use money::Money;
 use types::*;
 use super::Pool;
 
-use chrono::{Datelike, NaiveDate, Utc};
+use chrono::{Datelike, Months, NaiveDate, Utc};
 
 use std::collections::HashMap;
 
-pub fn check_login(pool: &Pool, login: &Login) -> Result<bool> {
+/// Returns the user's id and password hash if the login is valid.
+pub fn check_login(pool: &Pool, login: &Login) -> Result<Option<(i32, String)>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT password FROM users WHERE username = ?1"))?;
-    let password: String = match stmt.query_row(params![login.username], |row| row.get(0)) {
+    let mut stmt = conn.prepare(query!("SELECT id, password FROM users WHERE username = ?1"))?;
+    let (id, hash): (i32, String) = match stmt.query_row(params![login.username], |row| Ok((row.get(0)?, row.get(1)?))) {
         Ok(v) => v,
-        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(false),
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
         Err(e) => return Err(Error::from(e)),
     };
-    Ok(bcrypt::verify(&login.password, &password)?)
+    if password::verify(&login.password, &hash)? {
+        Ok(Some((id, hash)))
+    } else {
+        Ok(None)
+    }
 }
 
 pub fn user_id(pool: &Pool, username: &str) -> Result<i32> {
@@ -23,10 +29,55 @@ pub fn user_id(pool: &Pool, username: &str) -> Result<i32> {
     Ok(stmt.query_row(params![username], |row| row.get(0))?)
 }
 
-pub fn user(pool: &Pool, username: &str) -> Result<Login> {
+pub fn username(pool: &Pool, id: i32) -> Result<Option<String>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT username FROM users WHERE id = ?1"))?;
+    match stmt.query_row(params![id], |row| row.get(0)) {
+        Ok(username) => Ok(Some(username)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn profile(pool: &Pool, id: i32) -> Result<Profile> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT username, display_name, email FROM users WHERE id = ?1"))?;
+    Ok(stmt.query_row(params![id], |row| {
+        Ok(Profile::new(row.get(0)?, row.get(1)?, row.get(2)?))
+    })?)
+}
+
+pub fn user_exists(pool: &Pool, username: &str) -> Result<bool> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT EXISTS (SELECT 1 FROM users WHERE username = ?1)"))?;
+    Ok(stmt.query_row(params![username], |row| row.get(0))?)
+}
+
+pub fn oidc_user(pool: &Pool, issuer: &str, subject: &str) -> Result<Option<i32>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT username, password, api_key FROM users WHERE username = ?1"))?;
-    Ok(stmt.query_row(params![username], |row| {
+    let mut stmt = conn.prepare(query!("SELECT owner FROM oidc_identities WHERE issuer = ?1 AND subject = ?2"))?;
+    match stmt.query_row(params![issuer, subject], |row| row.get(0)) {
+        Ok(id) => Ok(Some(id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// The user a login proxy name is linked to.
+pub fn proxy_user(pool: &Pool, name: &str) -> Result<Option<i32>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT owner FROM proxy_identities WHERE name = ?1"))?;
+    match stmt.query_row(params![name], |row| row.get(0)) {
+        Ok(id) => Ok(Some(id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn user(pool: &Pool, id: i32) -> Result<Login> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT username, password, api_key FROM users WHERE id = ?1"))?;
+    Ok(stmt.query_row(params![id], |row| {
         Ok(Login {
             username: row.get(0)?,
             password: row.get(1)?,
@@ -35,9 +86,9 @@ pub fn user(pool: &Pool, username: &str) -> Result<Login> {
     })?)
 }
 
-pub fn user_by_api_key(pool: &Pool, api_key: &str) -> Result<Option<String>> {
+pub fn user_by_api_key(pool: &Pool, api_key: &str) -> Result<Option<i32>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT username FROM users WHERE api_key = ?1"))?;
+    let mut stmt = conn.prepare(query!("SELECT id FROM users WHERE api_key = ?1"))?;
     match stmt.query_row(params![api_key], |row| row.get(0)) {
         Ok(key) => Ok(key),
         Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
@@ -45,14 +96,13 @@ pub fn user_by_api_key(pool: &Pool, api_key: &str) -> Result<Option<String>> {
     }
 }
 
-pub fn search_uses(pool: &Pool, username: &str) -> Result<(u32, u32)> {
+pub fn search_uses(pool: &Pool, owner: i32) -> Result<(u32, u32)> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT default_uses, bang_uses FROM users WHERE username = ?1"))?;
-    Ok(stmt.query_row(params![username], |row| Ok((row.get(0)?, row.get(1)?)))?)
+    let mut stmt = conn.prepare(query!("SELECT default_uses, bang_uses FROM users WHERE id = ?1"))?;
+    Ok(stmt.query_row(params![owner], |row| Ok((row.get(0)?, row.get(1)?)))?)
 }
 
-pub fn bangs(pool: &Pool, username: &str) -> Result<Vec<Bang>> {
-    let user_id = user_id(pool, username)?;
+pub fn bangs(pool: &Pool, user_id: i32) -> Result<Vec<Bang>> {
     let conn = pool.get()?;
     let mut stmt = conn.prepare(query!("SELECT id, bang, value, uses FROM bangs WHERE owner = ?1"))?;
     let rows = stmt.query_map(params![user_id], |row| {
@@ -71,8 +121,7 @@ pub fn bangs(pool: &Pool, username: &str) -> Result<Vec<Bang>> {
     Ok(bangs)
 }
 
-pub fn bang(pool: &Pool, username: &str, bang: &str) -> Result<(i32, String)> {
-    let user_id = user_id(pool, username)?;
+pub fn bang(pool: &Pool, user_id: i32, bang: &str) -> Result<(i32, String)> {
     let conn = pool.get()?;
     let mut stmt = conn.prepare(query!("SELECT id, value FROM bangs WHERE owner = ?1 AND bang = ?2"))?;
     match stmt.query_row(params![user_id, bang], |row| Ok((row.get(0)?, row.get(1)?))) {
@@ -82,8 +131,7 @@ pub fn bang(pool: &Pool, username: &str, bang: &str) -> Result<(i32, String)> {
     }
 }
 
-pub fn quick_links(pool: &Pool, username: &str) -> Result<Vec<Link>> {
-    let user_id = user_id(pool, username)?;
+pub fn quick_links(pool: &Pool, user_id: i32) -> Result<Vec<Link>> {
     let conn = pool.get()?;
     let mut stmt = conn.prepare(query!("SELECT id, name, url FROM quick_links WHERE owner = ?1"))?;
     let rows = stmt.query_map(params![user_id], |row| {
@@ -101,17 +149,70 @@ pub fn quick_links(pool: &Pool, username: &str) -> Result<Vec<Link>> {
     Ok(links)
 }
 
-pub fn accounts(pool: &Pool, username: &str) -> Result<Vec<Account>> {
-    let id = user_id(pool, username)?;
+pub fn households(pool: &Pool, user: i32) -> Result<Vec<Household>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT id, name, amount FROM accounts WHERE owner = ?1"))?;
-    let rows = stmt.query_map(params![id], |row| {
-        let amount: i64 = row.get(2)?;
+    let mut stmt = conn.prepare(query!("SELECT households.id, households.name, household_members.role FROM households JOIN household_members ON household_members.household = households.id WHERE household_members.member = ?1 ORDER BY households.name"))?;
+    let rows = stmt.query_map(params![user], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut members_stmt = conn.prepare(query!("SELECT users.id, users.username, household_members.role FROM household_members JOIN users ON users.id = household_members.member WHERE household_members.household = ?1 ORDER BY users.username"))?;
+    let mut households = Vec::new();
+    for r in rows {
+        let (id, name, role): (i64, String, i32) = r?;
+        let role = Role::from_i32(role).unwrap_or(Role::Viewer);
+        let members = members_stmt.query_map(params![id], |row| {
+            let role: i32 = row.get(2)?;
+            Ok(Member {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                role: Role::from_i32(role).unwrap_or(Role::Viewer).name(),
+            })
+        })?;
+        let mut m = Vec::new();
+        for member in members {
+            m.push(member?);
+        }
+        households.push(Household {
+            id,
+            name,
+            role: role.name(),
+            can_edit: role >= Role::Editor,
+            is_owner: role == Role::Owner,
+            members: m,
+        });
+    }
+    Ok(households)
+}
+
+pub fn household_role(pool: &Pool, user: i32, household: i64) -> Result<Option<Role>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT role FROM household_members WHERE household = ?1 AND member = ?2"))?;
+    match stmt.query_row(params![household, user], |row| row.get(0)) {
+        Ok(role) => Ok(Role::from_i32(role)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// All unarchived accounts the user owns or shares through a household.
+pub fn accounts(pool: &Pool, user: i32) -> Result<Vec<Account>> {
+    accounts_by_archived(pool, user, false)
+}
+
+pub fn archived_accounts(pool: &Pool, user: i32) -> Result<Vec<Account>> {
+    accounts_by_archived(pool, user, true)
+}
+
+fn accounts_by_archived(pool: &Pool, user: i32, archived: bool) -> Result<Vec<Account>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT accounts.id, accounts.name, account_balances.balance, households.name, household_members.role, accounts.currency FROM accounts JOIN account_balances ON account_balances.account = accounts.id LEFT JOIN households ON households.id = accounts.household LEFT JOIN household_members ON household_members.household = accounts.household AND household_members.member = ?1 WHERE ((accounts.household IS NULL AND accounts.owner = ?1) OR household_members.member = ?1) AND accounts.archived = ?2 ORDER BY accounts.name"))?;
+    let rows = stmt.query_map(params![user, archived], |row| {
+        let role: Option<i32> = row.get(4)?;
         Ok(Account {
             id: row.get(0)?,
             name: row.get(1)?,
-            dollars: amount / 100,
-            cents: (amount.abs() % 100) as u8,
+            household: row.get(3)?,
+            writable: role.map_or(true, |r| r >= Role::Editor as i32),
+            currency: row.get(5)?,
+            balance: row.get(2)?,
         })
     })?;
     let mut accounts = Vec::new();
@@ -124,69 +225,203 @@ pub fn accounts(pool: &Pool, username: &str) -> Result<Vec<Account>> {
     Ok(accounts)
 }
 
-pub fn account_id(pool: &Pool, owner: i32, account: &str) -> Result<i64> {
+/// Resolves an account from a form value, either an account id or `__none`,
+/// if the user may post transactions to it. Archived accounts take no new ones.
+pub fn writable_account(pool: &Pool, user: i32, account: &str) -> Result<Option<i64>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT id FROM accounts WHERE owner = ?1 AND name = ?2"))?;
-    Ok(stmt.query_row(params![owner, account], |row| row.get(0))?)
+    super::writable_account(&conn, user, account)
 }
 
-pub fn account(pool: &Pool, owner: i32, account_id: i64) -> Result<Transactions> {
+/// Resolves the accounts of split legs, returning None if any can't be posted
+/// to by the user.
+pub fn split_accounts(pool: &Pool, user: i32, postings: &[NewPosting]) -> Result<Option<Vec<(i64, Money, Option<i64>)>>> {
+    let mut resolved = Vec::new();
+    for posting in postings {
+        match writable_account(pool, user, &posting.account)? {
+            Some(account) => resolved.push((account, posting.amount, posting.category)),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(resolved))
+}
+
+/// The name and number of transactions of an account the user can edit.
+pub fn account_summary(pool: &Pool, user: i32, account_id: i64) -> Result<Option<(String, i64)>> {
     let conn = pool.get()?;
-    let (name, amount): (String, i64) = conn.query_row(query!("SELECT name, amount FROM accounts WHERE id = ?1 AND owner = ?2"),
-        params![account_id, owner], |row| Ok((row.get(0)?, row.get(1)?)))?;
-    let mut accounts = HashMap::new();
-    accounts.insert(account_id, name.clone());
-    let mut stmt = conn.prepare(query!("SELECT f, t, amount, reason, time FROM transactions WHERE owner = ?1 AND (f = ?2 OR t = ?2) ORDER BY time DESC LIMIT 1000"))?;
-    let rows = stmt.query_map(params![owner, account_id], |row| {
-        let f = row.get(0)?;
-        let t = row.get(1)?;
-        let from = if let Some(f) = accounts.get(&f) {
-            f.clone()
-        } else {
-            let mut stmt = conn.prepare(query!("SELECT name FROM accounts WHERE owner = ?1 AND id = ?2"))?;
-            let s: String = stmt.query_row(params![owner, f], |row| row.get(0))?;
-            accounts.insert(f, s.clone());
-            s
-        };
-        let to = if let Some(t) = accounts.get(&t) {
-            t.clone()
-        } else {
-            let mut stmt = conn.prepare(query!("SELECT name FROM accounts WHERE owner = ?1 AND id = ?2"))?;
-            let s: String = stmt.query_row(params![owner, t], |row| row.get(0))?;
-            accounts.insert(t, s.clone());
-            s
-        };
-        let amount: i64 = row.get(2)?;
-        let date: chrono::DateTime<chrono::Utc> = row.get(4)?;
-        Ok(Transaction {
-            from: if from == "__none" { "PAYMENT".to_string() } else { from },
-            to: if to == "__none" { "EXPENSE".to_string() } else { to },
-            dollars: amount / 100,
-            cents: (amount % 100) as u8,
-            reason: row.get(3)?,
-            date: date.format("%a %b %e %Y @ %T").to_string(),
-        })
+    super::account_summary(&conn, user, account_id)
+}
+
+/// Transactions of an account shown per page of its history.
+const HISTORY_PAGE: usize = 50;
+
+/// A page of the account's transactions matching the filter, newest first,
+/// each with the account's balance after it.
+pub fn account(pool: &Pool, user: i32, account_id: i64, filter: &AccountFilter) -> Result<Transactions> {
+    let conn = pool.get()?;
+    let (name, balance, archived, currency): (String, Money, bool, String) = conn.query_row(query!("SELECT accounts.name, account_balances.balance, accounts.archived, accounts.currency FROM accounts JOIN account_balances ON account_balances.account = accounts.id WHERE accounts.id = ?1 AND ((accounts.household IS NULL AND accounts.owner = ?2) OR accounts.household IN (SELECT household FROM household_members WHERE member = ?2))"),
+        params![account_id, user], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+    // Running balances count back from the balance over every newer
+    // transaction, so they're right whatever the filter leaves out. A
+    // transaction moves the sum of its legs on the account
+    let mut stmt = conn.prepare(query!("WITH history AS (SELECT transactions.id, transactions.voided, transactions.reason, transactions.time, legs.net, legs.status, ?2 - COALESCE(SUM(CASE WHEN transactions.voided THEN 0 ELSE legs.net END) OVER (ORDER BY transactions.time DESC, transactions.id DESC ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING), 0) AS running FROM transactions JOIN (SELECT transaction_id, SUM(amount) AS net, MIN(status) AS status FROM postings WHERE account = ?1 GROUP BY transaction_id) legs ON legs.transaction_id = transactions.id) SELECT history.id, history.voided, s.sources, s.destinations, s.amount, history.reason, s.categories, (SELECT GROUP_CONCAT(tag, ', ') FROM transaction_tags WHERE transaction_id = history.id), history.time, s.currency, s.received, s.received_currency, history.running, history.status FROM history JOIN transaction_summaries s ON s.id = history.id WHERE (?3 IS NULL OR (history.time, history.id) < (SELECT time, id FROM transactions WHERE id = ?3)) AND (?4 IS NULL OR date(history.time) >= ?4) AND (?5 IS NULL OR date(history.time) <= ?5) AND (?6 IS NULL OR abs(history.net) >= ?6) AND (?7 IS NULL OR abs(history.net) <= ?7) AND (?8 IS NULL OR EXISTS (SELECT 1 FROM postings WHERE transaction_id = history.id AND account != ?1 AND (account = ?8 OR (account IN (SELECT id FROM accounts WHERE name = '__none') AND ?8 IN (SELECT id FROM accounts WHERE name = '__none'))))) AND (?9 IS NULL OR instr(lower(history.reason), lower(?9)) > 0) ORDER BY history.time DESC, history.id DESC LIMIT ?10"))?;
+    let rows = stmt.query_map(params![account_id, balance, filter.before, filter.from, filter.to, filter.min, filter.max, filter.counterparty, filter.reason, HISTORY_PAGE as i64 + 1], |row| {
+        let mut transaction = transaction_row(row)?;
+        transaction.balance = Some(row.get(12)?);
+        transaction.status = Status::from_i32(row.get(13)?).map(|s| s.name());
+        Ok(transaction)
     })?;
     let mut transactions = Vec::new();
     for r in rows {
         transactions.push(r?);
     }
+    let cleared = conn.query_row(query!("SELECT COALESCE(SUM(postings.amount), 0) FROM postings JOIN transactions ON transactions.id = postings.transaction_id WHERE transactions.voided = 0 AND postings.account = ?1 AND postings.status >= 1"),
+        params![account_id], |row| row.get(0))?;
+    let older = if transactions.len() > HISTORY_PAGE {
+        transactions.truncate(HISTORY_PAGE);
+        transactions.last().map(|t| t.id)
+    } else {
+        None
+    };
 
     Ok(Transactions {
         account: name,
         id: account_id,
-        dollars: amount / 100,
-        cents: (amount % 100) as u8,
+        archived,
+        currency,
+        balance,
+        cleared,
         transactions,
+        older,
     })
 }
 
-pub fn reminders(pool: &Pool, username: &str) -> Result<Reminders> {
+/// Reads a transaction selected as id, voided, source and destination account
+/// names, amount, reason, category paths, tags, time, currency, amount received
+/// and its currency.
+fn transaction_row(row: &rusqlite::Row) -> rusqlite::Result<Transaction> {
+    let from: Option<String> = row.get(2)?;
+    let to: Option<String> = row.get(3)?;
+    let tags: Option<String> = row.get(7)?;
+    let date: chrono::DateTime<chrono::Utc> = row.get(8)?;
+    let currency: String = row.get(9)?;
+    let received: Money = row.get(10)?;
+    let received_currency: String = row.get(11)?;
+    Ok(Transaction {
+        id: row.get(0)?,
+        voided: row.get(1)?,
+        from: from.unwrap_or_default(),
+        to: to.unwrap_or_default(),
+        amount: row.get(4)?,
+        received: if received_currency != currency { Some(format!("{} {}", received, received_currency)) } else { None },
+        currency,
+        reason: row.get(5)?,
+        category: row.get(6)?,
+        tags: tags.unwrap_or_default(),
+        date: date.format("%a %b %e %Y @ %T").to_string(),
+        balance: None,
+        status: None,
+    })
+}
+
+/// A transaction whose accounts the user can all post to, with its legs in the
+/// order they were entered.
+pub fn transaction(pool: &Pool, user: i32, id: i64) -> Result<Option<TransactionDetails>> {
+    let conn = pool.get()?;
+    let res = conn.query_row(query!("SELECT reason, voided, time, (SELECT GROUP_CONCAT(tag, ', ') FROM transaction_tags WHERE transaction_id = transactions.id) FROM transactions WHERE id = ?2 AND EXISTS (SELECT 1 FROM postings WHERE transaction_id = ?2) AND NOT EXISTS (SELECT 1 FROM postings WHERE transaction_id = ?2 AND account NOT IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1 AND role >= ?3)))"),
+        params![user, id, Role::Editor as i32], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)));
+    let (reason, voided, date, tags): (String, bool, chrono::DateTime<chrono::Utc>, Option<String>) = match res {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+
+    let mut stmt = conn.prepare(query!("SELECT account, amount, category, currency, status FROM postings WHERE transaction_id = ?1 ORDER BY id"))?;
+    let rows = stmt.query_map(params![id], |row| {
+        Ok((PostingDetails {
+            account: row.get(0)?,
+            amount: row.get(1)?,
+            category: row.get(2)?,
+        }, row.get(3)?, row.get(4)?))
+    })?;
+    let mut postings = Vec::new();
+    let mut currencies = Vec::new();
+    let mut locked = false;
+    for r in rows {
+        let (posting, currency, status): (PostingDetails, String, i32) = r?;
+        locked |= Status::from_i32(status) == Some(Status::Reconciled);
+        currencies.push(currency);
+        postings.push(posting);
+    }
+
+    // Two legs are edited as an amount moving from one account to the other
+    let (from, to) = match postings.iter().position(|p| p.amount.is_negative()) {
+        Some(1) => (1, 0),
+        _ => (0, 1),
+    };
+    let to_amount = match (postings.get(to), currencies.get(from), currencies.get(to)) {
+        (Some(received), Some(from_currency), Some(to_currency)) if from_currency != to_currency => received.amount.plain(),
+        _ => String::new(),
+    };
+    Ok(Some(TransactionDetails {
+        id,
+        from: postings.get(from).map_or(0, |p| p.account),
+        to: postings.get(to).map_or(0, |p| p.account),
+        category: postings.iter().filter_map(|p| p.category).next(),
+        tags: tags.unwrap_or_default(),
+        amount: postings.get(from).map(|p| (-p.amount).plain()).unwrap_or_default(),
+        to_amount,
+        reason,
+        voided,
+        locked,
+        date: date.format("%a %b %e %Y @ %T").to_string(),
+        split: postings.len() > 2,
+        postings,
+    }))
+}
+
+/// What's left to reconcile of an account the user can edit, up to the end of
+/// a statement. Returns None if the account can't be edited.
+pub fn reconciliation(pool: &Pool, user: i32, account_id: i64, date: NaiveDate) -> Result<Option<Reconciliation>> {
+    let conn = pool.get()?;
+    let res = conn.query_row(query!("SELECT name, currency, (SELECT COALESCE(SUM(postings.amount), 0) FROM postings JOIN transactions ON transactions.id = postings.transaction_id WHERE transactions.voided = 0 AND postings.account = ?1 AND postings.status = 2) FROM accounts WHERE id = ?1 AND name != '__none' AND ((household IS NULL AND owner = ?2) OR household IN (SELECT household FROM household_members WHERE member = ?2 AND role >= ?3))"),
+        params![account_id, user, Role::Editor as i32], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
+    let (account, currency, reconciled) = match res {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+
+    let mut stmt = conn.prepare(query!("SELECT transactions.id, transactions.time, (SELECT GROUP_CONCAT(CASE WHEN accounts.name = '__none' THEN 'None' ELSE accounts.name END, ', ') FROM postings JOIN accounts ON accounts.id = postings.account WHERE postings.transaction_id = transactions.id AND postings.account != ?1), legs.net, transactions.reason, legs.status FROM transactions JOIN (SELECT transaction_id, SUM(amount) AS net, MIN(status) AS status FROM postings WHERE account = ?1 GROUP BY transaction_id) legs ON legs.transaction_id = transactions.id WHERE transactions.voided = 0 AND date(transactions.time) <= ?2 AND legs.status < 2 AND EXISTS (SELECT 1 FROM postings WHERE transaction_id = transactions.id AND account != ?1) ORDER BY transactions.time, transactions.id"))?;
+    let rows = stmt.query_map(params![account_id, date], |row| {
+        let time: chrono::DateTime<chrono::Utc> = row.get(1)?;
+        let status: i32 = row.get(5)?;
+        Ok(ReconcileRow {
+            id: row.get(0)?,
+            date: time.format("%a %b %e %Y").to_string(),
+            account: row.get(2)?,
+            amount: row.get(3)?,
+            reason: row.get(4)?,
+            cleared: Status::from_i32(status) == Some(Status::Cleared),
+        })
+    })?;
+    let mut transactions = Vec::new();
+    for r in rows {
+        transactions.push(r?);
+    }
+
+    Ok(Some(Reconciliation {
+        account,
+        currency,
+        reconciled,
+        transactions,
+    }))
+}
+
+pub fn reminders(pool: &Pool, owner: i32) -> Result<Reminders> {
     let now = Utc::now();
 
-    let owner = user_id(pool, username)?;
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE owner = ?1 AND recurrence = ?2 AND date >= date() AND date <= date('now', '+1 month') ORDER BY date"))?;
+    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND recurrence = ?2 AND date >= date() AND date <= date('now', '+1 month') ORDER BY date"))?;
     let rows = stmt.query_map(params![owner, Recurrence::None as i32], |row| {
         Ok((row.get(0)?, row.get(1)?))
     })?;
@@ -195,7 +430,7 @@ pub fn reminders(pool: &Pool, username: &str) -> Result<Reminders> {
         non_recurring.push(r?);
     }
 
-    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE owner = ?1 AND recurrence = ?2"))?;
+    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND recurrence = ?2"))?;
     let rows = stmt.query_map(params![owner, Recurrence::Day as i32], |row| {
         Ok((row.get(0)?, row.get(1)?))
     })?;
@@ -204,7 +439,7 @@ pub fn reminders(pool: &Pool, username: &str) -> Result<Reminders> {
         day.push(r?);
     }
 
-    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE owner = ?1 AND recurrence = ?2"))?;
+    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND recurrence = ?2"))?;
     let rows = stmt.query_map(params![owner, Recurrence::Week as i32], |row| {
         Ok((row.get(0)?, row.get(1)?))
     })?;
@@ -219,7 +454,7 @@ pub fn reminders(pool: &Pool, username: &str) -> Result<Reminders> {
     week.rotate_left(p);
     let week = week.into_iter().map(|(r, d)| (r, d.weekday().to_string())).collect();
 
-    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE owner = ?1 AND recurrence = ?2"))?;
+    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND recurrence = ?2"))?;
     let rows = stmt.query_map(params![owner, Recurrence::Month as i32], |row| {
         Ok((row.get(0)?, row.get(1)?))
     })?;
@@ -241,7 +476,7 @@ pub fn reminders(pool: &Pool, username: &str) -> Result<Reminders> {
     }
     month.rotate_left(p);
 
-    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE owner = ?1 AND recurrence = ?2"))?;
+    let mut stmt = conn.prepare(query!("SELECT reason, date FROM reminders WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND recurrence = ?2"))?;
     let rows = stmt.query_map(params![owner, Recurrence::Year as i32], |row| {
         Ok((row.get(0)?, row.get(1)?))
     })?;
@@ -268,11 +503,10 @@ pub fn reminders(pool: &Pool, username: &str) -> Result<Reminders> {
     })
 }
 
-pub fn inventory(pool: &Pool, username: &str) -> Result<Vec<Item>> {
-    let id = user_id(pool, username)?;
+pub fn inventory(pool: &Pool, user: i32) -> Result<Vec<Item>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT id, name, quantity, unit, low_reminder FROM inventory WHERE owner = ?1"))?;
-    let rows = stmt.query_map(params![id], |row| {
+    let mut stmt = conn.prepare(query!("SELECT id, name, quantity, unit, low_reminder, household FROM inventory WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)"))?;
+    let rows = stmt.query_map(params![user], |row| {
         Ok(Item {
             id: row.get(0)?,
             owner: 0,
@@ -280,6 +514,7 @@ pub fn inventory(pool: &Pool, username: &str) -> Result<Vec<Item>> {
             quantity: row.get(2)?,
             unit: row.get(3)?,
             low_reminder: row.get(4)?,
+            household: row.get(5)?,
         })
     })?;
     let mut inventory = Vec::new();
@@ -288,3 +523,414 @@ pub fn inventory(pool: &Pool, username: &str) -> Result<Vec<Item>> {
     }
     Ok(inventory)
 }
+
+/// The user's categories, ordered so subcategories follow their parents.
+pub fn categories(pool: &Pool, user: i32) -> Result<Vec<Category>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("WITH RECURSIVE paths(id, parent, path) AS (SELECT id, parent, name FROM categories WHERE parent IS NULL AND owner = ?1 UNION ALL SELECT categories.id, categories.parent, paths.path || ':' || categories.name FROM categories JOIN paths ON categories.parent = paths.id) SELECT id, parent, path FROM paths ORDER BY path"))?;
+    let rows = stmt.query_map(params![user], |row| {
+        Ok(Category {
+            id: row.get(0)?,
+            parent: row.get(1)?,
+            path: row.get(2)?,
+        })
+    })?;
+    let mut categories = Vec::new();
+    for r in rows {
+        categories.push(r?);
+    }
+    Ok(categories)
+}
+
+/// Spending over the period per category of the user, including subcategories,
+/// in the user's home currency. Spending is the legs into outside money of
+/// transactions moving money out of an account the user can read.
+pub fn category_totals(pool: &Pool, user: i32, period: &Period) -> Result<HashMap<i64, Money>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("WITH RECURSIVE tree(root, id) AS (SELECT id, id FROM categories WHERE owner = ?1 UNION ALL SELECT tree.root, categories.id FROM categories JOIN tree ON categories.parent = tree.id), spending AS (SELECT p.transaction_id, p.amount, p.currency, p.category, transactions.time FROM postings p JOIN accounts oa ON oa.id = p.account JOIN transactions ON transactions.id = p.transaction_id WHERE oa.name = '__none' AND p.amount > 0 AND transactions.voided = 0 AND p.transaction_id IN (SELECT transaction_id FROM postings WHERE amount < 0 AND account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)))) SELECT tree.root, spending.currency, SUM(spending.amount) FROM tree JOIN spending ON spending.category = tree.id WHERE date(spending.time) BETWEEN ?2 AND ?3 GROUP BY tree.root, spending.currency"))?;
+    let rows = stmt.query_map(params![user, period.from, period.to], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut totals = Vec::new();
+    for r in rows {
+        totals.push(r?);
+    }
+    super::home_totals(&conn, user, totals, period.to)
+}
+
+/// Spending over the period without one of the user's categories, in the
+/// user's home currency.
+pub fn uncategorized_total(pool: &Pool, user: i32, period: &Period) -> Result<Money> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("WITH spending AS (SELECT p.transaction_id, p.amount, p.currency, p.category, transactions.time FROM postings p JOIN accounts oa ON oa.id = p.account JOIN transactions ON transactions.id = p.transaction_id WHERE oa.name = '__none' AND p.amount > 0 AND transactions.voided = 0 AND p.transaction_id IN (SELECT transaction_id FROM postings WHERE amount < 0 AND account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)))) SELECT currency, SUM(amount) FROM spending WHERE date(time) BETWEEN ?2 AND ?3 AND (category IS NULL OR category NOT IN (SELECT id FROM categories WHERE owner = ?1)) GROUP BY currency"))?;
+    let rows = stmt.query_map(params![user, period.from, period.to], |row| Ok(((), row.get(0)?, row.get(1)?)))?;
+    let mut totals = Vec::new();
+    for r in rows {
+        totals.push(r?);
+    }
+    Ok(super::home_totals(&conn, user, totals, period.to)?.remove(&()).unwrap_or_default())
+}
+
+/// Spending over the period per tag, in the user's home currency.
+pub fn tag_totals(pool: &Pool, user: i32, period: &Period) -> Result<Vec<(String, Money)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("WITH spending AS (SELECT p.transaction_id, p.amount, p.currency, p.category, transactions.time FROM postings p JOIN accounts oa ON oa.id = p.account JOIN transactions ON transactions.id = p.transaction_id WHERE oa.name = '__none' AND p.amount > 0 AND transactions.voided = 0 AND p.transaction_id IN (SELECT transaction_id FROM postings WHERE amount < 0 AND account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)))) SELECT transaction_tags.tag, spending.currency, SUM(spending.amount) FROM transaction_tags JOIN spending ON spending.transaction_id = transaction_tags.transaction_id WHERE date(spending.time) BETWEEN ?2 AND ?3 GROUP BY transaction_tags.tag, spending.currency"))?;
+    let rows = stmt.query_map(params![user, period.from, period.to], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut totals = Vec::new();
+    for r in rows {
+        totals.push(r?);
+    }
+    let mut totals: Vec<_> = super::home_totals(&conn, user, totals, period.to)?.into_iter().collect();
+    totals.sort();
+    Ok(totals)
+}
+
+/// The spending transactions over the period that make up a report total.
+pub fn report_transactions(pool: &Pool, user: i32, period: &Period, filter: &ReportFilter) -> Result<Vec<Transaction>> {
+    let (kind, category, tag) = match *filter {
+        ReportFilter::Category(id) => (0, Some(id), None),
+        ReportFilter::Tag(ref tag) => (1, None, Some(tag.as_str())),
+        ReportFilter::Uncategorized => (2, None, None),
+    };
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("WITH RECURSIVE sub(id) AS (SELECT ?5 UNION ALL SELECT categories.id FROM categories JOIN sub ON categories.parent = sub.id), spending AS (SELECT p.transaction_id, p.amount, p.currency, p.category, transactions.time FROM postings p JOIN accounts oa ON oa.id = p.account JOIN transactions ON transactions.id = p.transaction_id WHERE oa.name = '__none' AND p.amount > 0 AND transactions.voided = 0 AND p.transaction_id IN (SELECT transaction_id FROM postings WHERE amount < 0 AND account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)))) SELECT transactions.id, transactions.voided, s.sources, s.destinations, s.amount, transactions.reason, s.categories, (SELECT GROUP_CONCAT(tag, ', ') FROM transaction_tags WHERE transaction_id = transactions.id), transactions.time, s.currency, s.received, s.received_currency FROM transactions JOIN transaction_summaries s ON s.id = transactions.id WHERE transactions.id IN (SELECT transaction_id FROM spending WHERE date(time) BETWEEN ?2 AND ?3 AND ((?4 = 0 AND category IN (SELECT id FROM sub)) OR (?4 = 1 AND EXISTS (SELECT 1 FROM transaction_tags WHERE transaction_id = spending.transaction_id AND tag = ?6)) OR (?4 = 2 AND (category IS NULL OR category NOT IN (SELECT id FROM categories WHERE owner = ?1))))) ORDER BY transactions.time DESC"))?;
+    let rows = stmt.query_map(params![user, period.from, period.to, kind, category, tag], transaction_row)?;
+    let mut transactions = Vec::new();
+    for r in rows {
+        transactions.push(r?);
+    }
+    Ok(transactions)
+}
+
+/// The user's budgets for the month containing `month`, as of `today`.
+pub fn budgets(pool: &Pool, user: i32, month: NaiveDate, today: NaiveDate) -> Result<Vec<Budget>> {
+    let month_start = month.with_day(1).unwrap();
+    let month_end = month_start + Months::new(1);
+    let categories: HashMap<i64, String> = categories(pool, user)?
+        .into_iter()
+        .map(|c| (c.id, c.path))
+        .collect();
+
+    let conn = pool.get()?;
+    // Budgets on accounts the user can no longer read are left out
+    let mut stmt = conn.prepare(query!("SELECT budgets.id, budgets.category, budgets.account, accounts.name, budgets.amount, budgets.rollover, budgets.start FROM budgets LEFT JOIN accounts ON accounts.id = budgets.account WHERE budgets.owner = ?1 AND (budgets.account IS NULL OR budgets.account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1))) ORDER BY budgets.id"))?;
+    let rows = stmt.query_map(params![user], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+    })?;
+
+    let mut budgets = Vec::new();
+    for r in rows {
+        let (id, category, account, account_name, amount, rollover, start): (i64, Option<i64>, Option<i64>, Option<String>, Money, bool, NaiveDate) = r?;
+        let name = match (category, account_name) {
+            (Some(category), _) => match categories.get(&category) {
+                Some(path) => path.clone(),
+                None => continue,
+            },
+            (None, Some(name)) => name,
+            (None, None) => continue,
+        };
+        // Spending from the budget's first month, or just this one without rollover
+        let from = if rollover { start.min(month_start) } else { month_start };
+        let spending = budget_spending(&conn, user, category, account, from, month_end)?;
+
+        let mut carried = Money::ZERO;
+        if rollover {
+            let mut m = start;
+            while m < month_start {
+                let spent = spending.get(&m.format("%Y-%m").to_string()).cloned().unwrap_or_default();
+                carried = (carried + amount - spent).max(Money::ZERO);
+                m = m + Months::new(1);
+            }
+        }
+        let available = amount + carried;
+        let spent = spending.get(&month_start.format("%Y-%m").to_string()).cloned().unwrap_or_default();
+
+        let projected = if today >= month_start && today < month_end {
+            let elapsed = (today - month_start).num_days() + 1;
+            let days = (month_end - month_start).num_days();
+            Money::from_minor(spent.minor() * days / elapsed)
+        } else {
+            spent
+        };
+        let percent = if available > Money::ZERO {
+            spent.minor() * 100 / available.minor()
+        } else if spent > Money::ZERO {
+            100
+        } else {
+            0
+        };
+
+        budgets.push(Budget {
+            id,
+            name,
+            rollover,
+            available,
+            spent,
+            remaining: available - spent,
+            projected_overrun: if projected > available { Some(projected - available) } else { None },
+            percent,
+            warning: percent >= 80,
+            over: percent >= 100,
+        });
+    }
+    Ok(budgets)
+}
+
+/// Spending per `%Y-%m` month on a budget's category or account in the user's
+/// home currency, from `from` up to but excluding `to`.
+fn budget_spending(conn: &rusqlite::Connection, user: i32, category: Option<i64>, account: Option<i64>, from: NaiveDate, to: NaiveDate) -> Result<HashMap<String, Money>> {
+    let mut spending = Vec::new();
+    if let Some(category) = category {
+        let mut stmt = conn.prepare(query!("WITH RECURSIVE sub(id) AS (SELECT ?2 UNION ALL SELECT categories.id FROM categories JOIN sub ON categories.parent = sub.id), spending AS (SELECT p.transaction_id, p.amount, p.currency, p.category, transactions.time FROM postings p JOIN accounts oa ON oa.id = p.account JOIN transactions ON transactions.id = p.transaction_id WHERE oa.name = '__none' AND p.amount > 0 AND transactions.voided = 0 AND p.transaction_id IN (SELECT transaction_id FROM postings WHERE amount < 0 AND account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)))) SELECT strftime('%Y-%m', time), currency, SUM(amount) FROM spending WHERE category IN (SELECT id FROM sub) AND date(time) >= ?3 AND date(time) < ?4 GROUP BY 1, 2"))?;
+        let rows = stmt.query_map(params![user, category, from, to], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for r in rows {
+            spending.push(r?);
+        }
+    } else if let Some(account) = account {
+        let mut stmt = conn.prepare(query!("SELECT strftime('%Y-%m', transactions.time), legs.currency, SUM(legs.net) FROM transactions JOIN (SELECT transaction_id, currency, SUM(amount) AS net FROM postings WHERE account = ?1 GROUP BY transaction_id, currency) legs ON legs.transaction_id = transactions.id WHERE transactions.voided = 0 AND legs.net > 0 AND date(transactions.time) >= ?2 AND date(transactions.time) < ?3 GROUP BY 1, 2"))?;
+        let rows = stmt.query_map(params![account, from, to], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        for r in rows {
+            spending.push(r?);
+        }
+    }
+    super::home_totals(conn, user, spending, to)
+}
+
+/// The user's scheduled transactions that have occurrences left, soonest first.
+pub fn scheduled_transactions(pool: &Pool, user: i32, today: NaiveDate) -> Result<Vec<ScheduledTransaction>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT scheduled_transactions.id, fa.name, ta.name, scheduled_transactions.amount, scheduled_transactions.reason, scheduled_transactions.recurrence, scheduled_transactions.next_date, scheduled_transactions.end_date FROM scheduled_transactions JOIN accounts fa ON fa.id = scheduled_transactions.f JOIN accounts ta ON ta.id = scheduled_transactions.t WHERE scheduled_transactions.owner = ?1 AND scheduled_transactions.next_date IS NOT NULL ORDER BY scheduled_transactions.next_date"))?;
+    let rows = stmt.query_map(params![user], |row| {
+        let from: String = row.get(1)?;
+        let to: String = row.get(2)?;
+        let recurrence: i32 = row.get(5)?;
+        let next: NaiveDate = row.get(6)?;
+        Ok(ScheduledTransaction {
+            id: row.get(0)?,
+            from: if from == "__none" { "PAYMENT".to_string() } else { from },
+            to: if to == "__none" { "EXPENSE".to_string() } else { to },
+            amount: row.get(3)?,
+            reason: row.get(4)?,
+            recurrence: Recurrence::from_i32(recurrence).unwrap_or(Recurrence::None).name(),
+            next,
+            end: row.get(7)?,
+            due: next <= today,
+        })
+    })?;
+    let mut scheduled = Vec::new();
+    for r in rows {
+        scheduled.push(r?);
+    }
+    Ok(scheduled)
+}
+
+/// Ids of all scheduled transactions with an occurrence due by `today`.
+pub fn due_schedules(pool: &Pool, today: NaiveDate) -> Result<Vec<i64>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id FROM scheduled_transactions WHERE next_date <= ?1 ORDER BY next_date"))?;
+    let rows = stmt.query_map(params![today], |row| row.get(0))?;
+    let mut due = Vec::new();
+    for r in rows {
+        due.push(r?);
+    }
+    Ok(due)
+}
+
+pub fn import_profiles(pool: &Pool, user: i32) -> Result<Vec<ImportProfile>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, name, delimiter, has_header, date_column, date_format, amount_column, sign, credit_column, description_column, balance_column, decimal FROM import_profiles WHERE owner = ?1 ORDER BY name"))?;
+    let rows = stmt.query_map(params![user], import_profile_row)?;
+    let mut profiles = Vec::new();
+    for r in rows {
+        profiles.push(r?);
+    }
+    Ok(profiles)
+}
+
+pub fn import_profile(pool: &Pool, user: i32, id: i64) -> Result<Option<ImportProfile>> {
+    let conn = pool.get()?;
+    let res = conn.query_row(query!("SELECT id, name, delimiter, has_header, date_column, date_format, amount_column, sign, credit_column, description_column, balance_column, decimal FROM import_profiles WHERE owner = ?1 AND id = ?2"),
+        params![user, id], import_profile_row);
+    match res {
+        Ok(profile) => Ok(Some(profile)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+fn import_profile_row(row: &rusqlite::Row) -> rusqlite::Result<ImportProfile> {
+    let delimiter: String = row.get(2)?;
+    let column = |i: usize| -> rusqlite::Result<usize> { Ok(row.get::<_, i64>(i)? as usize) };
+    let optional_column = |i: usize| -> rusqlite::Result<Option<usize>> { Ok(row.get::<_, Option<i64>>(i)?.map(|c| c as usize)) };
+    let sign: i32 = row.get(7)?;
+    let decimal: String = row.get(11)?;
+    Ok(ImportProfile {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        columns: ImportColumns {
+            delimiter: delimiter.bytes().next().unwrap_or(b','),
+            has_header: row.get(3)?,
+            date: column(4)?,
+            date_format: row.get(5)?,
+            amount: column(6)?,
+            sign: AmountSign::from_i32(sign).unwrap_or(AmountSign::Normal),
+            credit: optional_column(8)?,
+            description: column(9)?,
+            balance: optional_column(10)?,
+            decimal: decimal.chars().next().unwrap_or('.'),
+        },
+    })
+}
+
+/// Marks rows already imported to the account as duplicates.
+pub fn mark_duplicates(pool: &Pool, account: i64, rows: &mut [ImportRow]) -> Result<()> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT EXISTS (SELECT 1 FROM transactions WHERE import_hash = ?2 AND id IN (SELECT transaction_id FROM postings WHERE account = ?1))"))?;
+    for row in rows {
+        row.duplicate = stmt.query_row(params![account, row.hash], |r| r.get(0))?;
+    }
+    Ok(())
+}
+
+/// Transactions over the period involving an account the user can read, oldest
+/// first, with their legs. Voided transactions are left out.
+pub fn export_transactions(pool: &Pool, user: i32, period: &Period) -> Result<Vec<ExportTransaction>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, date(time), reason, (SELECT GROUP_CONCAT(tag, ',') FROM transaction_tags WHERE transaction_id = transactions.id) FROM transactions WHERE voided = 0 AND date(time) BETWEEN ?2 AND ?3 AND id IN (SELECT transaction_id FROM postings WHERE account IN (SELECT id FROM accounts WHERE (household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1))) ORDER BY time, id"))?;
+    let rows = stmt.query_map(params![user, period.from, period.to], |row| {
+        let reason: Option<String> = row.get(2)?;
+        let tags: Option<String> = row.get(3)?;
+        Ok(ExportTransaction {
+            id: row.get(0)?,
+            date: row.get(1)?,
+            reason: reason.unwrap_or_default(),
+            tags: tags.map(|t| t.split(',').map(String::from).collect()).unwrap_or_default(),
+            postings: Vec::new(),
+        })
+    })?;
+    let mut transactions = Vec::new();
+    for r in rows {
+        transactions.push(r?);
+    }
+
+    let mut stmt = conn.prepare(query!("WITH RECURSIVE paths(id, path) AS (SELECT id, name FROM categories WHERE parent IS NULL UNION ALL SELECT categories.id, paths.path || ':' || categories.name FROM categories JOIN paths ON categories.parent = paths.id) SELECT accounts.name, postings.amount, postings.currency, paths.path FROM postings JOIN accounts ON accounts.id = postings.account LEFT JOIN paths ON paths.id = postings.category WHERE postings.transaction_id = ?1 ORDER BY postings.id"))?;
+    for transaction in &mut transactions {
+        let rows = stmt.query_map(params![transaction.id], |row| {
+            Ok(ExportPosting {
+                account: row.get(0)?,
+                amount: row.get(1)?,
+                currency: row.get(2)?,
+                category: row.get(3)?,
+            })
+        })?;
+        for r in rows {
+            transaction.postings.push(r?);
+        }
+    }
+    Ok(transactions)
+}
+
+/// The user's exchange rates, newest first.
+pub fn exchange_rates(pool: &Pool, user: i32) -> Result<Vec<ExchangeRate>> {
+    let conn = pool.get()?;
+    super::exchange_rates(&conn, user)
+}
+
+pub fn home_currency(pool: &Pool, user: i32) -> Result<String> {
+    let conn = pool.get()?;
+    super::home_currency(&conn, user)
+}
+
+/// The total balance of the unarchived accounts the user can read, in their
+/// home currency at the rates nearest `date`.
+pub fn net_worth(pool: &Pool, user: i32, date: NaiveDate) -> Result<Money> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT accounts.currency, SUM(account_balances.balance) FROM accounts JOIN account_balances ON account_balances.account = accounts.id WHERE ((accounts.household IS NULL AND accounts.owner = ?1) OR accounts.household IN (SELECT household FROM household_members WHERE member = ?1)) AND accounts.name != '__none' AND accounts.archived = 0 GROUP BY accounts.currency"))?;
+    let rows = stmt.query_map(params![user], |row| Ok(((), row.get(0)?, row.get(1)?)))?;
+    let mut totals = Vec::new();
+    for r in rows {
+        totals.push(r?);
+    }
+    Ok(super::home_totals(&conn, user, totals, date)?.remove(&()).unwrap_or_default())
+}
+
+/// Daily balances of an account the user can read from `from`, or its first
+/// transaction, through `to`.
+pub fn balance_history(pool: &Pool, user: i32, account: i64, from: Option<NaiveDate>, to: NaiveDate) -> Result<Vec<(NaiveDate, Money)>> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    let first: Option<NaiveDate> = tx.query_row(query!("SELECT (SELECT MIN(date(transactions.time)) FROM postings JOIN transactions ON transactions.id = postings.transaction_id WHERE postings.account = ?1) FROM accounts WHERE id = ?1 AND ((household IS NULL AND owner = ?2) OR household IN (SELECT household FROM household_members WHERE member = ?2))"),
+        params![account, user], |row| row.get(0))?;
+    let history = match from.or(first) {
+        Some(from) => super::daily_balances(&tx, account, from, to)?,
+        None => Vec::new(),
+    };
+    tx.commit()?;
+    Ok(history)
+}
+
+/// Total daily balances of the unarchived accounts the user can read, in their
+/// home currency at each day's nearest rates, from `from`, or the first
+/// transaction, through `to`.
+pub fn net_worth_history(pool: &Pool, user: i32, from: Option<NaiveDate>, to: NaiveDate) -> Result<Vec<(NaiveDate, Money)>> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    let mut accounts = Vec::new();
+    let first: Option<NaiveDate> = {
+        let mut stmt = tx.prepare(query!("SELECT id, currency FROM accounts WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND name != '__none' AND archived = 0"))?;
+        let rows = stmt.query_map(params![user], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        for r in rows {
+            let (id, currency): (i64, String) = r?;
+            accounts.push((id, currency));
+        }
+        tx.query_row(query!("WITH readable(id) AS (SELECT id FROM accounts WHERE ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1)) AND name != '__none' AND archived = 0) SELECT MIN(date(transactions.time)) FROM postings JOIN transactions ON transactions.id = postings.transaction_id WHERE postings.account IN readable"),
+            params![user], |row| row.get(0))?
+    };
+    let from = match from.or(first) {
+        Some(from) => from,
+        None => return Ok(Vec::new()),
+    };
+
+    let home = super::home_currency(&tx, user)?;
+    let rates = super::rates(&tx, user)?;
+    let mut history: Vec<(NaiveDate, Money)> = from.iter_days()
+        .take_while(|&d| d <= to)
+        .map(|d| (d, Money::ZERO))
+        .collect();
+    for (id, currency) in accounts {
+        let balances = super::daily_balances(&tx, id, from, to)?;
+        for (day, (_, balance)) in history.iter_mut().zip(balances) {
+            if let Some(balance) = rates.convert(balance, &currency, &home, day.0) {
+                day.1 += balance;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(history)
+}
+
+/// What the unarchived accounts the user can read with positive balances hold
+/// and those with negative balances owe, in the user's home currency at the
+/// rates nearest `date`.
+pub fn assets_and_liabilities(pool: &Pool, user: i32, date: NaiveDate) -> Result<(Money, Money)> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT account_balances.balance >= 0, accounts.currency, SUM(account_balances.balance) FROM accounts JOIN account_balances ON account_balances.account = accounts.id WHERE ((accounts.household IS NULL AND accounts.owner = ?1) OR accounts.household IN (SELECT household FROM household_members WHERE member = ?1)) AND accounts.name != '__none' AND accounts.archived = 0 GROUP BY 1, 2"))?;
+    let rows = stmt.query_map(params![user], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut totals = Vec::new();
+    for r in rows {
+        totals.push(r?);
+    }
+    let mut totals: HashMap<bool, Money> = super::home_totals(&conn, user, totals, date)?;
+    Ok((totals.remove(&true).unwrap_or_default(), -totals.remove(&false).unwrap_or_default()))
+}
+
+/// Currencies of accounts the user can read that have no rate to their home
+/// currency, so are left out of converted totals.
+pub fn unconverted_currencies(pool: &Pool, user: i32) -> Result<Vec<String>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("WITH home(currency) AS (SELECT home_currency FROM users WHERE id = ?1) SELECT DISTINCT accounts.currency FROM accounts, home WHERE ((accounts.household IS NULL AND accounts.owner = ?1) OR accounts.household IN (SELECT household FROM household_members WHERE member = ?1)) AND accounts.name != '__none' AND accounts.currency != home.currency AND NOT EXISTS (SELECT 1 FROM exchange_rates WHERE owner = ?1 AND ((base = accounts.currency AND quote = home.currency) OR (base = home.currency AND quote = accounts.currency))) ORDER BY accounts.currency"))?;
+    let rows = stmt.query_map(params![user], |row| row.get(0))?;
+    let mut currencies = Vec::new();
+    for r in rows {
+        currencies.push(r?);
+    }
+    Ok(currencies)
+}
@@ -1,32 +1,40 @@
-use Result;
+use {Error, Result};
 use types::*;
 use super::Pool;
 
-use chrono::Utc;
+use chrono::{DateTime, NaiveDate, Utc};
 
-pub fn password(pool: &Pool, password: &Login) -> Result<()> {
+pub fn password(pool: &Pool, id: i32, password_hash: &str) -> Result<()> {
     let conn = pool.get()?;
-    conn.execute(query!("UPDATE users SET password = ?1 WHERE username = ?2"),
-        params![password.password, password.username])?;
+    conn.execute(query!("UPDATE users SET password = ?1 WHERE id = ?2"),
+        params![password_hash, id])?;
     Ok(())
 }
 
-pub fn new_api_key(pool: &Pool, username: &str) -> Result<()> {
+/// Returns false if the new username is already taken.
+pub fn profile(pool: &Pool, id: i32, profile: &UpdateProfile) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("UPDATE users SET username = ?2, display_name = ?3, email = ?4 WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM users WHERE username = ?2 AND id != ?1)"),
+        params![id, profile.username, profile.display_name, profile.email])?;
+    Ok(n == 1)
+}
+
+pub fn new_api_key(pool: &Pool, id: i32) -> Result<()> {
     let conn = pool.get()?;
     let api_key = Login::gen_api_key();
-    conn.execute(query!("UPDATE users SET api_key = ?1 WHERE username = ?2"),
-        params![api_key, username])?;
+    conn.execute(query!("UPDATE users SET api_key = ?1 WHERE id = ?2"),
+        params![api_key, id])?;
     Ok(())
 }
 
-pub fn search_uses(pool: &Pool, username: &str, bang: i32, defaultp: bool) -> Result<()> {
+pub fn search_uses(pool: &Pool, owner: i32, bang: i32, defaultp: bool) -> Result<()> {
     let conn = pool.get()?;
     if defaultp {
-        conn.execute(query!("UPDATE users SET default_uses = default_uses + 1 WHERE username = ?1"),
-            params![username])?;
+        conn.execute(query!("UPDATE users SET default_uses = default_uses + 1 WHERE id = ?1"),
+            params![owner])?;
     } else {
-        conn.execute(query!("UPDATE users SET bang_uses = bang_uses + 1 WHERE username = ?1"),
-            params![username])?;
+        conn.execute(query!("UPDATE users SET bang_uses = bang_uses + 1 WHERE id = ?1"),
+            params![owner])?;
     }
     conn.execute(query!("UPDATE bangs SET uses = uses + 1 WHERE id = ?1"),
         params![bang])?;
@@ -47,14 +55,143 @@ pub fn quick_link(pool: &Pool, link: &Link) -> Result<()> {
     Ok(())
 }
 
-pub fn inventory_set_quantity(pool: &Pool, owner: i32, item_id: i32, quantity: i32) -> Result<()> {
+/// Returns false if the account doesn't exist or the user can't edit it.
+pub fn account(pool: &Pool, user: i32, account_id: i64, account: &EditAccount) -> Result<bool> {
+    let conn = pool.get()?;
+    let n = conn.execute(query!("UPDATE accounts SET name = ?3, archived = ?4 WHERE id = ?2 AND name != '__none' AND ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1 AND role >= ?5))"),
+        params![user, account_id, account.name, account.archived, Role::Editor as i32])?;
+    Ok(n == 1)
+}
+
+/// Replaces the accounts, amount and reason of a transaction with a leg out of
+/// one account and one into the other. Returns false if the user can't post to
+/// either the old or the new accounts, or the transaction is reconciled.
+pub fn transaction(pool: &Pool, user: i32, id: i64, transaction: NewTransaction) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    if !super::unlocked_transaction(&tx, user, id)? {
+        return Ok(false);
+    }
+    let from = super::read::writable_account(pool, user, &transaction.from)?;
+    let to = super::read::writable_account(pool, user, &transaction.to)?;
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Ok(false),
+    };
+
+    let time: DateTime<Utc> = tx.query_row(query!("SELECT time FROM transactions WHERE id = ?1"),
+        params![id], |row| row.get(0))?;
+    let legs = super::transfer_legs(&tx, user, from, to, transaction.amount, transaction.to_amount, transaction.category, time.date_naive())?;
+    tx.execute(query!("UPDATE transactions SET reason = ?2 WHERE id = ?1"),
+        params![id, transaction.reason])?;
+    super::set_postings(&tx, id, user, &legs)?;
+    super::set_tags(&tx, id, &transaction.tags)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Replaces the legs, reason and tags of a transaction. Returns false if the
+/// user can't post to either the old or the new accounts, or the transaction
+/// is reconciled.
+pub fn split(pool: &Pool, user: i32, id: i64, split: NewSplit) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    if !super::unlocked_transaction(&tx, user, id)? {
+        return Ok(false);
+    }
+    let postings = match super::read::split_accounts(pool, user, &split.postings)? {
+        Some(postings) => postings,
+        None => return Ok(false),
+    };
+
+    let legs = super::split_legs(&tx, &postings)?;
+    tx.execute(query!("UPDATE transactions SET reason = ?2 WHERE id = ?1"),
+        params![id, split.reason])?;
+    super::set_postings(&tx, id, user, &legs)?;
+    super::set_tags(&tx, id, &split.tags)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Voids or restores a transaction. Voided transactions are kept in the history
+/// but don't count towards balances. Reconciled transactions can't be voided.
+pub fn void_transaction(pool: &Pool, user: i32, id: i64, voided: bool) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    if !super::unlocked_transaction(&tx, user, id)? {
+        return Ok(false);
+    }
+    tx.execute(query!("UPDATE transactions SET voided = ?2 WHERE id = ?1"),
+        params![id, voided])?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Marks the given transactions of an account the user can edit cleared, and
+/// its other unreconciled ones up to `date` pending. Returns false if the
+/// account can't be edited.
+pub fn clear_transactions(pool: &Pool, user: i32, account: i64, date: NaiveDate, cleared: &[i64]) -> Result<bool> {
+    if super::read::account_summary(pool, user, account)?.is_none() {
+        return Ok(false);
+    }
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+
+    tx.execute(query!("UPDATE postings SET status = 0 WHERE account = ?1 AND status = 1 AND transaction_id IN (SELECT id FROM transactions WHERE date(time) <= ?2)"),
+        params![account, date])?;
+    for id in cleared {
+        tx.execute(query!("UPDATE postings SET status = 1 WHERE account = ?1 AND transaction_id = ?2 AND status = 0 AND transaction_id IN (SELECT id FROM transactions WHERE voided = 0 AND date(time) <= ?3)"),
+            params![account, id, date])?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Locks the cleared transactions of an account the user can edit up to
+/// `date` as reconciled. Returns false if the account can't be edited.
+pub fn reconcile(pool: &Pool, user: i32, account: i64, date: NaiveDate) -> Result<bool> {
+    if super::read::account_summary(pool, user, account)?.is_none() {
+        return Ok(false);
+    }
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE postings SET status = 2 WHERE account = ?1 AND status = 1 AND transaction_id IN (SELECT id FROM transactions WHERE voided = 0 AND date(time) <= ?2)"),
+        params![account, date])?;
+    Ok(true)
+}
+
+/// Moves one of a user's scheduled transactions on to its next occurrence
+/// without posting the one passed over. Returns false if nothing is scheduled.
+pub fn skip_schedule(pool: &Pool, user: i32, id: i64) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    if super::advance_schedule(&tx, id, Some(user))?.is_none() {
+        return Ok(false);
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Returns false if the item doesn't exist or the user can't edit it.
+pub fn inventory_set_quantity(pool: &Pool, user: i32, item_id: i32, quantity: i32) -> Result<bool> {
     let conn = pool.get()?;
-    let (name, low_reminder): (String, i32) = conn.query_row(query!("UPDATE inventory SET quantity = ?3 WHERE owner = ?1 and id = ?2 RETURNING name, low_reminder"),
-        params![owner, item_id, quantity],
-        |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let res = conn.query_row(query!("UPDATE inventory SET quantity = ?3 WHERE id = ?2 AND ((household IS NULL AND owner = ?1) OR household IN (SELECT household FROM household_members WHERE member = ?1 AND role >= ?4)) RETURNING name, low_reminder, household"),
+        params![user, item_id, quantity, Role::Editor as i32],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
+    let (name, low_reminder, household): (String, i32, Option<i64>) = match res {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(false),
+        Err(e) => return Err(Error::from(e)),
+    };
     if quantity <= low_reminder {
-        let reminder = Reminder { recurrence: Recurrence::None, reason: format!("Buy more {}", name), date: Utc::now().date_naive() + ::chrono::Days::new(7) };
-        super::create::reminder(pool, owner, reminder)?;
+        let reminder = Reminder { recurrence: Recurrence::None, reason: format!("Buy more {}", name), date: Utc::now().date_naive() + ::chrono::Days::new(7), household };
+        super::create::reminder(pool, user, reminder)?;
     }
+    Ok(true)
+}
+
+pub fn home_currency(pool: &Pool, user: i32, currency: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET home_currency = ?2 WHERE id = ?1"),
+        params![user, currency])?;
     Ok(())
 }
@@ -0,0 +1,173 @@
+//! Writers for plain-text accounting journals and CSV.
+
+use money::Money;
+use types::{ExportPosting, ExportTransaction};
+
+use chrono::NaiveDate;
+use csv::Writer;
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// A leg's account as a journal account name, a list of components. Outside
+/// money becomes income when it pays into an account and an expense when paid
+/// out, named after the leg's category.
+fn posting_account(posting: &ExportPosting) -> Vec<String> {
+    if posting.account != "__none" {
+        return vec!["Assets".to_string(), posting.account.clone()];
+    }
+    let mut name = vec![if posting.amount.is_negative() { "Income" } else { "Expenses" }.to_string()];
+    match posting.category {
+        Some(ref category) => name.extend(category.split(':').map(String::from)),
+        None => name.push("Uncategorized".to_string()),
+    }
+    name
+}
+
+/// A ledger amount, with dollars written the usual way.
+fn ledger_amount(amount: Money, currency: &str) -> String {
+    if currency == "USD" {
+        format!("${}", amount.plain())
+    } else {
+        format!("{} {}", amount.plain(), currency)
+    }
+}
+
+/// The amount of each leg. Transfers between currencies price the amount
+/// paid at the amount received.
+fn posting_amounts(transaction: &ExportTransaction, amount: fn(Money, &str) -> String) -> Vec<String> {
+    let legs = &transaction.postings;
+    if legs.len() == 2 && legs[0].currency != legs[1].currency {
+        legs.iter().zip(legs.iter().rev())
+            .map(|(leg, other)| if leg.amount.is_negative() {
+                format!("{} @@ {}", amount(leg.amount, &leg.currency), amount(other.amount, &other.currency))
+            } else {
+                amount(leg.amount, &leg.currency)
+            })
+            .collect()
+    } else {
+        legs.iter().map(|leg| amount(leg.amount, &leg.currency)).collect()
+    }
+}
+
+/// Ledger and hledger allow anything in account names but colons, which
+/// separate components, and double spaces or tabs, which end the name.
+fn ledger_account(components: &[String]) -> String {
+    components.iter()
+        .map(|c| c.replace(':', "-").replace('\t', " ").split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Writes a journal readable by both ledger and hledger.
+pub fn ledger(transactions: &[ExportTransaction]) -> String {
+    let mut journal = String::new();
+    for transaction in transactions {
+        let description = transaction.reason.replace('\n', " ");
+        writeln!(journal, "{} {}", transaction.date.format("%Y-%m-%d"), description.trim()).unwrap();
+        if !transaction.tags.is_empty() {
+            writeln!(journal, "    ; :{}:", transaction.tags.iter()
+                .map(|t| t.replace(':', "-").replace(' ', "-"))
+                .collect::<Vec<_>>()
+                .join(":")).unwrap();
+        }
+        writeln!(journal, "    ; hestia-id: {}", transaction.id).unwrap();
+        for (posting, amount) in transaction.postings.iter().zip(posting_amounts(transaction, ledger_amount)) {
+            writeln!(journal, "    {}  {}", ledger_account(&posting_account(posting)), amount).unwrap();
+        }
+        journal.push('\n');
+    }
+    journal
+}
+
+/// Beancount account components start with a capital letter or digit and
+/// contain only letters, digits and dashes.
+fn beancount_account(components: &[String]) -> String {
+    components.iter()
+        .map(|c| {
+            let mut component: String = c.split_whitespace()
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<String>()
+                .chars()
+                .map(|ch| if ch.is_ascii_alphanumeric() || ch == '-' { ch } else { '-' })
+                .collect();
+            if !component.starts_with(|ch: char| ch.is_ascii_uppercase() || ch.is_ascii_digit()) {
+                component.insert(0, 'X');
+            }
+            component
+        })
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn beancount_amount(amount: Money, currency: &str) -> String {
+    format!("{} {}", amount.plain(), currency)
+}
+
+fn beancount_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', " "))
+}
+
+/// Writes a beancount file in the `home` operating currency, opening every
+/// account on `opened`.
+pub fn beancount(transactions: &[ExportTransaction], opened: NaiveDate, home: &str) -> String {
+    let mut accounts = BTreeSet::new();
+    for transaction in transactions {
+        for posting in &transaction.postings {
+            accounts.insert(beancount_account(&posting_account(posting)));
+        }
+    }
+
+    let mut file = String::new();
+    writeln!(file, "option \"operating_currency\" \"{}\"\n", home).unwrap();
+    for account in &accounts {
+        writeln!(file, "{} open {}", opened.format("%Y-%m-%d"), account).unwrap();
+    }
+    file.push('\n');
+
+    for transaction in transactions {
+        write!(file, "{} * {}", transaction.date.format("%Y-%m-%d"), beancount_string(&transaction.reason)).unwrap();
+        for tag in &transaction.tags {
+            let tag: String = tag.chars()
+                .map(|ch| if ch.is_ascii_alphanumeric() || "-_/.".contains(ch) { ch } else { '-' })
+                .collect();
+            write!(file, " #{}", tag).unwrap();
+        }
+        file.push('\n');
+        writeln!(file, "  hestia-id: {}", beancount_string(&transaction.id.to_string())).unwrap();
+        for (posting, amount) in transaction.postings.iter().zip(posting_amounts(transaction, beancount_amount)) {
+            writeln!(file, "  {}  {}", beancount_account(&posting_account(posting)), amount).unwrap();
+        }
+        file.push('\n');
+    }
+    file
+}
+
+/// Writes one row per leg, the legs of a transaction sharing its id, with
+/// outside money named as in journals.
+pub fn csv(transactions: &[ExportTransaction]) -> Result<String, ::csv::Error> {
+    let mut writer = Writer::from_writer(Vec::new());
+    writer.write_record(&["date", "id", "account", "amount", "currency", "reason", "category", "tags"])?;
+    for transaction in transactions {
+        for posting in &transaction.postings {
+            writer.write_record(&[
+                transaction.date.format("%Y-%m-%d").to_string(),
+                transaction.id.to_string(),
+                ledger_account(&posting_account(posting)),
+                posting.amount.plain(),
+                posting.currency.clone(),
+                transaction.reason.clone(),
+                posting.category.clone().unwrap_or_default(),
+                transaction.tags.join(","),
+            ])?;
+        }
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8(bytes).unwrap_or_default())
+}
@@ -0,0 +1,105 @@
+use money::Money;
+use types::{AmountSign, ImportColumns, ImportRow, NewExchangeRate};
+use super::{row, ImportError, Statement};
+
+use chrono::NaiveDate;
+use csv::{ReaderBuilder, StringRecord};
+
+/// Parses CSV statement text with the given column mapping. The ledger balance
+/// is the balance column of the latest row, taking the last of rows on the same
+/// day.
+pub fn parse(text: &str, columns: &ImportColumns) -> Result<Statement, ImportError> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(columns.delimiter)
+        .has_headers(columns.has_header)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line() as usize);
+        // Skip blank lines
+        if record.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+
+        let date = field(&record, columns.date, "date", line)?;
+        let date = NaiveDate::parse_from_str(date, &columns.date_format)
+            .map_err(|_| ImportError::Invalid(line, format!("\"{}\" doesn't match the date format", date)))?;
+
+        let amount = match columns.sign {
+            AmountSign::Normal => money(field(&record, columns.amount, "amount", line)?, columns.decimal, line)?,
+            AmountSign::Inverted => -money(field(&record, columns.amount, "amount", line)?, columns.decimal, line)?,
+            AmountSign::Split => {
+                let debit = field(&record, columns.amount, "amount", line)?;
+                let credit = field(&record, columns.credit.unwrap_or(columns.amount), "credit", line)?;
+                let debit = if debit.is_empty() { Money::ZERO } else { money(debit, columns.decimal, line)?.abs() };
+                let credit = if credit.is_empty() { Money::ZERO } else { money(credit, columns.decimal, line)?.abs() };
+                credit - debit
+            }
+        };
+        let description = field(&record, columns.description, "description", line)?.to_string();
+        let balance = match columns.balance {
+            Some(column) => match field(&record, column, "balance", line)? {
+                "" => None,
+                b => Some(money(b, columns.decimal, line)?),
+            },
+            None => None,
+        };
+
+        rows.push(row(rows.len(), date, amount, description, balance));
+    }
+
+    let ledger_balance = rows.iter()
+        .filter(|r| r.balance.is_some())
+        .max_by_key(|r| (r.date, r.index))
+        .and_then(|r| r.balance);
+    Ok(Statement {
+        rows,
+        ledger_balance,
+    })
+}
+
+/// Parses historical exchange rates with `date,base,quote,rate` columns, dates
+/// as `%Y-%m-%d`. A header row is skipped.
+pub fn parse_rates(text: &str) -> Result<Vec<NewExchangeRate>, ImportError> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+
+    let mut rates = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line() as usize);
+        if record.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+
+        let date = field(&record, 0, "date", line)?;
+        if i == 0 && NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+            continue;
+        }
+        let rate = NewExchangeRate::parse(field(&record, 1, "base", line)?,
+                                          field(&record, 2, "quote", line)?,
+                                          date,
+                                          field(&record, 3, "rate", line)?);
+        match rate {
+            Some(rate) => rates.push(rate),
+            None => return Err(ImportError::Invalid(line, "expected a date, two currency codes and a positive rate".to_string())),
+        }
+    }
+    Ok(rates)
+}
+
+fn field<'a>(record: &'a StringRecord, column: usize, name: &str, line: usize) -> Result<&'a str, ImportError> {
+    record.get(column)
+        .map(str::trim)
+        .ok_or_else(|| ImportError::Invalid(line, format!("no {} column", name)))
+}
+
+fn money(amount: &str, decimal: char, line: usize) -> Result<Money, ImportError> {
+    Money::parse_with(amount, decimal)
+        .ok_or_else(|| ImportError::Invalid(line, format!("\"{}\" isn't an amount", amount)))
+}
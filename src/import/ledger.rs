@@ -0,0 +1,395 @@
+use money::Money;
+use types::{parse_currency, JournalEntry, JournalPosting};
+
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+
+pub struct Journal {
+    pub entries: Vec<JournalEntry>,
+    /// Transactions and directives that were skipped, and why.
+    pub problems: Vec<String>,
+}
+
+/// A transaction whose postings are still being read.
+struct Pending {
+    line: usize,
+    date: NaiveDate,
+    description: String,
+    comments: Vec<String>,
+    postings: Vec<(usize, String)>,
+}
+
+/// Parses a ledger or hledger journal. Only transactions in a single currency
+/// can be represented; everything else is listed in `problems`.
+pub fn parse(text: &str) -> Journal {
+    let mut journal = Journal {
+        entries: Vec::new(),
+        problems: Vec::new(),
+    };
+    let mut current: Option<Pending> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let trimmed = line.trim();
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Lines under skipped directives are skipped with them
+            if let (Some(pending), false) = (current.as_mut(), trimmed.is_empty()) {
+                if trimmed.starts_with(';') || trimmed.starts_with('#') {
+                    pending.comments.push(trimmed[1..].trim().to_string());
+                } else {
+                    pending.postings.push((number, trimmed.to_string()));
+                }
+            }
+            continue;
+        }
+
+        if let Some(pending) = current.take() {
+            finish(pending, &mut journal);
+        }
+        if trimmed.is_empty() || trimmed.starts_with(|c: char| ";#%|*".contains(c)) {
+            continue;
+        }
+        if trimmed.starts_with(|c: char| c.is_ascii_digit()) {
+            match header(number, trimmed) {
+                Some(pending) => current = Some(pending),
+                None => journal.problems.push(format!("Line {}: couldn't read the transaction date", number)),
+            }
+        } else if trimmed.starts_with('~') {
+            journal.problems.push(format!("Line {}: periodic transactions aren't supported", number));
+        } else if trimmed.starts_with('=') {
+            journal.problems.push(format!("Line {}: automated transactions aren't supported", number));
+        } else if !trimmed.starts_with("account ") {
+            // Accounts are created as they're used, so account directives aren't needed
+            let directive = trimmed.split_whitespace().next().unwrap_or(trimmed);
+            journal.problems.push(format!("Line {}: the \"{}\" directive isn't supported", number, directive));
+        }
+    }
+    if let Some(pending) = current.take() {
+        finish(pending, &mut journal);
+    }
+    journal
+}
+
+/// Reads `DATE[=DATE2] [*|!] [(CODE)] DESCRIPTION [; COMMENT]`.
+fn header(number: usize, line: &str) -> Option<Pending> {
+    let (line, comment) = split_comment(line);
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let date = parts.next()?.split('=').next()?;
+    let date = ["%Y-%m-%d", "%Y/%m/%d", "%Y.%m.%d"].iter()
+        .filter_map(|f| NaiveDate::parse_from_str(date, f).ok())
+        .next()?;
+
+    let mut description = parts.next().unwrap_or("").trim();
+    if description.starts_with('*') || description.starts_with('!') {
+        description = description[1..].trim_start();
+    }
+    if description.starts_with('(') {
+        if let Some(end) = description.find(')') {
+            description = description[end + 1..].trim_start();
+        }
+    }
+
+    Some(Pending {
+        line: number,
+        date,
+        description: description.to_string(),
+        comments: comment.into_iter().collect(),
+        postings: Vec::new(),
+    })
+}
+
+fn split_comment(line: &str) -> (&str, Option<String>) {
+    match line.find(';') {
+        Some(i) => (line[..i].trim_end(), Some(line[i + 1..].trim().to_string())),
+        None => (line, None),
+    }
+}
+
+/// Where a journal account goes in Hestia: outside money with a category for
+/// income and expenses, otherwise an account named without the `Assets:` prefix.
+fn map_account(name: &str) -> (Option<String>, Option<String>) {
+    for top in &["Income", "Expenses"] {
+        if name == *top {
+            return (None, None);
+        }
+        if name.starts_with(top) && name[top.len()..].starts_with(':') {
+            let category = &name[top.len() + 1..];
+            // Exports name uncategorized outside money this way
+            let category = if category.is_empty() || category == "Uncategorized" { None } else { Some(category.to_string()) };
+            return (None, category);
+        }
+    }
+    if name == "__none" {
+        return (None, None);
+    }
+    if name.starts_with("Assets:") {
+        return (Some(name["Assets:".len()..].to_string()), None);
+    }
+    (Some(name.to_string()), None)
+}
+
+/// Parses a posting amount and its currency, such as `$-12.34`, `-$12.34`,
+/// `12.34 USD` or `-5.00 EUR`. Amounts without a commodity are in dollars.
+fn posting_amount(amount: &str) -> Result<(Money, String), String> {
+    let commodity: String = amount.chars()
+        .filter(|c| !c.is_ascii_digit() && !"-+., ".contains(*c))
+        .collect();
+    let commodity = commodity.trim_matches('"');
+    let currency = match commodity {
+        "" | "$" => Some("USD".to_string()),
+        _ => parse_currency(commodity),
+    };
+    let currency = currency.ok_or_else(|| format!("the commodity \"{}\" isn't supported", commodity))?;
+    let number: String = amount.chars()
+        .filter(|c| c.is_ascii_digit() || "-+.,".contains(*c))
+        .collect();
+    match Money::parse(&number) {
+        Some(money) => Ok((money, currency)),
+        None => Err(format!("\"{}\" isn't an amount exact to the cent", amount)),
+    }
+}
+
+fn finish(pending: Pending, journal: &mut Journal) {
+    let problem = |reason: String| format!("Line {}: {}", pending.line, reason);
+    if pending.postings.len() < 2 {
+        journal.problems.push(problem(format!("transactions with {} postings aren't supported", pending.postings.len())));
+        return;
+    }
+
+    let mut comments = pending.comments.clone();
+    let mut postings = Vec::with_capacity(pending.postings.len());
+    for &(line, ref posting) in &pending.postings {
+        let (posting, comment) = split_comment(posting);
+        comments.extend(comment);
+        let (account, amount) = match posting.find("  ").into_iter().chain(posting.find('\t')).min() {
+            Some(i) => (posting[..i].trim(), posting[i..].trim()),
+            None => (posting.trim(), ""),
+        };
+        if account.starts_with('(') || account.starts_with('[') {
+            journal.problems.push(format!("Line {}: virtual postings aren't supported", line));
+            return;
+        }
+        if amount.contains('@') {
+            journal.problems.push(format!("Line {}: prices aren't supported", line));
+            return;
+        }
+        // Balance assertions only check the journal, so they can be dropped
+        let amount = amount.split('=').next().unwrap_or("").trim();
+        let amount = if amount.is_empty() {
+            None
+        } else {
+            match posting_amount(amount) {
+                Ok(amount) => Some(amount),
+                Err(e) => {
+                    journal.problems.push(format!("Line {}: {}", line, e));
+                    return;
+                }
+            }
+        };
+        postings.push((account.to_string(), amount));
+    }
+
+    // Moving money between currencies takes a price, so every posting shares one
+    let mut currencies: Vec<String> = postings.iter().filter_map(|p| p.1.as_ref().map(|a| a.1.clone())).collect();
+    currencies.sort();
+    currencies.dedup();
+    if currencies.len() > 1 {
+        journal.problems.push(problem(format!("postings in {} aren't supported together", currencies.join(" and "))));
+        return;
+    }
+    let currency = currencies.pop().unwrap_or_else(|| "USD".to_string());
+    let postings: Vec<_> = postings.into_iter()
+        .map(|(account, amount)| (account, amount.map(|a| a.0)))
+        .collect();
+
+    // One posting may leave its amount out to balance the others
+    let total: Money = postings.iter().filter_map(|p| p.1).sum();
+    let postings: Vec<_> = match postings.iter().filter(|p| p.1.is_none()).count() {
+        0 if total == Money::ZERO => postings.into_iter().map(|(account, amount)| (account, amount.unwrap())).collect(),
+        0 => {
+            journal.problems.push(problem("the postings don't balance".to_string()));
+            return;
+        }
+        1 => postings.into_iter().map(|(account, amount)| (account, amount.unwrap_or(-total))).collect(),
+        _ => {
+            journal.problems.push(problem("more than one posting has no amount".to_string()));
+            return;
+        }
+    };
+    let postings: Vec<_> = postings.into_iter()
+        .map(|(name, amount)| {
+            let (account, category) = map_account(&name);
+            JournalPosting { account, amount, category }
+        })
+        .collect();
+    if postings.iter().all(|p| p.account.is_none()) {
+        journal.problems.push(problem("transactions between income and expenses aren't supported".to_string()));
+        return;
+    }
+
+    let mut tags = Vec::new();
+    let mut notes = Vec::new();
+    for comment in comments {
+        if comment.len() > 1 && comment.starts_with(':') && comment.ends_with(':') {
+            tags.extend(comment.split(':').filter(|t| !t.is_empty()).map(String::from));
+        } else if !comment.is_empty() && !comment.starts_with("hestia-id:") {
+            notes.push(comment);
+        }
+    }
+    let reason = if notes.is_empty() {
+        pending.description.clone()
+    } else {
+        format!("{} ; {}", pending.description, notes.join("; "))
+    };
+
+    let hash = Sha256::digest(entry_key(pending.date, &postings, &currency, &reason).as_bytes());
+    journal.entries.push(JournalEntry {
+        line: pending.line,
+        date: pending.date,
+        currency,
+        postings,
+        reason,
+        tags,
+        hash: format!("{:x}", hash),
+    });
+}
+
+/// What identifies an entry across imports. Two postings in dollars keep the
+/// key they had before splits and other currencies could be imported, so
+/// journals imported then are still recognized.
+fn entry_key(date: NaiveDate, postings: &[JournalPosting], currency: &str, reason: &str) -> String {
+    let key = if let [ref a, ref b] = *postings {
+        // Money moves from the negative posting to the positive one
+        let (from, to) = if a.amount <= Money::ZERO { (a, b) } else { (b, a) };
+        format!("ledger|{}|{:?}|{:?}|{}|{}", date, from.account, to.account, to.amount.minor(), reason)
+    } else {
+        let legs: Vec<_> = postings.iter()
+            .map(|p| format!("{:?}|{:?}|{}", p.account, p.category, p.amount.minor()))
+            .collect();
+        format!("ledger|{}|{}|{}", date, legs.join("|"), reason)
+    };
+    if currency == "USD" {
+        key
+    } else {
+        format!("{}|{}", key, currency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use export;
+    use types::{ExportPosting, ExportTransaction};
+
+    fn posting(account: Option<&str>, minor: i64, category: Option<&str>) -> JournalPosting {
+        JournalPosting {
+            account: account.map(String::from),
+            amount: Money::from_minor(minor),
+            category: category.map(String::from),
+        }
+    }
+
+    fn export_posting(account: &str, minor: i64, currency: &str, category: Option<&str>) -> ExportPosting {
+        ExportPosting {
+            account: account.to_string(),
+            amount: Money::from_minor(minor),
+            currency: currency.to_string(),
+            category: category.map(String::from),
+        }
+    }
+
+    #[test]
+    fn round_trips_exported_splits() {
+        let exported = export::ledger(&[ExportTransaction {
+            id: 7,
+            date: NaiveDate::from_ymd_opt(2024, 3, 2).unwrap(),
+            reason: "Market".to_string(),
+            tags: vec!["weekly".to_string()],
+            postings: vec![
+                export_posting("Checking", -10000, "USD", None),
+                export_posting("__none", 6000, "USD", Some("Food:Groceries")),
+                export_posting("__none", 4000, "USD", Some("Household")),
+            ],
+        }]);
+        let journal = parse(&exported);
+        assert!(journal.problems.is_empty(), "{:?}", journal.problems);
+        assert_eq!(journal.entries.len(), 1);
+
+        let entry = &journal.entries[0];
+        assert_eq!(entry.currency, "USD");
+        assert_eq!(entry.reason, "Market");
+        assert_eq!(entry.tags, vec!["weekly".to_string()]);
+        assert_eq!(entry.postings, vec![
+            posting(Some("Checking"), -10000, None),
+            posting(None, 6000, Some("Food:Groceries")),
+            posting(None, 4000, Some("Household")),
+        ]);
+    }
+
+    #[test]
+    fn round_trips_exported_currencies() {
+        let exported = export::ledger(&[ExportTransaction {
+            id: 8,
+            date: NaiveDate::from_ymd_opt(2024, 3, 4).unwrap(),
+            reason: "Bakery".to_string(),
+            tags: Vec::new(),
+            postings: vec![
+                export_posting("Euro Cash", -450, "EUR", None),
+                export_posting("__none", 450, "EUR", Some("Food")),
+            ],
+        }]);
+        let journal = parse(&exported);
+        assert!(journal.problems.is_empty(), "{:?}", journal.problems);
+
+        let entry = &journal.entries[0];
+        assert_eq!(entry.currency, "EUR");
+        assert_eq!(entry.postings, vec![
+            posting(Some("Euro Cash"), -450, None),
+            posting(None, 450, Some("Food")),
+        ]);
+        // The same amounts in dollars are another transaction
+        let dollars = parse("2024-03-04 Bakery\n    Assets:Euro Cash  $-4.50\n    Expenses:Food  $4.50\n");
+        assert_ne!(entry.hash, dollars.entries[0].hash);
+    }
+
+    #[test]
+    fn elided_amount_balances_a_split() {
+        let journal = parse("2024-03-02 Rent and power\n    Expenses:Rent  $1200.00\n    Expenses:Utilities  $80.50\n    Assets:Checking\n");
+        assert!(journal.problems.is_empty(), "{:?}", journal.problems);
+        assert_eq!(journal.entries[0].postings, vec![
+            posting(None, 120000, Some("Rent")),
+            posting(None, 8050, Some("Utilities")),
+            posting(Some("Checking"), -128050, None),
+        ]);
+    }
+
+    #[test]
+    fn two_postings_keep_their_import_hash() {
+        // Journals imported before splits were supported must still be recognized
+        let journal = parse("2024/01/05 * Coffee\n    Expenses:Dining  $4.25\n    Assets:Checking\n");
+        let entry = &journal.entries[0];
+        let legacy = Sha256::digest(format!("ledger|2024-01-05|{:?}|{:?}|425|Coffee", Some("Checking"), None::<String>).as_bytes());
+        assert_eq!(entry.hash, format!("{:x}", legacy));
+    }
+
+    #[test]
+    fn reports_postings_that_cant_be_posted() {
+        let journal = parse(concat!(
+            "2024-01-01 Lonely\n    Assets:Checking  $1.00\n\n",
+            "2024-01-02 Unbalanced\n    Assets:Checking  $-5.00\n    Expenses:Food  $3.00\n    Expenses:Fun  $1.00\n\n",
+            "2024-01-03 Vague\n    Assets:Checking  $-5.00\n    Expenses:Food\n    Expenses:Fun\n\n",
+            "2024-01-04 Reclassified\n    Income:Salary  $-5.00\n    Expenses:Food  $2.00\n    Expenses:Fun  $3.00\n\n",
+            "2024-01-05 Exchange\n    Assets:Checking  $-5.00\n    Assets:Euro Cash  4.50 EUR\n\n",
+            "2024-01-06 Shells\n    Assets:Checking  $-5.00\n    Assets:Beach  5 SHELLS\n",
+        ));
+        assert!(journal.entries.is_empty());
+        assert_eq!(journal.problems, vec![
+            "Line 1: transactions with 1 postings aren't supported".to_string(),
+            "Line 4: the postings don't balance".to_string(),
+            "Line 9: more than one posting has no amount".to_string(),
+            "Line 14: transactions between income and expenses aren't supported".to_string(),
+            "Line 19: postings in EUR and USD aren't supported together".to_string(),
+            "Line 25: the commodity \"SHELLS\" isn't supported".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,46 @@
+//! Parsers turning bank statements into rows to post to an account.
+
+pub mod csv;
+pub mod ledger;
+pub mod ofx;
+
+use money::Money;
+use types::ImportRow;
+
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ImportError {
+        Csv(err: ::csv::Error) {
+            from()
+            display("Could not read the statement: {}", err)
+        }
+        Invalid(line: usize, reason: String) {
+            display("Line {}: {}", line, reason)
+        }
+    }
+}
+
+pub struct Statement {
+    pub rows: Vec<ImportRow>,
+    /// Closing balance according to the statement.
+    pub ledger_balance: Option<Money>,
+}
+
+/// Builds a row, hashing its date, amount and description so imports of
+/// overlapping statements can be recognised.
+pub fn row(index: usize, date: NaiveDate, amount: Money, description: String, balance: Option<Money>) -> ImportRow {
+    let normalized = description.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    let hash = Sha256::digest(format!("{}|{}|{}", date, amount.minor(), normalized).as_bytes());
+    ImportRow {
+        index,
+        date,
+        amount,
+        description,
+        balance,
+        hash: format!("{:x}", hash),
+        duplicate: false,
+    }
+}
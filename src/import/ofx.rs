@@ -0,0 +1,218 @@
+use money::Money;
+use super::{row, ImportError, Statement};
+
+use chrono::NaiveDate;
+use sha2::{Digest, Sha256};
+
+/// A transaction read from a STMTTRN aggregate.
+#[derive(Default)]
+struct OfxTransaction {
+    fitid: Option<String>,
+    posted: Option<String>,
+    amount: Option<String>,
+    name: Option<String>,
+    memo: Option<String>,
+}
+
+/// Parses an OFX 1.x (SGML) or 2.x (XML) bank or credit card statement.
+///
+/// Both versions are read as a stream of tags: aggregates are always closed,
+/// while SGML leaves the elements holding values unclosed, so a tag directly
+/// followed by text is treated as an element whatever the version.
+pub fn parse(text: &str) -> Result<Statement, ImportError> {
+    let body = match text.find("<OFX>") {
+        Some(start) => &text[start..],
+        None => return Err(ImportError::Invalid(1, "not an OFX statement".to_string())),
+    };
+
+    let mut aggregates: Vec<String> = Vec::new();
+    let mut account = String::new();
+    let mut current: Option<OfxTransaction> = None;
+    let mut transactions = Vec::new();
+    let mut ledger_balance = None;
+
+    let mut rest = body;
+    while let Some(open) = rest.find('<') {
+        let close = match rest[open..].find('>') {
+            Some(close) => open + close,
+            None => break,
+        };
+        let tag = rest[open + 1..close].trim();
+        rest = &rest[close + 1..];
+        let value_end = rest.find('<').unwrap_or(rest.len());
+        let value = decode_entities(rest[..value_end].trim());
+
+        if tag.starts_with('?') || tag.starts_with('!') || tag.ends_with('/') {
+            continue;
+        } else if tag.starts_with('/') {
+            let name = &tag[1..];
+            if name == "STMTTRN" {
+                transactions.extend(current.take());
+            }
+            // Closing tags of elements aren't on the stack
+            if let Some(pos) = aggregates.iter().rposition(|a| a == name) {
+                aggregates.truncate(pos);
+            }
+        } else if value.is_empty() {
+            if tag == "STMTTRN" {
+                current = Some(OfxTransaction::default());
+            }
+            aggregates.push(tag.to_string());
+        } else {
+            let parent = aggregates.last().map(String::as_str);
+            match (parent, tag) {
+                (Some("BANKACCTFROM"), "ACCTID") | (Some("CCACCTFROM"), "ACCTID") => account = value,
+                (Some("LEDGERBAL"), "BALAMT") => ledger_balance = Some(ofx_amount(&value)?),
+                (Some("STMTTRN"), _) => if let Some(ref mut t) = current {
+                    match tag {
+                        "FITID" => t.fitid = Some(value),
+                        "DTPOSTED" => t.posted = Some(value),
+                        "TRNAMT" => t.amount = Some(value),
+                        "NAME" => t.name = Some(value),
+                        "MEMO" => t.memo = Some(value),
+                        _ => {}
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    let mut rows = Vec::with_capacity(transactions.len());
+    for (index, t) in transactions.into_iter().enumerate() {
+        let line = index + 1;
+        let missing = |field: &str| ImportError::Invalid(line, format!("transaction {} has no {}", line, field));
+        let fitid = t.fitid.ok_or_else(|| missing("FITID"))?;
+        let posted = t.posted.ok_or_else(|| missing("DTPOSTED"))?;
+        let date = posted.get(..8)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+            .ok_or_else(|| ImportError::Invalid(line, format!("\"{}\" isn't a date", posted)))?;
+        let amount = ofx_amount(&t.amount.ok_or_else(|| missing("TRNAMT"))?)?;
+        let description = match (t.name, t.memo) {
+            (Some(name), Some(memo)) => format!("{} - {}", name, memo),
+            (Some(name), None) => name,
+            (None, Some(memo)) => memo,
+            (None, None) => String::new(),
+        };
+
+        let mut r = row(index, date, amount, description, None);
+        // FITIDs are unique per account, unlike dates, amounts and descriptions
+        r.hash = format!("{:x}", Sha256::digest(format!("ofx|{}|{}", account, fitid).as_bytes()));
+        rows.push(r);
+    }
+
+    Ok(Statement {
+        rows,
+        ledger_balance,
+    })
+}
+
+/// Parses an OFX amount, which is never grouped but may have more than two
+/// decimal places.
+fn ofx_amount(amount: &str) -> Result<Money, ImportError> {
+    // Some banks use a decimal comma
+    let decimal = if amount.contains(',') { ',' } else { '.' };
+    Money::parse_with(amount, decimal)
+        .ok_or_else(|| ImportError::Invalid(0, format!("\"{}\" isn't an amount", amount)))
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SGML: &str = include_str!("../../tests/fixtures/ofx/checking.ofx");
+    const XML: &str = include_str!("../../tests/fixtures/ofx/credit_card.ofx");
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn fitid_hash(account: &str, fitid: &str) -> String {
+        format!("{:x}", Sha256::digest(format!("ofx|{}|{}", account, fitid).as_bytes()))
+    }
+
+    fn invalid(text: &str) -> String {
+        match parse(text) {
+            Err(ImportError::Invalid(_, reason)) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("parsed"),
+        }
+    }
+
+    #[test]
+    fn parses_sgml_with_unclosed_elements() {
+        let statement = parse(SGML).unwrap();
+        assert_eq!(statement.ledger_balance, Some(Money::from_minor(245750)));
+        assert_eq!(statement.rows.len(), 2);
+
+        let debit = &statement.rows[0];
+        assert_eq!(debit.index, 0);
+        assert_eq!(debit.date, date(2024, 1, 3));
+        assert_eq!(debit.amount, Money::from_minor(-4250));
+        assert_eq!(debit.description, "GROCERY & DELI - Card 1234");
+        assert_eq!(debit.hash, fitid_hash("123456789", "20240103001"));
+
+        let credit = &statement.rows[1];
+        assert_eq!(credit.date, date(2024, 1, 15));
+        assert_eq!(credit.amount, Money::from_minor(150000));
+        assert_eq!(credit.description, "PAYROLL");
+        assert_eq!(credit.hash, fitid_hash("123456789", "20240115001"));
+    }
+
+    #[test]
+    fn parses_xml() {
+        let statement = parse(XML).unwrap();
+        assert_eq!(statement.ledger_balance, Some(Money::from_minor(-103450)));
+        assert_eq!(statement.rows.len(), 2);
+
+        let debit = &statement.rows[0];
+        assert_eq!(debit.date, date(2024, 1, 20));
+        assert_eq!(debit.amount, Money::from_minor(-123450));
+        assert_eq!(debit.description, "AIRLINE <TICKETS>");
+        assert_eq!(debit.hash, fitid_hash("4111111111111111", "CC-0001"));
+
+        let credit = &statement.rows[1];
+        assert_eq!(credit.date, date(2024, 1, 25));
+        assert_eq!(credit.amount, Money::from_minor(20000));
+        assert_eq!(credit.description, "Payment, thank you");
+    }
+
+    #[test]
+    fn hashes_identify_fitids_per_account() {
+        let first = parse(SGML).unwrap();
+        let again = parse(SGML).unwrap();
+        assert_eq!(first.rows[0].hash, again.rows[0].hash);
+        assert_ne!(first.rows[0].hash, first.rows[1].hash);
+
+        // The same FITID on another account is another transaction
+        let other = parse(&SGML.replace("<ACCTID>123456789", "<ACCTID>987654321")).unwrap();
+        assert_ne!(first.rows[0].hash, other.rows[0].hash);
+        // A corrected description or amount is still the same transaction
+        let edited = parse(&SGML.replace("<TRNAMT>-42.50", "<TRNAMT>-42.05")).unwrap();
+        assert_eq!(first.rows[0].hash, edited.rows[0].hash);
+    }
+
+    #[test]
+    fn reads_decimal_commas() {
+        let statement = parse(&SGML.replace("<TRNAMT>-42.50", "<TRNAMT>-42,50")).unwrap();
+        assert_eq!(statement.rows[0].amount, Money::from_minor(-4250));
+    }
+
+    #[test]
+    fn rejects_malformed_statements() {
+        assert_eq!(invalid("OFXHEADER:100\n<STMTTRN>"), "not an OFX statement");
+        assert!(invalid(&SGML.replace("<FITID>20240103001\n", "")).contains("FITID"));
+        assert!(invalid(&SGML.replace("<DTPOSTED>20240115", "<DTPOSTED>2024-01-15")).contains("isn't a date"));
+        assert!(invalid(&SGML.replace("<TRNAMT>1500.00", "<TRNAMT>1500.005")).contains("isn't an amount"));
+        assert!(invalid(&XML.replace("<TRNAMT>+200.00</TRNAMT>", "<TRNAMT>lots</TRNAMT>")).contains("isn't an amount"));
+    }
+}
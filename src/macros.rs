@@ -6,37 +6,69 @@ macro_rules! ok {
 }
 
 macro_rules! route {
+    // For routes taking POSTs that can't carry a csrf token, like searches
+    // from the browser's search bar
+    ( unprotected $name:ident, $req:ident, $res:ident, $ctx:ident, $body:expr) => {
+        #[allow(unused_mut, unused_variables)]
+        pub fn $name($req: &mut ::hayaku::Request, $res: &mut ::hayaku::Response, $ctx: &::Context)
+            -> ::Result<()>
+        {
+            let _scope = ::routes::util::RequestScope::enter();
+            $body
+        }
+    };
+    // POSTs from a logged in user must carry the session's csrf token
     ( $name:ident, $req:ident, $res:ident, $ctx:ident, $body:expr) => {
         #[allow(unused_mut, unused_variables)]
         pub fn $name($req: &mut ::hayaku::Request, $res: &mut ::hayaku::Response, $ctx: &::Context)
             -> ::Result<()>
         {
+            let _scope = ::routes::util::RequestScope::enter();
+            if !::routes::util::check_csrf($req, $res, $ctx)? {
+                error!($req, $res, $ctx, "", "Invalid form token, please try again");
+            }
             $body
         }
     };
 }
 
 macro_rules! redirect {
-    ( $res:ident, $ctx:ident, $path:expr, $msg:expr) => {
-        ok!($res.redirect(::hayaku::Status::FOUND, &format!("{}{}", $ctx.mount, $path), $msg));
+    ( $req:ident, $res:ident, $ctx:ident, $path:expr, $msg:expr) => {
+        ::routes::util::create_flash($req, $res, $ctx, ::templates::FlashKind::Success, &$msg)?;
+        ok!($res.redirect(::hayaku::Status::FOUND, &format!("{}{}", $ctx.mount, $path), &$msg));
     };
 }
 
 macro_rules! error {
-    ( $res:ident, $ctx:ident, $path:expr, $msg:expr ) => {
-        //::routes::util::create_error($res, $ctx, $msg.to_string());
-        redirect!($res, $ctx, $path, $msg);
+    ( $req:ident, $res:ident, $ctx:ident, $path:expr, $msg:expr ) => {
+        ::routes::util::create_flash($req, $res, $ctx, ::templates::FlashKind::Error, &$msg)?;
+        ok!($res.redirect(::hayaku::Status::FOUND, &format!("{}{}", $ctx.mount, $path), &$msg));
     };
 }
 
 macro_rules! check_login {
-    ( $cookies:expr, $res:ident, $ctx:ident ) => {
+    ( $req:ident, $res:ident, $ctx:ident ) => {
         {
-            if let Some(name) = ::routes::util::check_login($ctx, $cookies)? {
-                name
+            if let Some(user) = ::routes::util::current_user($req, $res, $ctx)? {
+                user
             } else {
-                error!($res, $ctx, "", "You must be logged in for this");
+                error!($req, $res, $ctx, "", "You must be logged in for this");
+            }
+        }
+    };
+}
+
+macro_rules! check_admin {
+    ( $req:ident, $res:ident, $ctx:ident ) => {
+        check_admin!(@admin check_login!($req, $res, $ctx), $req, $res, $ctx)
+    };
+    ( @admin $user:expr, $req:ident, $res:ident, $ctx:ident ) => {
+        {
+            let user = $user;
+            if !::routes::util::is_admin($ctx, &user.username) {
+                error!($req, $res, $ctx, "", "You must be an admin for this");
             }
+            user
         }
     };
 }
@@ -54,9 +86,9 @@ macro_rules! parse_param {
 
 macro_rules! tmpl {
     ( $req:ident, $res:ident, $ctx:ident, $name:expr, $body:expr ) => {
-        //let err = ::routes::util::get_error($req, $res);
-        //let tmpl = ::templates::Template::new($ctx, $name, $body);
-        let tmpl = ::templates::Template::new($name, $body);
+        let flash = ::routes::util::get_flash($req, $ctx)?;
+        let csrf_token = ::routes::util::current_user($req, $res, $ctx)?.map(|u| u.csrf_token);
+        let tmpl = ::templates::Template::new($name, flash, csrf_token, $body);
         let headers = $req.headers();
         let compress = headers.get(::hayaku::header::ACCEPT_ENCODING);
 
@@ -1,3 +1,5 @@
+extern crate argon2;
+extern crate base64;
 #[macro_use]
 extern crate bart_derive;
 extern crate bcrypt;
@@ -5,6 +7,7 @@ extern crate brotli;
 #[macro_use]
 extern crate check_psql;
 extern crate chrono;
+extern crate csv;
 #[macro_use]
 extern crate hayaku;
 extern crate libflate;
@@ -18,15 +21,25 @@ extern crate rusqlite;
 extern crate rusqlite_migration;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate sessions;
+extern crate sha2;
 extern crate time;
 extern crate toml;
+extern crate ureq;
 
 #[macro_use]
 mod macros;
 mod db;
+mod export;
+mod import;
+mod money;
+mod oidc;
+mod password;
 mod routes;
+mod scheduler;
 mod templates;
+mod throttle;
 mod types;
 
 use routes::*;
@@ -35,7 +48,7 @@ use hayaku::{Http, Router};
 use r2d2_sqlite::SqliteConnectionManager;
 
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
@@ -53,6 +66,9 @@ quick_error! {
         ParseInt(err: ::std::num::ParseIntError) {
             from()
         }
+        PasswordHash(err: ::argon2::password_hash::Error) {
+            from()
+        }
         R2D2(err: r2d2::Error) {
             from()
         }
@@ -62,6 +78,12 @@ quick_error! {
         Sqlite(err: rusqlite::Error) {
             from()
         }
+        NoExchangeRate(from: String, to: String) {
+            display("There is no exchange rate from {} to {}", from, to)
+        }
+        MixedCurrencies {
+            display("The accounts of a split transaction must share a currency")
+        }
     }
 }
 
@@ -73,6 +95,20 @@ pub struct Config {
     mount: Option<String>,
     signup: bool,
     default_bang: String,
+    admins: Option<Vec<String>>,
+    throttle: Option<throttle::ThrottleConfig>,
+    password_policy: Option<password::PolicyConfig>,
+    proxy_auth: Option<ProxyAuth>,
+    oidc: Option<oidc::OidcConfig>,
+    /// The decimal separator of amounts typed into text fields, `.` or `,`.
+    decimal: Option<char>,
+}
+
+/// Trust a header naming the logged in user when it comes from one of `trusted_proxies`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProxyAuth {
+    pub header: String,
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 pub struct Context {
@@ -82,6 +118,12 @@ pub struct Context {
     pub mount: String,
     pub signup: bool,
     pub default_bang: String,
+    pub admins: Vec<String>,
+    pub throttle: throttle::Throttle,
+    pub password_policy: password::Policy,
+    pub proxy_auth: Option<ProxyAuth>,
+    pub oidc: Option<oidc::Oidc>,
+    pub decimal: char,
 }
 
 fn main() {
@@ -94,21 +136,8 @@ fn main() {
 
     {
         // Run migrations
-        use rusqlite_migration::{M, Migrations};
-        let migrations = Migrations::new(vec![
-            M::up(include_str!("../migrations/1/up.sql"))
-                .down(include_str!("../migrations/1/down.sql")),
-            M::up(include_str!("../migrations/2/up.sql"))
-                .down(include_str!("../migrations/2/down.sql")),
-            M::up(include_str!("../migrations/3/up.sql"))
-                .down(include_str!("../migrations/3/down.sql")),
-            M::up(include_str!("../migrations/4/up.sql"))
-                .down(include_str!("../migrations/4/down.sql")),
-            M::up(include_str!("../migrations/5/up.sql"))
-                .down(include_str!("../migrations/5/down.sql")),
-        ]);
         let mut conn = pool.get().unwrap();
-        migrations.to_latest(&mut conn).unwrap();
+        db::migrations().to_latest(&mut conn).unwrap();
     }
 
     let mount = match config.mount {
@@ -126,6 +155,8 @@ fn main() {
         ::sessions::SessionSet::new(config.sessions_dir).expect("failed to load sessions")
     };
 
+    scheduler::spawn(pool.clone());
+
     let ctx = Context {
         db_pool: pool,
         logins: Arc::new(Mutex::new(sessions)),
@@ -133,6 +164,17 @@ fn main() {
         mount: mount,
         signup: config.signup,
         default_bang: config.default_bang,
+        admins: config.admins.unwrap_or_default(),
+        throttle: throttle::Throttle::new(config.throttle.unwrap_or_default()),
+        password_policy: password::Policy::load(config.password_policy.unwrap_or_default())
+            .expect("failed to load password policy"),
+        proxy_auth: config.proxy_auth,
+        oidc: config.oidc.map(oidc::Oidc::new),
+        decimal: match config.decimal {
+            None | Some('.') => '.',
+            Some(',') => ',',
+            Some(_) => panic!("decimal must be \".\" or \",\""),
+        },
     };
 
     let mut router = Router::mount(ctx.mount.clone());
@@ -144,31 +186,82 @@ fn main() {
         get "/signup" => signup,
         post "/signup" => signup_post,
         post "/login" => login,
-        get "/logout" => logout,
+        post "/logout" => logout,
+        get "/oidc/login" => sso::login,
+        get "/oidc/callback" => sso::callback,
+        post "/settings/oidc" => sso::link,
 
         // settings
         get "/settings" => settings::settings,
-        get "/settings/new-api-key" => settings::new_api_key,
+        get "/settings/new-api-key" => settings::confirm_new_api_key,
+        post "/settings/new-api-key" => settings::new_api_key,
         post "/settings/password" => settings::password,
+        post "/settings/profile" => settings::profile,
+        post "/settings/proxy" => settings::link_proxy,
         post "/settings/bangs" => settings::create_bang,
         post "/settings/bangs/{id:[[:digit:]]+}" => settings::edit_bang,
-        get "/settings/bangs/{id:[[:digit:]]+}" => settings::delete_bang,
+        get "/settings/bangs/{id:[[:digit:]]+}/delete" => settings::confirm_delete_bang,
+        post "/settings/bangs/{id:[[:digit:]]+}/delete" => settings::delete_bang,
         post "/settings/links" => settings::create_link,
         post "/settings/links/{id:[[:digit:]]+}" => settings::edit_link,
-        get "/settings/links/{id:[[:digit:]]+}" => settings::delete_link,
+        get "/settings/links/{id:[[:digit:]]+}/delete" => settings::confirm_delete_link,
+        post "/settings/links/{id:[[:digit:]]+}/delete" => settings::delete_link,
+        post "/settings/households" => settings::create_household,
+        post "/settings/households/{id:[[:digit:]]+}/members" => settings::add_member,
+        post "/settings/households/{id:[[:digit:]]+}/members/{member:[[:digit:]]+}/delete" => settings::remove_member,
 
         // search
         get "/search/{api-key}" => search,
         post "/search/{api-key}" => search,
         get "/opensearch/{api-key}/opensearch.xml" => opensearch,
 
+        // admin
+        get "/admin/lockouts" => admin::lockouts,
+        post "/admin/lockouts" => admin::unlock,
+
         // finance
         get "/finance" => finance::home,
         post "/finance/account" => finance::new_account,
         get "/finance/account/{id:[[:digit:]]+}" => finance::view_account,
         post "/finance/account/{id:[[:digit:]]+}" => finance::edit_account,
-        get "/finance/account/{id:[[:digit:]]+}/delete" => finance::delete_account,
+        get "/finance/account/{id:[[:digit:]]+}/delete" => finance::confirm_delete_account,
+        post "/finance/account/{id:[[:digit:]]+}/delete" => finance::delete_account,
+        get "/finance/account/{id:[[:digit:]]+}/import" => finance::import_form,
+        post "/finance/account/{id:[[:digit:]]+}/import" => finance::import,
+        post "/finance/account/{id:[[:digit:]]+}/import/preview" => finance::import_preview,
+        post "/finance/account/{id:[[:digit:]]+}/import/profile" => finance::new_import_profile,
+        get "/finance/account/{id:[[:digit:]]+}/reconcile" => finance::reconcile,
+        post "/finance/account/{id:[[:digit:]]+}/reconcile" => finance::save_reconcile,
+        get "/finance/budgets" => finance::budgets,
+        post "/finance/budgets" => finance::new_budget,
+        post "/finance/budgets/{id:[[:digit:]]+}/delete" => finance::delete_budget,
+        get "/finance/scheduled" => finance::scheduled,
+        post "/finance/scheduled" => finance::new_scheduled,
+        post "/finance/scheduled/{id:[[:digit:]]+}/skip" => finance::skip_scheduled,
+        post "/finance/scheduled/{id:[[:digit:]]+}/post" => finance::post_scheduled,
+        post "/finance/scheduled/{id:[[:digit:]]+}/delete" => finance::delete_scheduled,
+        get "/finance/categories" => finance::categories,
+        post "/finance/categories" => finance::new_category,
+        post "/finance/categories/{id:[[:digit:]]+}/delete" => finance::delete_category,
+        get "/finance/report" => finance::report,
+        get "/finance/net-worth" => finance::net_worth,
+        get "/finance/export" => finance::export,
+        get "/finance/journal" => finance::journal_form,
+        post "/finance/journal" => finance::import_journal,
+        get "/finance/rates" => finance::rates,
+        post "/finance/rates" => finance::new_rate,
+        post "/finance/rates/import" => finance::import_rates,
+        post "/finance/rates/{id:[[:digit:]]+}/delete" => finance::delete_rate,
+        post "/finance/currency" => finance::home_currency,
+        get "/finance/split" => finance::split_form,
+        post "/finance/split" => finance::new_split,
         post "/finance/transaction" => finance::new_transaction,
+        get "/finance/transaction/{id:[[:digit:]]+}" => finance::view_transaction,
+        post "/finance/transaction/{id:[[:digit:]]+}" => finance::edit_transaction,
+        post "/finance/transaction/{id:[[:digit:]]+}/split" => finance::edit_split,
+        post "/finance/transaction/{id:[[:digit:]]+}/void" => finance::void_transaction,
+        get "/finance/transaction/{id:[[:digit:]]+}/delete" => finance::confirm_delete_transaction,
+        post "/finance/transaction/{id:[[:digit:]]+}/delete" => finance::delete_transaction,
 
         // inventory
         get "/inventory" => inventory::home,
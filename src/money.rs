@@ -0,0 +1,290 @@
+//! Amounts of money, kept as a whole number of minor units so sums are exact.
+
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+
+/// An amount in minor units, e.g. cents, of some currency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn from_minor(minor: i64) -> Self {
+        Money(minor)
+    }
+
+    pub fn minor(self) -> i64 {
+        self.0
+    }
+
+    pub fn abs(self) -> Self {
+        Money(self.0.abs())
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Parses an amount with `.` as the decimal separator.
+    pub fn parse(text: &str) -> Option<Self> {
+        Money::parse_with(text, '.')
+    }
+
+    /// Parses an amount like `12`, `-1,234.5`, `+0.05`, `$12.00` or `(12.00)`,
+    /// the last being negative. Whichever of `.` and `,` isn't the decimal
+    /// separator, spaces and apostrophes may group the whole part into
+    /// thousands. Returns None for anything else, including fractions of a
+    /// minor unit and a separator used the other way, like `12,50` with a
+    /// decimal point.
+    pub fn parse_with(text: &str, decimal: char) -> Option<Self> {
+        let mut text = text.trim();
+        let mut negative = false;
+        if text.starts_with('(') && text.ends_with(')') {
+            negative = true;
+            text = text[1..text.len() - 1].trim();
+        }
+        let thousands = if decimal == ',' { '.' } else { ',' };
+
+        let mut signed = false;
+        let mut whole = String::new();
+        // Digits in each group of the whole part
+        let mut groups = vec![0];
+        let mut fraction: Option<String> = None;
+        for c in text.chars() {
+            match c {
+                '-' | '+' if !signed && !negative && whole.is_empty() && fraction.is_none() => {
+                    signed = true;
+                    negative = c == '-';
+                }
+                '$' if whole.is_empty() && fraction.is_none() => {}
+                c if c == decimal && fraction.is_none() => fraction = Some(String::new()),
+                c if c.is_ascii_digit() => match fraction {
+                    Some(ref mut fraction) => fraction.push(c),
+                    None => {
+                        whole.push(c);
+                        *groups.last_mut().unwrap() += 1;
+                    }
+                },
+                c if (c == thousands || c == ' ' || c == '\'') && !whole.is_empty() && fraction.is_none() => groups.push(0),
+                _ => return None,
+            }
+        }
+
+        let fraction = fraction.unwrap_or_default();
+        if whole.is_empty() && fraction.is_empty() {
+            return None;
+        }
+        if groups.len() > 1 && (groups[0] > 3 || groups[1..].iter().any(|&n| n != 3)) {
+            return None;
+        }
+        // Trailing zeros past the minor unit don't change the amount
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > 2 {
+            return None;
+        }
+        let major: i64 = if whole.is_empty() { 0 } else { whole.parse().ok()? };
+        let minor: i64 = format!("{:0<2}", fraction).parse().ok()?;
+        let amount = major.checked_mul(100)?.checked_add(minor)?;
+        Some(Money(if negative { -amount } else { amount }))
+    }
+
+    /// Formats without grouping, e.g. `-1234.50`, for form inputs and files
+    /// read by other programs.
+    pub fn plain(self) -> String {
+        self.plain_with('.')
+    }
+
+    /// Formats without grouping and with the given decimal separator, so
+    /// `parse_with` reads it back.
+    pub fn plain_with(self, decimal: char) -> String {
+        let abs = self.0.unsigned_abs();
+        format!("{}{}{}{:02}", if self.0 < 0 { "-" } else { "" }, abs / 100, decimal, abs % 100)
+    }
+
+    /// Multiplies by an exchange rate, rounding to the nearest minor unit.
+    pub fn convert(self, rate: f64) -> Self {
+        Money((self.0 as f64 * rate).round() as i64)
+    }
+}
+
+/// Formats with grouped thousands, e.g. `-1,234.50`.
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let abs = self.0.unsigned_abs();
+        let whole = (abs / 100).to_string();
+        let mut grouped = String::with_capacity(whole.len() + whole.len() / 3);
+        for (i, c) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(c);
+        }
+        write!(f, "{}{}.{:02}", if self.0 < 0 { "-" } else { "" }, grouped, abs % 100)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+impl ToSql for Money {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for Money {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        i64::column_result(value).map(Money)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Option<i64> {
+        Money::parse(text).map(Money::minor)
+    }
+
+    #[test]
+    fn parses_amounts() {
+        assert_eq!(parse("12"), Some(1200));
+        assert_eq!(parse("12.5"), Some(1250));
+        assert_eq!(parse("0.05"), Some(5));
+        assert_eq!(parse(".5"), Some(50));
+        assert_eq!(parse(" 12.50 "), Some(1250));
+        assert_eq!(parse("12.340"), Some(1234));
+    }
+
+    #[test]
+    fn parses_signs_and_symbols() {
+        assert_eq!(parse("-12.5"), Some(-1250));
+        assert_eq!(parse("+0.05"), Some(5));
+        assert_eq!(parse("$12.00"), Some(1200));
+        assert_eq!(parse("-$5"), Some(-500));
+        assert_eq!(parse("(12.00)"), Some(-1200));
+    }
+
+    #[test]
+    fn parses_grouping() {
+        assert_eq!(parse("1,234.56"), Some(123456));
+        assert_eq!(parse("1'234.56"), Some(123456));
+        assert_eq!(parse("1 234 567.8"), Some(123456780));
+    }
+
+    #[test]
+    fn parses_decimal_commas() {
+        assert_eq!(Money::parse_with("12,5", ','), Some(Money(1250)));
+        assert_eq!(Money::parse_with("-1.234,56", ','), Some(Money(-123456)));
+        assert_eq!(Money::parse_with("1 234,56", ','), Some(Money(123456)));
+        assert_eq!(Money::parse_with("1'234,56", ','), Some(Money(123456)));
+    }
+
+    #[test]
+    fn groups_only_thousands() {
+        // A decimal comma typed where a point is expected isn't a 100x amount
+        assert_eq!(parse("12,50"), None);
+        assert_eq!(parse("1,23"), None);
+        assert_eq!(parse("1,234,5"), None);
+        assert_eq!(parse("1234,567"), None);
+        assert_eq!(parse("1,,234"), None);
+        assert_eq!(parse("1,234,"), None);
+        assert_eq!(parse("12,345,678"), Some(1234567800));
+        assert_eq!(Money::parse_with("12.50", ','), None);
+        assert_eq!(Money::parse_with("12.345.678,9", ','), Some(Money(1234567890)));
+    }
+
+    #[test]
+    fn rejects_fractions_of_a_cent() {
+        assert_eq!(parse("12.345"), None);
+        assert_eq!(parse("0.001"), None);
+        assert_eq!(Money::parse_with("1,005", ','), None);
+    }
+
+    #[test]
+    fn rejects_junk() {
+        for text in &["", " ", "-", "$", "abc", "12a", "1.2.3", "--5", "+-5", "(-5)", "5-", ",5", "1 2.3.4", "12.5$"] {
+            assert_eq!(parse(text), None, "{:?}", text);
+        }
+        assert_eq!(parse("99999999999999999999"), None);
+    }
+
+    #[test]
+    fn displays_grouped() {
+        assert_eq!(Money(0).to_string(), "0.00");
+        assert_eq!(Money(5).to_string(), "0.05");
+        assert_eq!(Money(99999).to_string(), "999.99");
+        assert_eq!(Money(100000).to_string(), "1,000.00");
+        assert_eq!(Money(123456789).to_string(), "1,234,567.89");
+    }
+
+    #[test]
+    fn displays_negatives() {
+        assert_eq!(Money(-5).to_string(), "-0.05");
+        assert_eq!(Money(-50).to_string(), "-0.50");
+        assert_eq!(Money(-100000).to_string(), "-1,000.00");
+        assert_eq!(Money(i64::MIN).to_string(), "-92,233,720,368,547,758.08");
+    }
+
+    #[test]
+    fn plain_is_ungrouped() {
+        assert_eq!(Money(-123450).plain(), "-1234.50");
+        assert_eq!(Money(5).plain(), "0.05");
+        assert_eq!(Money(-5).plain(), "-0.05");
+        assert_eq!(Money::parse(&Money(-123456789).plain()), Some(Money(-123456789)));
+        assert_eq!(Money(-123450).plain_with(','), "-1234,50");
+        assert_eq!(Money::parse_with(&Money(-123456789).plain_with(','), ','), Some(Money(-123456789)));
+    }
+
+    #[test]
+    fn converts_to_the_nearest_cent() {
+        assert_eq!(Money(1000).convert(0.8), Money(800));
+        assert_eq!(Money(333).convert(1.5), Money(500));
+        assert_eq!(Money(-333).convert(1.5), Money(-500));
+        assert_eq!(Money(1234).convert(0.0042), Money(5));
+        assert_eq!(Money(1).convert(0.004), Money(0));
+    }
+}
@@ -0,0 +1,523 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use ureq;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a user has to finish logging in at the provider.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum OidcError {
+        Http(err: Box<ureq::Error>) {
+            from(err: ureq::Error) -> (Box::new(err))
+            display("SSO provider request failed: {}", err)
+        }
+        Io(err: ::std::io::Error) {
+            from()
+            display("SSO provider response could not be read: {}", err)
+        }
+        Json(err: serde_json::Error) {
+            from()
+            display("SSO provider response was invalid: {}", err)
+        }
+        Base64(err: base64::DecodeError) {
+            from()
+            display("SSO id token was invalid: {}", err)
+        }
+        Invalid(reason: &'static str) {
+            display("SSO login failed: {}", reason)
+        }
+    }
+}
+
+fn default_scopes() -> String {
+    "openid profile email".to_string()
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OidcConfig {
+    /// Issuer url, without the `/.well-known/openid-configuration` suffix.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Must point at `/oidc/callback` on this server.
+    pub redirect_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    /// Claim used as the username of provisioned users.
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// Create a user on first login when no existing user is linked.
+    #[serde(default)]
+    pub auto_provision: bool,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+struct Pending {
+    verifier: String,
+    nonce: String,
+    link_user: Option<i32>,
+    created: Instant,
+}
+
+/// A login that completed at the provider.
+pub struct Identity {
+    pub subject: String,
+    pub username: Option<String>,
+    /// The user who started the login to link this identity, if any.
+    pub link_user: Option<i32>,
+}
+
+pub struct Oidc {
+    pub config: OidcConfig,
+    discovery: Mutex<Option<Discovery>>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Oidc {
+            config: config,
+            discovery: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.config.issuer
+    }
+
+    /// Starts a login, returning the state to remember in the browser and the
+    /// provider url to redirect to.
+    pub fn authorize_url(&self, link_user: Option<i32>) -> Result<(String, String), OidcError> {
+        let endpoint = self.with_discovery(|d| d.authorization_endpoint.clone())?;
+        let state = random_string(32);
+        let nonce = random_string(32);
+        let verifier = random_string(64);
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let url = format!("{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+                          endpoint,
+                          if endpoint.contains('?') { '&' } else { '?' },
+                          percent_encode(&self.config.client_id),
+                          percent_encode(&self.config.redirect_url),
+                          percent_encode(&self.config.scopes),
+                          state,
+                          nonce,
+                          challenge);
+
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| now.duration_since(p.created) < PENDING_TIMEOUT);
+        pending.insert(state.clone(), Pending {
+            verifier: verifier,
+            nonce: nonce,
+            link_user: link_user,
+            created: now,
+        });
+        Ok((state, url))
+    }
+
+    /// Exchanges the authorization code from the callback for the user's identity.
+    pub fn finish(&self, state: &str, code: &str) -> Result<Identity, OidcError> {
+        let pending = match self.pending.lock().unwrap().remove(state) {
+            Some(p) => p,
+            None => return Err(OidcError::Invalid("unknown or expired login")),
+        };
+        if pending.created.elapsed() >= PENDING_TIMEOUT {
+            return Err(OidcError::Invalid("unknown or expired login"));
+        }
+
+        let (token_endpoint, userinfo_endpoint) = self.with_discovery(|d| {
+            (d.token_endpoint.clone(), d.userinfo_endpoint.clone())
+        })?;
+        check_back_channel(&token_endpoint)?;
+        if let Some(ref endpoint) = userinfo_endpoint {
+            check_back_channel(endpoint)?;
+        }
+        let tokens: TokenResponse = ureq::post(&token_endpoint)
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+                ("code_verifier", &pending.verifier),
+            ])?
+            .into_json()?;
+
+        // The id token came straight from the token endpoint over TLS, enforced by
+        // check_back_channel, so its claims are checked but its signature is not
+        // (OpenID Connect Core 3.1.3.7). Id tokens from anywhere else must not be
+        // passed to decode_id_token.
+        let claims = decode_id_token(&tokens.id_token)?;
+        let issuer = claims.get("iss").and_then(Value::as_str).map(|iss| iss.trim_end_matches('/'));
+        if issuer != Some(self.config.issuer.trim_end_matches('/')) {
+            return Err(OidcError::Invalid("id token has the wrong issuer"));
+        }
+        let audience_ok = match claims.get("aud") {
+            Some(&Value::String(ref aud)) => *aud == self.config.client_id,
+            Some(&Value::Array(ref auds)) => auds.iter().any(|a| a.as_str() == Some(self.config.client_id.as_str())),
+            _ => false,
+        };
+        if !audience_ok {
+            return Err(OidcError::Invalid("id token has the wrong audience"));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if claims.get("exp").and_then(Value::as_u64).map_or(true, |exp| exp <= now) {
+            return Err(OidcError::Invalid("id token has expired"));
+        }
+        if claims.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+            return Err(OidcError::Invalid("id token has the wrong nonce"));
+        }
+        let subject = match claims.get("sub").and_then(Value::as_str) {
+            Some(sub) => sub.to_string(),
+            None => return Err(OidcError::Invalid("id token has no subject")),
+        };
+
+        let mut username = claims.get(&self.config.username_claim)
+            .and_then(Value::as_str)
+            .map(String::from);
+        if let (None, Some(endpoint)) = (username.as_ref(), userinfo_endpoint) {
+            let userinfo: HashMap<String, Value> = ureq::get(&endpoint)
+                .set("Authorization", &format!("Bearer {}", tokens.access_token))
+                .call()?
+                .into_json()?;
+            if userinfo.get("sub").and_then(Value::as_str) == Some(subject.as_str()) {
+                username = userinfo.get(&self.config.username_claim)
+                    .and_then(Value::as_str)
+                    .map(String::from);
+            }
+        }
+
+        Ok(Identity {
+            subject: subject,
+            username: username,
+            link_user: pending.link_user,
+        })
+    }
+
+    fn with_discovery<T, F: FnOnce(&Discovery) -> T>(&self, f: F) -> Result<T, OidcError> {
+        let mut discovery = self.discovery.lock().unwrap();
+        if discovery.is_none() {
+            let url = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+            check_back_channel(&url)?;
+            *discovery = Some(ureq::get(&url).call()?.into_json()?);
+        }
+        Ok(f(discovery.as_ref().unwrap()))
+    }
+}
+
+/// Id token signatures aren't checked, so everything fetched from the provider
+/// has to come over TLS. Plain http is only allowed to providers on the loopback
+/// interface, where nothing sits in between.
+fn check_back_channel(url: &str) -> Result<(), OidcError> {
+    if url.starts_with("https://") {
+        return Ok(());
+    }
+    let authority = match url.strip_prefix("http://") {
+        Some(rest) => rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or(""),
+        None => return Err(OidcError::Invalid("provider urls must use https")),
+    };
+    let host = authority.rsplit('@').next().unwrap_or("");
+    let host = if host.starts_with('[') {
+        host[1..].split(']').next().unwrap_or("")
+    } else {
+        host.split(':').next().unwrap_or("")
+    };
+    let loopback = host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false);
+    if loopback {
+        Ok(())
+    } else {
+        Err(OidcError::Invalid("provider urls must use https"))
+    }
+}
+
+fn decode_id_token(token: &str) -> Result<HashMap<String, Value>, OidcError> {
+    let payload = match token.split('.').nth(1) {
+        Some(p) => p,
+        None => return Err(OidcError::Invalid("id token is malformed")),
+    };
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('='))?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    const CLIENT_ID: &str = "hestia";
+
+    struct MockState {
+        token_endpoint: String,
+        id_claims: String,
+        userinfo: String,
+        token_forms: Vec<String>,
+    }
+
+    /// A provider on the loopback interface serving discovery, token and userinfo endpoints.
+    struct MockIssuer {
+        url: String,
+        state: Arc<Mutex<MockState>>,
+    }
+
+    impl MockIssuer {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(MockState {
+                token_endpoint: format!("{}/token", url),
+                id_claims: String::from("{}"),
+                userinfo: String::from("{}"),
+                token_forms: Vec::new(),
+            }));
+
+            let base = url.clone();
+            let shared = state.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if let Ok(stream) = stream {
+                        serve(stream, &base, &shared);
+                    }
+                }
+            });
+            MockIssuer {
+                url: url,
+                state: state,
+            }
+        }
+
+        fn oidc(&self) -> Oidc {
+            Oidc::new(OidcConfig {
+                issuer: self.url.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: "secret".to_string(),
+                redirect_url: "https://hestia.example/oidc/callback".to_string(),
+                scopes: default_scopes(),
+                username_claim: default_username_claim(),
+                auto_provision: false,
+            })
+        }
+
+        fn set_claims(&self, claims: &str) {
+            self.state.lock().unwrap().id_claims = claims.to_string();
+        }
+    }
+
+    fn serve(mut stream: TcpStream, base: &str, state: &Mutex<MockState>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            let lower = line.to_ascii_lowercase();
+            if let Some(value) = lower.strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut state = state.lock().unwrap();
+        let response = match request_line.split(' ').nth(1).unwrap_or("") {
+            "/.well-known/openid-configuration" => format!(
+                r#"{{"issuer":"{0}","authorization_endpoint":"{0}/authorize","token_endpoint":"{1}","userinfo_endpoint":"{0}/userinfo"}}"#,
+                base, state.token_endpoint),
+            "/token" => {
+                state.token_forms.push(String::from_utf8(body).unwrap());
+                format!(r#"{{"access_token":"access","token_type":"Bearer","id_token":"{}"}}"#,
+                        id_token(&state.id_claims))
+            }
+            "/userinfo" => state.userinfo.clone(),
+            _ => String::from("{}"),
+        };
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               response.len(), response).unwrap();
+    }
+
+    /// An unsigned token; only its claims are read.
+    fn id_token(claims: &str) -> String {
+        format!("{}.{}.signature",
+                URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+                URL_SAFE_NO_PAD.encode(claims))
+    }
+
+    fn query_value(url: &str, name: &str) -> String {
+        let query = url.splitn(2, '?').nth(1).unwrap();
+        query.split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                if parts.next() == Some(name) { parts.next() } else { None }
+            })
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn claims(issuer: &str, audience: &str, exp: u64, nonce: &str) -> String {
+        format!(r#"{{"iss":"{}","aud":"{}","exp":{},"nonce":"{}","sub":"subject-1","preferred_username":"alice"}}"#,
+                issuer, audience, exp, nonce)
+    }
+
+    /// Starts a login and has the provider answer with the claims built from its nonce.
+    fn login<F: Fn(&str) -> String>(issuer: &MockIssuer, oidc: &Oidc, make_claims: F)
+        -> (String, Result<Identity, OidcError>)
+    {
+        let (state, url) = oidc.authorize_url(None).unwrap();
+        issuer.set_claims(&make_claims(&query_value(&url, "nonce")));
+        let result = oidc.finish(&state, "code-1");
+        (url, result)
+    }
+
+    #[test]
+    fn finishes_login_with_pkce() {
+        let issuer = MockIssuer::start();
+        let oidc = issuer.oidc();
+        let (url, result) = login(&issuer, &oidc, |nonce| claims(&issuer.url, CLIENT_ID, now() + 300, nonce));
+        let identity = result.unwrap();
+        assert_eq!(identity.subject, "subject-1");
+        assert_eq!(identity.username.as_ref().map(String::as_str), Some("alice"));
+        assert_eq!(identity.link_user, None);
+
+        assert!(url.starts_with(&format!("{}/authorize?", issuer.url)));
+        assert_eq!(query_value(&url, "code_challenge_method"), "S256");
+        let forms = issuer.state.lock().unwrap().token_forms.clone();
+        assert_eq!(forms.len(), 1);
+        let verifier = forms[0].split('&')
+            .find(|pair| pair.starts_with("code_verifier="))
+            .unwrap()["code_verifier=".len()..]
+            .to_string();
+        assert_eq!(URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())),
+                   query_value(&url, "code_challenge"));
+        assert!(forms[0].contains("code=code-1"));
+    }
+
+    #[test]
+    fn state_is_single_use() {
+        let issuer = MockIssuer::start();
+        let oidc = issuer.oidc();
+        let (state, url) = oidc.authorize_url(Some(7)).unwrap();
+        issuer.set_claims(&claims(&issuer.url, CLIENT_ID, now() + 300, &query_value(&url, "nonce")));
+        assert_eq!(oidc.finish(&state, "code-1").unwrap().link_user, Some(7));
+        assert!(oidc.finish(&state, "code-1").is_err());
+        assert!(oidc.finish("unknown", "code-1").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_claims() {
+        let issuer = MockIssuer::start();
+        let oidc = issuer.oidc();
+        let base = issuer.url.as_str();
+        let fresh = now() + 300;
+        let cases = [
+            ("issuer", "https://evil.example", CLIENT_ID, fresh, None),
+            ("audience", base, "someone-else", fresh, None),
+            ("expired", base, CLIENT_ID, now() - 1, None),
+            ("nonce", base, CLIENT_ID, fresh, Some("replayed")),
+        ];
+        for &(name, iss, aud, exp, replayed) in cases.iter() {
+            let (_, result) = login(&issuer, &oidc, |nonce| claims(iss, aud, exp, replayed.unwrap_or(nonce)));
+            match result {
+                Err(OidcError::Invalid(_)) => (),
+                Err(e) => panic!("{}: unexpected error {}", name, e),
+                Ok(_) => panic!("{}: accepted", name),
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_userinfo_for_username() {
+        let issuer = MockIssuer::start();
+        let oidc = issuer.oidc();
+        issuer.state.lock().unwrap().userinfo = String::from(r#"{"sub":"subject-1","preferred_username":"bob"}"#);
+        let (_, result) = login(&issuer, &oidc, |nonce| {
+            format!(r#"{{"iss":"{}","aud":["{}"],"exp":{},"nonce":"{}","sub":"subject-1"}}"#,
+                    issuer.url, CLIENT_ID, now() + 300, nonce)
+        });
+        assert_eq!(result.unwrap().username.as_ref().map(String::as_str), Some("bob"));
+    }
+
+    #[test]
+    fn refuses_id_tokens_over_plain_http() {
+        let issuer = MockIssuer::start();
+        issuer.state.lock().unwrap().token_endpoint = String::from("http://idp.example/token");
+        let oidc = issuer.oidc();
+        let (_, result) = login(&issuer, &oidc, |nonce| claims(&issuer.url, CLIENT_ID, now() + 300, nonce));
+        match result {
+            Err(OidcError::Invalid(reason)) => assert_eq!(reason, "provider urls must use https"),
+            _ => panic!("token endpoint over plain http was used"),
+        }
+        assert!(issuer.state.lock().unwrap().token_forms.is_empty());
+    }
+
+    #[test]
+    fn back_channel_needs_tls_off_loopback() {
+        assert!(check_back_channel("https://idp.example/token").is_ok());
+        assert!(check_back_channel("http://127.0.0.1:8080/token").is_ok());
+        assert!(check_back_channel("http://localhost/token").is_ok());
+        assert!(check_back_channel("http://[::1]:80/token").is_ok());
+        assert!(check_back_channel("http://idp.example/token").is_err());
+        assert!(check_back_channel("http://127.0.0.1.idp.example/token").is_err());
+        assert!(check_back_channel("http://127.0.0.1@idp.example/token").is_err());
+        assert!(check_back_channel("ftp://127.0.0.1/token").is_err());
+    }
+}
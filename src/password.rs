@@ -0,0 +1,126 @@
+use Result;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum PasswordError {
+        Mismatch {
+            display("Passwords do not match")
+        }
+        Incorrect {
+            display("Current password is incorrect")
+        }
+        TooShort(min: usize) {
+            display("Password must be at least {} characters long", min)
+        }
+        Breached {
+            display("Password appears in a list of breached passwords")
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PolicyConfig {
+    pub min_length: usize,
+    /// File of known breached passwords, one per line.
+    pub breached_list: Option<PathBuf>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        PolicyConfig {
+            min_length: 8,
+            breached_list: None,
+        }
+    }
+}
+
+pub struct Policy {
+    min_length: usize,
+    breached: HashSet<String>,
+}
+
+impl Policy {
+    pub fn load(config: PolicyConfig) -> Result<Self> {
+        let breached = match config.breached_list {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty())
+                .map(String::from)
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Policy {
+            min_length: config.min_length,
+            breached: breached,
+        })
+    }
+
+    pub fn check(&self, password: &str) -> ::std::result::Result<(), PasswordError> {
+        if password.chars().count() < self.min_length {
+            Err(PasswordError::TooShort(self.min_length))
+        } else if self.breached.contains(password) {
+            Err(PasswordError::Breached)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Hashes a password with Argon2id.
+pub fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// Verifies a password against an Argon2 or legacy bcrypt hash.
+pub fn verify(password: &str, hash: &str) -> Result<bool> {
+    if hash.starts_with("$2") {
+        Ok(::bcrypt::verify(password, hash)?)
+    } else if hash.starts_with("$argon2") {
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                // A corrupt hash locks the account out rather than failing the login
+                eprintln!("Unparsable argon2 password hash: {}", e);
+                return Ok(false);
+            }
+        };
+        Ok(Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+    } else {
+        // Accounts without a usable password, e.g. ones provisioned by a proxy
+        Ok(false)
+    }
+}
+
+/// Returns true for hashes that should be replaced by a fresh Argon2id hash.
+pub fn needs_rehash(hash: &str) -> bool {
+    !hash.starts_with("$argon2id$")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_argon2_hashes() {
+        let hashed = hash("correct horse").unwrap();
+        assert!(verify("correct horse", &hashed).unwrap());
+        assert!(!verify("wrong horse", &hashed).unwrap());
+    }
+
+    #[test]
+    fn malformed_argon2_hash_does_not_verify() {
+        assert!(!verify("anything", "$argon2id$v=19$not-a-hash").unwrap());
+        assert!(!verify("anything", "$argon2").unwrap());
+    }
+}
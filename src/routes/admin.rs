@@ -0,0 +1,23 @@
+use templates::*;
+
+// GET /admin/lockouts
+route!{lockouts, req, res, ctx, {
+    let user = check_admin!(req, res, ctx);
+
+    let lockouts = ctx.throttle.lockouts();
+    let body = LockoutsTmpl { lockouts };
+    tmpl!(req, res, ctx, Some("Lockouts"), body);
+}}
+
+// POST /admin/lockouts
+route!{unlock, req, res, ctx, {
+    check_admin!(req, res, ctx);
+
+    let id = if let Some(id) = req.form_value("id") {
+        id
+    } else {
+        error!(req, res, ctx, "admin/lockouts", "Invalid input");
+    };
+    ctx.throttle.unlock(&id);
+    redirect!(req, res, ctx, "admin/lockouts", "Lockout removed");
+}}
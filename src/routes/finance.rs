@@ -1,64 +1,896 @@
-use db;
+use {db, export, import, Error, Result};
+use money::Money;
 use templates::*;
 use types::*;
 
+use hayaku::Request;
+use hayaku::header::{self, HeaderValue};
+
+use chrono::{Datelike, Months, NaiveDate, Utc};
+
 route!{home, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
-    let accounts = db::read::accounts(&ctx.db_pool, username)?;
-    let body = FinanceHomeTmpl { accounts };
+    let user = check_login!(req, res, ctx);
+    let accounts = db::read::accounts(&ctx.db_pool, user.id)?;
+    let writable = accounts.iter().filter(|a| a.writable).map(|a| (a.id, a.name.clone())).collect();
+    let households = db::read::households(&ctx.db_pool, user.id)?.into_iter().filter(|h| h.can_edit).collect();
+    let archived = db::read::archived_accounts(&ctx.db_pool, user.id)?;
+    let categories = db::read::categories(&ctx.db_pool, user.id)?;
+    let home_currency = db::read::home_currency(&ctx.db_pool, user.id)?;
+    let net_worth = db::read::net_worth(&ctx.db_pool, user.id, Utc::now().date_naive())?;
+    let unconverted = db::read::unconverted_currencies(&ctx.db_pool, user.id)?;
+    let body = FinanceHomeTmpl {
+        accounts,
+        writable,
+        households,
+        archived,
+        categories,
+        home_currency,
+        net_worth,
+        unconverted,
+    };
     tmpl!(req, res, ctx, None, body);
 }}
 
 // POST /account
 route!{new_account, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let account = NewAccount::new(req, owner);
     if account.is_none() {
-        redirect!(res, ctx, "finance", "Invalid input");
+        error!(req, res, ctx, "finance", "Invalid input");
+    }
+    if !db::create::account(pool, account.unwrap())? {
+        error!(req, res, ctx, "finance", "You can't add accounts to that household");
     }
-    db::create::account(pool, account.unwrap())?;
-    redirect!(res, ctx, "finance", "Account created");
+    redirect!(req, res, ctx, "finance", "Account created");
 }}
 
 // GET /account/{id}
 route!{view_account, req, res, ctx, {
-    let cookies = &req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let account = parse_param!(req, res, ctx, "id", i64);
-    let transactions = db::read::account(pool, owner, account)?;
+    let filter = match AccountFilter::new(req, ctx.decimal) {
+        Some(filter) => filter,
+        None => { error!(req, res, ctx, format!("finance/account/{}", account), "Invalid filter"); }
+    };
+    let transactions = db::read::account(pool, owner, account, &filter)?;
     let name = transactions.account.clone();
-    let body = FinanceAccountTmpl { transactions };
+    let today = Utc::now().date_naive();
+    let history = db::read::balance_history(pool, owner, account, ChartRange::Year.start(today), today)?;
+
+    let query = filter.query(ctx.decimal);
+    let older = transactions.older.map(|id| if query.is_empty() {
+        format!("before={}", id)
+    } else {
+        format!("{}&before={}", query, id)
+    });
+    let mut counterparties: Vec<_> = db::read::accounts(pool, owner)?
+        .into_iter()
+        .filter(|a| a.id != account)
+        .map(|a| AccountChoice { selected: filter.counterparty == Some(a.id), id: a.id, name: a.name })
+        .collect();
+    if let Some(none) = db::read::writable_account(pool, owner, "__none")? {
+        counterparties.insert(0, AccountChoice { id: none, name: "None".to_string(), selected: filter.counterparty == Some(none) });
+    }
+
+    let body = FinanceAccountTmpl {
+        transactions,
+        from: filter.from.map(|d| d.to_string()).unwrap_or_default(),
+        to: filter.to.map(|d| d.to_string()).unwrap_or_default(),
+        min: filter.min.map(|m| m.plain_with(ctx.decimal)).unwrap_or_default(),
+        max: filter.max.map(|m| m.plain_with(ctx.decimal)).unwrap_or_default(),
+        reason: filter.reason.clone().unwrap_or_default(),
+        counterparties,
+        chart: LineChart::new(&history),
+        newest: filter.before.map(|_| query.clone()),
+        older,
+    };
     tmpl!(req, res, ctx, Some(&name), body);
 }}
 
 // POST /account/{id}
 route!{edit_account, req, res, ctx, {
-    todo!();
+    let user = check_login!(req, res, ctx);
+    let account = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/account/{}", account);
+
+    let edit = EditAccount::new(req);
+    if edit.is_none() {
+        error!(req, res, ctx, path, "Invalid input");
+    }
+    if !db::update::account(&ctx.db_pool, user.id, account, &edit.unwrap())? {
+        error!(req, res, ctx, path, "You can't edit that account");
+    }
+    redirect!(req, res, ctx, path, "Account updated");
 }}
 
 // GET /account/{id}/delete
+route!{confirm_delete_account, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let account = parse_param!(req, res, ctx, "id", i64);
+    let (name, transaction_count) = match db::read::account_summary(pool, user.id, account)? {
+        Some(summary) => summary,
+        None => { error!(req, res, ctx, "finance", "You can't delete that account"); }
+    };
+    let targets = db::read::accounts(pool, user.id)?
+        .into_iter()
+        .filter(|a| a.writable && a.id != account)
+        .map(|a| (a.id, a.name))
+        .collect();
+    let body = DeleteAccountTmpl {
+        id: account,
+        name,
+        has_transactions: transaction_count > 0,
+        transaction_count,
+        targets,
+    };
+    tmpl!(req, res, ctx, Some("Delete Account"), body);
+}}
+
+// POST /account/{id}/delete
 route!{delete_account, req, res, ctx, {
-    todo!();
+    let user = check_login!(req, res, ctx);
+    let account = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/account/{}/delete", account);
+
+    let mode = match DeleteAccount::new(req) {
+        Some(mode) => mode,
+        // Accounts without transactions have nothing to move
+        None => match db::read::account_summary(&ctx.db_pool, user.id, account)? {
+            Some((_, 0)) => DeleteAccount::Remove,
+            Some(_) => { error!(req, res, ctx, path, "Choose what to do with the transactions"); }
+            None => { error!(req, res, ctx, "finance", "You can't delete that account"); }
+        },
+    };
+    let failure = match mode {
+        DeleteAccount::Move(_) => "You can't move transactions to that account, or it's in another currency",
+        DeleteAccount::Remove => "Some of its transactions are reconciled or involve accounts you can't edit",
+    };
+    if !db::delete::account(&ctx.db_pool, user.id, account, mode)? {
+        error!(req, res, ctx, path, failure);
+    }
+    redirect!(req, res, ctx, "finance", "Account deleted");
 }}
 
 // POST /transaction
 route!{new_transaction, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let transaction = NewTransaction::new(req, owner);
     if transaction.is_none() {
-        redirect!(res, ctx, "finance", "Invalid input");
+        error!(req, res, ctx, "finance", "Invalid input");
+    }
+    match db::create::transaction(pool, transaction.unwrap()) {
+        Ok(true) => {}
+        Ok(false) => { error!(req, res, ctx, "finance", "You can't post to those accounts"); }
+        Err(e @ Error::NoExchangeRate(..)) => { error!(req, res, ctx, "finance", e.to_string()); }
+        Err(e) => return Err(e),
+    }
+    redirect!(req, res, ctx, "finance", "Transaction processed");
+}}
+
+// GET /account/{id}/reconcile
+route!{reconcile, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    let started = req.form_value("date").is_some();
+    let statement = match StatementEnd::new(req, ctx.decimal) {
+        Some(statement) => Some(statement),
+        None if !started => None,
+        None => { error!(req, res, ctx, format!("finance/account/{}/reconcile", id), "Invalid statement date or balance"); }
+    };
+    let today = Utc::now().date_naive();
+    let reconciliation = match db::read::reconciliation(pool, user.id, id, statement.as_ref().map_or(today, |s| s.date))? {
+        Some(r) => r,
+        None => { error!(req, res, ctx, "finance", "You can't reconcile that account"); }
+    };
+
+    let cleared = reconciliation.reconciled + reconciliation.transactions.iter()
+        .filter(|t| t.cleared)
+        .map(|t| t.amount)
+        .sum::<Money>();
+    let difference = statement.as_ref().map_or(Money::ZERO, |s| s.balance - cleared);
+    let title = format!("Reconcile {}", reconciliation.account);
+    let body = ReconcileTmpl {
+        id,
+        name: reconciliation.account,
+        currency: reconciliation.currency,
+        date: statement.as_ref().map_or(today.to_string(), |s| s.date.to_string()),
+        balance: statement.as_ref().map(|s| s.balance.plain_with(ctx.decimal)).unwrap_or_default(),
+        started: statement.is_some(),
+        reconciled: reconciliation.reconciled,
+        cleared,
+        difference,
+        balanced: difference == Money::ZERO,
+        transactions: if statement.is_some() { reconciliation.transactions } else { Vec::new() },
+    };
+    tmpl!(req, res, ctx, Some(&title), body);
+}}
+
+// POST /account/{id}/reconcile
+route!{save_reconcile, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/account/{}/reconcile", id);
+
+    let statement = match StatementEnd::new(req, ctx.decimal) {
+        Some(statement) => statement,
+        None => { error!(req, res, ctx, path, "Invalid statement date or balance"); }
+    };
+    let path = format!("{}?date={}&balance={}", path, statement.date, statement.balance.plain_with(ctx.decimal));
+    let reconciliation = match db::read::reconciliation(pool, user.id, id, statement.date)? {
+        Some(r) => r,
+        None => { error!(req, res, ctx, "finance", "You can't reconcile that account"); }
+    };
+
+    let mut cleared = Vec::new();
+    let mut cleared_balance = reconciliation.reconciled;
+    for t in &reconciliation.transactions {
+        if req.form_value(&format!("cleared-{}", t.id)).is_some() {
+            cleared.push(t.id);
+            cleared_balance += t.amount;
+        }
+    }
+    if !db::update::clear_transactions(pool, user.id, id, statement.date, &cleared)? {
+        error!(req, res, ctx, "finance", "You can't reconcile that account");
+    }
+
+    if req.form_value("action").map_or(false, |a| a == "finish") {
+        if cleared_balance != statement.balance {
+            error!(req, res, ctx, path, "The cleared balance doesn't match the statement");
+        }
+        if !db::update::reconcile(pool, user.id, id, statement.date)? {
+            error!(req, res, ctx, "finance", "You can't reconcile that account");
+        }
+        redirect!(req, res, ctx, format!("finance/account/{}", id), "Statement reconciled");
+    }
+    redirect!(req, res, ctx, path, "Cleared transactions saved");
+}}
+
+// GET /transaction/{id}
+route!{view_transaction, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let transaction = match db::read::transaction(pool, user.id, id)? {
+        Some(t) => t,
+        None => { error!(req, res, ctx, "finance", "You can't edit that transaction"); }
+    };
+
+    let accounts = posting_accounts(pool, user.id)?;
+    let choices = |selected: i64| -> Vec<AccountChoice> {
+        accounts.iter()
+            .map(|&(id, ref name)| AccountChoice { id, name: name.clone(), selected: id == selected })
+            .collect()
+    };
+    let from = choices(transaction.from);
+    let to = choices(transaction.to);
+    let categories = db::read::categories(pool, user.id)?
+        .into_iter()
+        .map(|c| CategoryChoice { selected: transaction.category == Some(c.id), id: c.id, path: c.path })
+        .collect();
+
+    let rows = if transaction.split { split_rows(pool, user.id, &transaction.postings)? } else { Vec::new() };
+
+    let body = FinanceTransactionTmpl { transaction, from, to, categories, rows };
+    tmpl!(req, res, ctx, Some("Transaction"), body);
+}}
+
+/// Accounts the user can post to, with outside money first as "None".
+fn posting_accounts(pool: &db::Pool, user: i32) -> Result<Vec<(i64, String)>> {
+    let mut accounts: Vec<_> = db::read::accounts(pool, user)?
+        .into_iter()
+        .filter(|a| a.writable)
+        .map(|a| (a.id, a.name))
+        .collect();
+    if let Some(none) = db::read::writable_account(pool, user, "__none")? {
+        accounts.insert(0, (none, "None".to_string()));
+    }
+    Ok(accounts)
+}
+
+/// The rows of the split transaction form, filled in with the legs and with
+/// at least one empty row.
+fn split_rows(pool: &db::Pool, user: i32, postings: &[PostingDetails]) -> Result<Vec<SplitRow>> {
+    let accounts = posting_accounts(pool, user)?;
+    let categories = db::read::categories(pool, user)?;
+    Ok((0..SPLIT_LEGS.max(postings.len() + 1)).map(|n| {
+        let posting = postings.get(n);
+        SplitRow {
+            n,
+            accounts: accounts.iter()
+                .map(|&(id, ref name)| AccountChoice { id, name: name.clone(), selected: posting.map_or(false, |p| p.account == id) })
+                .collect(),
+            categories: categories.iter()
+                .map(|c| CategoryChoice { id: c.id, path: c.path.clone(), selected: posting.map_or(false, |p| p.category == Some(c.id)) })
+                .collect(),
+            amount: posting.map(|p| p.amount.plain()).unwrap_or_default(),
+        }
+    }).collect())
+}
+
+// GET /split
+route!{split_form, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let rows = split_rows(&ctx.db_pool, user.id, &[])?;
+    let body = SplitTmpl { rows };
+    tmpl!(req, res, ctx, Some("Split Transaction"), body);
+}}
+
+// POST /split
+route!{new_split, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let split = NewSplit::new(req, user.id);
+    if split.is_none() {
+        error!(req, res, ctx, "finance/split", "Invalid input, the amounts must add up to zero");
+    }
+    match db::create::split(&ctx.db_pool, split.unwrap()) {
+        Ok(true) => {}
+        Ok(false) => { error!(req, res, ctx, "finance/split", "You can't post to those accounts"); }
+        Err(e @ Error::MixedCurrencies) => { error!(req, res, ctx, "finance/split", e.to_string()); }
+        Err(e) => return Err(e),
+    }
+    redirect!(req, res, ctx, "finance", "Transaction processed");
+}}
+
+// POST /transaction/{id}
+route!{edit_transaction, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/transaction/{}", id);
+
+    let transaction = NewTransaction::new(req, user.id);
+    if transaction.is_none() {
+        error!(req, res, ctx, path, "Invalid input");
+    }
+    match db::update::transaction(&ctx.db_pool, user.id, id, transaction.unwrap()) {
+        Ok(true) => {}
+        Ok(false) => { error!(req, res, ctx, path, "You can't post to those accounts"); }
+        Err(e @ Error::NoExchangeRate(..)) => { error!(req, res, ctx, path, e.to_string()); }
+        Err(e) => return Err(e),
+    }
+    redirect!(req, res, ctx, path, "Transaction updated");
+}}
+
+// POST /transaction/{id}/split
+route!{edit_split, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/transaction/{}", id);
+
+    let split = NewSplit::new(req, user.id);
+    if split.is_none() {
+        error!(req, res, ctx, path, "Invalid input, the amounts must add up to zero");
+    }
+    match db::update::split(&ctx.db_pool, user.id, id, split.unwrap()) {
+        Ok(true) => {}
+        Ok(false) => { error!(req, res, ctx, path, "You can't post to those accounts"); }
+        Err(e @ Error::MixedCurrencies) => { error!(req, res, ctx, path, e.to_string()); }
+        Err(e) => return Err(e),
+    }
+    redirect!(req, res, ctx, path, "Transaction updated");
+}}
+
+// POST /transaction/{id}/void
+route!{void_transaction, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/transaction/{}", id);
+
+    let voided = req.form_value("voided").map_or(false, |v| v == "true");
+    if !db::update::void_transaction(&ctx.db_pool, user.id, id, voided)? {
+        error!(req, res, ctx, "finance", "You can't edit that transaction");
+    }
+    redirect!(req, res, ctx, path, if voided { "Transaction voided" } else { "Transaction restored" });
+}}
+
+// GET /transaction/{id}/delete
+route!{confirm_delete_transaction, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let back = format!("/finance/transaction/{}", id);
+    let body = ConfirmTmpl {
+        message: "Delete this transaction?",
+        action: format!("/finance/transaction/{}/delete", id),
+        back: &back,
+    };
+    tmpl!(req, res, ctx, Some("Delete Transaction"), body);
+}}
+
+// POST /transaction/{id}/delete
+route!{delete_transaction, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    if !db::delete::transaction(&ctx.db_pool, user.id, id)? {
+        error!(req, res, ctx, "finance", "You can't delete that transaction");
+    }
+    redirect!(req, res, ctx, "finance", "Transaction deleted");
+}}
+
+// GET /categories
+route!{categories, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let categories = db::read::categories(&ctx.db_pool, user.id)?;
+    let body = CategoriesTmpl { categories };
+    tmpl!(req, res, ctx, Some("Categories"), body);
+}}
+
+// POST /categories
+route!{new_category, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let category = NewCategory::new(req);
+    if category.is_none() {
+        error!(req, res, ctx, "finance/categories", "Invalid input");
+    }
+    if !db::create::category(&ctx.db_pool, user.id, category.unwrap())? {
+        error!(req, res, ctx, "finance/categories", "Invalid parent category");
+    }
+    redirect!(req, res, ctx, "finance/categories", "Category created");
+}}
+
+// POST /categories/{id}/delete
+route!{delete_category, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    if !db::delete::category(&ctx.db_pool, user.id, id)? {
+        error!(req, res, ctx, "finance/categories", "You can't delete that category");
+    }
+    redirect!(req, res, ctx, "finance/categories", "Category deleted");
+}}
+
+// GET /report
+route!{report, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+
+    let period = match Period::new(req) {
+        Some(period) => period,
+        None => { error!(req, res, ctx, "finance/report", "Invalid period"); }
+    };
+    let range = format!("from={}&to={}", period.from, period.to);
+    let total = |name: String, query: String, depth: usize, amount: Money| Total {
+        name,
+        query: format!("{}&{}", range, query),
+        depth,
+        amount,
+    };
+
+    let category_list = db::read::categories(pool, user.id)?;
+    let category_totals = db::read::category_totals(pool, user.id, &period)?;
+    let mut categories: Vec<Total> = category_list.iter()
+        .filter_map(|c| {
+            let amount = *category_totals.get(&c.id)?;
+            Some(total(c.path.clone(), format!("category={}", c.id), c.path.matches(':').count(), amount))
+        })
+        .collect();
+    let uncategorized = db::read::uncategorized_total(pool, user.id, &period)?;
+    if uncategorized != Money::ZERO {
+        categories.push(total("Uncategorized".to_string(), "category=none".to_string(), 0, uncategorized));
+    }
+    let tags = db::read::tag_totals(pool, user.id, &period)?
+        .into_iter()
+        .map(|(tag, amount)| {
+            let query = format!("tag={}", ::routes::util::percent_encode(&tag));
+            total(tag, query, 0, amount)
+        })
+        .collect();
+
+    let (selected, transactions) = match ReportFilter::new(req) {
+        Some(filter) => {
+            let name = match filter {
+                ReportFilter::Category(id) => category_list.iter()
+                    .find(|c| c.id == id)
+                    .map(|c| c.path.clone())
+                    .unwrap_or_default(),
+                ReportFilter::Tag(ref tag) => format!("#{}", tag),
+                ReportFilter::Uncategorized => "Uncategorized".to_string(),
+            };
+            (Some(name), db::read::report_transactions(pool, user.id, &period, &filter)?)
+        }
+        None => (None, Vec::new()),
+    };
+
+    let body = ReportTmpl {
+        from: period.from.to_string(),
+        to: period.to.to_string(),
+        categories,
+        tags,
+        home_currency: db::read::home_currency(pool, user.id)?,
+        unconverted: db::read::unconverted_currencies(pool, user.id)?,
+        selected,
+        transactions,
+    };
+    tmpl!(req, res, ctx, Some("Spending Report"), body);
+}}
+
+// GET /net-worth
+route!{net_worth, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+
+    let range = match ChartRange::new(req) {
+        Some(range) => range,
+        None => { error!(req, res, ctx, "finance/net-worth", "Invalid range"); }
+    };
+    let today = Utc::now().date_naive();
+    let history = db::read::net_worth_history(pool, user.id, range.start(today), today)?;
+    let (assets, liabilities) = db::read::assets_and_liabilities(pool, user.id, today)?;
+
+    let body = NetWorthTmpl {
+        home_currency: db::read::home_currency(pool, user.id)?,
+        assets,
+        liabilities,
+        net_worth: assets - liabilities,
+        unconverted: db::read::unconverted_currencies(pool, user.id)?,
+        ranges: ChartRange::ALL.iter().map(|&r| (r.value(), r.name(), r == range)).collect(),
+        chart: LineChart::new(&history),
+    };
+    tmpl!(req, res, ctx, Some("Net Worth"), body);
+}}
+
+// GET /budgets
+route!{budgets, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+
+    let today = Utc::now().date_naive();
+    let month = match req.form_value("month") {
+        Some(ref m) if !m.is_empty() => match NaiveDate::parse_from_str(&format!("{}-01", m), "%Y-%m-%d") {
+            Ok(month) => month,
+            Err(_) => { error!(req, res, ctx, "finance/budgets", "Invalid month"); }
+        },
+        _ => today.with_day(1).unwrap(),
+    };
+    let budgets = db::read::budgets(pool, user.id, month, today)?;
+    let categories = db::read::categories(pool, user.id)?;
+    let accounts = db::read::accounts(pool, user.id)?.into_iter().map(|a| (a.id, a.name)).collect();
+
+    let body = BudgetsTmpl {
+        month: month.format("%Y-%m").to_string(),
+        previous: (month - Months::new(1)).format("%Y-%m").to_string(),
+        next: (month + Months::new(1)).format("%Y-%m").to_string(),
+        budgets,
+        home_currency: db::read::home_currency(pool, user.id)?,
+        categories,
+        accounts,
+    };
+    tmpl!(req, res, ctx, Some("Budgets"), body);
+}}
+
+// POST /budgets
+route!{new_budget, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let budget = NewBudget::new(req);
+    if budget.is_none() {
+        error!(req, res, ctx, "finance/budgets", "Invalid input");
+    }
+    if !db::create::budget(&ctx.db_pool, user.id, budget.unwrap())? {
+        error!(req, res, ctx, "finance/budgets", "You can't budget for that");
+    }
+    redirect!(req, res, ctx, "finance/budgets", "Budget created");
+}}
+
+// POST /budgets/{id}/delete
+route!{delete_budget, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    if !db::delete::budget(&ctx.db_pool, user.id, id)? {
+        error!(req, res, ctx, "finance/budgets", "You can't delete that budget");
+    }
+    redirect!(req, res, ctx, "finance/budgets", "Budget deleted");
+}}
+
+// GET /scheduled
+route!{scheduled, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let today = Utc::now().date_naive();
+    let scheduled = db::read::scheduled_transactions(pool, user.id, today)?;
+    let writable = db::read::accounts(pool, user.id)?.into_iter().filter(|a| a.writable).map(|a| (a.id, a.name)).collect();
+    let categories = db::read::categories(pool, user.id)?;
+    let body = ScheduledTmpl { scheduled, writable, categories, today };
+    tmpl!(req, res, ctx, Some("Scheduled Transactions"), body);
+}}
+
+// POST /scheduled
+route!{new_scheduled, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let schedule = NewSchedule::new(req, user.id);
+    if schedule.is_none() {
+        error!(req, res, ctx, "finance/scheduled", "Invalid input");
+    }
+    if !db::create::scheduled_transaction(&ctx.db_pool, schedule.unwrap())? {
+        error!(req, res, ctx, "finance/scheduled", "You can't post to those accounts");
+    }
+    redirect!(req, res, ctx, "finance/scheduled", "Transaction scheduled");
+}}
+
+// POST /scheduled/{id}/skip
+route!{skip_scheduled, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    if !db::update::skip_schedule(&ctx.db_pool, user.id, id)? {
+        error!(req, res, ctx, "finance/scheduled", "Nothing to skip");
+    }
+    redirect!(req, res, ctx, "finance/scheduled", "Occurrence skipped");
+}}
+
+// POST /scheduled/{id}/post
+route!{post_scheduled, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    match db::create::scheduled_occurrence(&ctx.db_pool, id, Some(user.id), Some(Utc::now())) {
+        Ok(Some(true)) => {}
+        Ok(Some(false)) => { error!(req, res, ctx, "finance/scheduled", "You can't post to those accounts"); }
+        Ok(None) => { error!(req, res, ctx, "finance/scheduled", "Nothing to post"); }
+        Err(e @ Error::NoExchangeRate(..)) => { error!(req, res, ctx, "finance/scheduled", e.to_string()); }
+        Err(e) => return Err(e),
+    }
+    redirect!(req, res, ctx, "finance/scheduled", "Transaction processed");
+}}
+
+// POST /scheduled/{id}/delete
+route!{delete_scheduled, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    if !db::delete::scheduled_transaction(&ctx.db_pool, user.id, id)? {
+        error!(req, res, ctx, "finance/scheduled", "You can't delete that schedule");
+    }
+    redirect!(req, res, ctx, "finance/scheduled", "Schedule deleted");
+}}
+
+// GET /account/{id}/import
+route!{import_form, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let name = match db::read::account_summary(pool, user.id, id)? {
+        Some((name, _)) => name,
+        None => { error!(req, res, ctx, "finance", "You can't import to that account"); }
+    };
+    let profiles = db::read::import_profiles(pool, user.id)?.into_iter().map(|p| (p.id, p.name)).collect();
+    let body = ImportTmpl { id, name, profiles };
+    tmpl!(req, res, ctx, Some("Import"), body);
+}}
+
+// POST /account/{id}/import/profile
+route!{new_import_profile, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/account/{}/import", id);
+
+    let profile = NewImportProfile::new(req);
+    if profile.is_none() {
+        error!(req, res, ctx, path, "Invalid input");
+    }
+    db::create::import_profile(&ctx.db_pool, user.id, &profile.unwrap())?;
+    redirect!(req, res, ctx, path, "Import profile created");
+}}
+
+// POST /account/{id}/import/preview
+route!{import_preview, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/account/{}/import", id);
+
+    let name = match db::read::account_summary(pool, user.id, id)? {
+        Some((name, _)) => name,
+        None => { error!(req, res, ctx, "finance", "You can't import to that account"); }
+    };
+    let (format, profile, statement, text) = match read_statement(req, pool, user.id, id)? {
+        Ok(parsed) => parsed,
+        Err(e) => { error!(req, res, ctx, path, e); }
+    };
+
+    let balance = db::read::account(pool, user.id, id, &AccountFilter::default())?.balance;
+    let rows = statement.rows;
+    let balance_after = balance + rows.iter().filter(|r| !r.duplicate).map(|r| r.amount).sum::<Money>();
+    let body = ImportPreviewTmpl {
+        id,
+        name,
+        format,
+        profile,
+        statement: text,
+        duplicates: rows.iter().filter(|r| r.duplicate).count(),
+        balance,
+        balance_after,
+        statement_balance: statement.ledger_balance,
+        difference: statement.ledger_balance.map(|b| b - balance),
+        difference_after: statement.ledger_balance.map(|b| b - balance_after),
+        rows: rows.iter().map(PreviewRow::new).collect(),
+    };
+    tmpl!(req, res, ctx, Some("Import"), body);
+}}
+
+// POST /account/{id}/import
+route!{import, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let path = format!("finance/account/{}/import", id);
+
+    let rows = match read_statement(req, pool, user.id, id)? {
+        Ok((_, _, statement, _)) => statement.rows,
+        Err(e) => { error!(req, res, ctx, path, e); }
+    };
+    let accepted: Vec<_> = rows.into_iter()
+        .filter(|r| req.form_value(&format!("row-{}", r.index)).is_some())
+        .collect();
+    if !db::create::import(pool, user.id, id, &accepted)? {
+        error!(req, res, ctx, "finance", "You can't import to that account");
+    }
+    redirect!(req, res, ctx, format!("finance/account/{}", id), format!("Imported {} transactions", accepted.len()));
+}}
+
+/// Parses the statement in the `statement` field as OFX or, with the import
+/// profile in the `profile` field, CSV. Returns the format, profile id, parsed
+/// statement with duplicates marked and the statement text, or an error to show.
+fn read_statement(req: &mut Request, pool: &db::Pool, user: i32, account: i64)
+    -> Result<::std::result::Result<(&'static str, Option<i64>, import::Statement, String), String>>
+{
+    let text = req.form_value("statement").unwrap_or_default();
+    let (format, profile, parsed) = if req.form_value("format").map_or(false, |f| f == "ofx") {
+        ("ofx", None, import::ofx::parse(&text))
+    } else {
+        let profile = match req.form_value("profile").and_then(|p| p.parse().ok()) {
+            Some(profile) => profile,
+            None => return Ok(Err("Choose an import profile".to_string())),
+        };
+        let profile = match db::read::import_profile(pool, user, profile)? {
+            Some(profile) => profile,
+            None => return Ok(Err("Unknown import profile".to_string())),
+        };
+        ("csv", Some(profile.id), import::csv::parse(&text, &profile.columns))
+    };
+    let mut statement = match parsed {
+        Ok(statement) => statement,
+        Err(e) => return Ok(Err(e.to_string())),
+    };
+    if statement.rows.is_empty() {
+        return Ok(Err("The statement has no transactions".to_string()));
+    }
+    db::read::mark_duplicates(pool, account, &mut statement.rows)?;
+    Ok(Ok((format, profile, statement, text)))
+}
+
+// GET /journal
+route!{journal_form, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let body = JournalTmpl { summary: None, problems: Vec::new() };
+    tmpl!(req, res, ctx, Some("Import Journal"), body);
+}}
+
+// POST /journal
+route!{import_journal, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let mut journal = import::ledger::parse(&req.form_value("journal").unwrap_or_default());
+    let (posted, skipped) = match db::create::journal(&ctx.db_pool, user.id, &journal.entries, &mut journal.problems) {
+        Ok(counts) => counts,
+        Err(e @ Error::NoExchangeRate(..)) => { error!(req, res, ctx, "finance/journal", e.to_string()); }
+        Err(e @ Error::MixedCurrencies) => { error!(req, res, ctx, "finance/journal", e.to_string()); }
+        Err(e) => return Err(e),
+    };
+    let body = JournalTmpl {
+        summary: Some(format!("Imported {} transactions, skipped {} already imported", posted, skipped)),
+        problems: journal.problems,
+    };
+    tmpl!(req, res, ctx, Some("Import Journal"), body);
+}}
+
+// GET /rates
+route!{rates, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let pool = &ctx.db_pool;
+    let body = RatesTmpl {
+        home_currency: db::read::home_currency(pool, user.id)?,
+        unconverted: db::read::unconverted_currencies(pool, user.id)?,
+        rates: db::read::exchange_rates(pool, user.id)?,
+        today: Utc::now().date_naive(),
+    };
+    tmpl!(req, res, ctx, Some("Exchange Rates"), body);
+}}
+
+// POST /rates
+route!{new_rate, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let rate = match NewExchangeRate::new(req) {
+        Some(rate) => rate,
+        None => { error!(req, res, ctx, "finance/rates", "Invalid input"); }
+    };
+    db::create::exchange_rates(&ctx.db_pool, user.id, &[rate])?;
+    redirect!(req, res, ctx, "finance/rates", "Exchange rate added");
+}}
+
+// POST /rates/import
+route!{import_rates, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let rates = match import::csv::parse_rates(&req.form_value("rates").unwrap_or_default()) {
+        Ok(rates) => rates,
+        Err(e) => { error!(req, res, ctx, "finance/rates", e.to_string()); }
+    };
+    let n = db::create::exchange_rates(&ctx.db_pool, user.id, &rates)?;
+    redirect!(req, res, ctx, "finance/rates", format!("Imported {} exchange rates", n));
+}}
+
+// POST /rates/{id}/delete
+route!{delete_rate, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    if !db::delete::exchange_rate(&ctx.db_pool, user.id, id)? {
+        error!(req, res, ctx, "finance/rates", "You can't delete that exchange rate");
+    }
+    redirect!(req, res, ctx, "finance/rates", "Exchange rate deleted");
+}}
+
+// POST /currency
+route!{home_currency, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let currency = match req.form_value("currency").and_then(|c| parse_currency(&c)) {
+        Some(currency) => currency,
+        None => { error!(req, res, ctx, "finance/rates", "Invalid currency code"); }
+    };
+    db::update::home_currency(&ctx.db_pool, user.id, &currency)?;
+    redirect!(req, res, ctx, "finance/rates", "Home currency saved");
+}}
+
+// GET /export
+route!{export, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let period = match Period::new(req) {
+        Some(period) => period,
+        None => { error!(req, res, ctx, "finance/export", "Invalid period"); }
+    };
+    let format = match req.form_value("format").and_then(|f| ExportFormat::from_str(&f)) {
+        Some(format) => format,
+        None => {
+            let body = ExportTmpl { from: period.from.to_string(), to: period.to.to_string() };
+            tmpl!(req, res, ctx, Some("Export"), body);
+        }
+    };
+
+    let transactions = db::read::export_transactions(&ctx.db_pool, user.id, &period)?;
+    let (content_type, body) = match format {
+        ExportFormat::Ledger => ("text/plain; charset=utf-8", export::ledger(&transactions)),
+        ExportFormat::Beancount => {
+            let home = db::read::home_currency(&ctx.db_pool, user.id)?;
+            ("text/plain; charset=utf-8", export::beancount(&transactions, period.from, &home))
+        }
+        ExportFormat::Csv => match export::csv(&transactions) {
+            Ok(csv) => ("text/csv; charset=utf-8", csv),
+            Err(_) => { error!(req, res, ctx, "finance/export", "Export failed"); }
+        },
+    };
+    let filename = format!("attachment; filename=\"hestia-{}-{}.{}\"", period.from, period.to, format.extension());
+    res.add_header(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(disposition) = HeaderValue::from_str(&filename) {
+        res.add_header(header::CONTENT_DISPOSITION, disposition);
     }
-    db::create::transaction(pool, transaction.unwrap())?;
-    redirect!(res, ctx, "finance", "Transaction processed");
+    ok!(res.body(body));
 }}
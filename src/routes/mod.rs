@@ -1,27 +1,35 @@
-use {db, Context, Error};
+use {db, password, Context, Error, Result};
+use throttle::Key;
 use templates::*;
 use types::*;
 
+pub mod admin;
 pub mod finance;
 pub mod inventory;
 pub mod reminders;
 pub mod settings;
-mod util;
+pub mod sso;
+pub mod util;
 
+use chrono::Utc;
 use hayaku::{Request, Response, Status};
 use hayaku::header::{self, HeaderValue};
 
 route!{home, req, res, ctx, {
-    let cookies = &req.get_cookies();
-    if let Some(username) = util::check_login(ctx, cookies)? {
-        let links = db::read::quick_links(&ctx.db_pool, username)?;
-        let search_uses = db::read::search_uses(&ctx.db_pool, username)?;
-        let reminders = db::read::reminders(&ctx.db_pool, username)?;
-        let api_key = db::read::user(&ctx.db_pool, username)?.api_key;
-        let body = HomeTmpl { links, search_uses, reminders, api_key };
+    if let Some(user) = util::current_user(req, res, ctx)? {
+        let links = db::read::quick_links(&ctx.db_pool, user.id)?;
+        let search_uses = db::read::search_uses(&ctx.db_pool, user.id)?;
+        let reminders = db::read::reminders(&ctx.db_pool, user.id)?;
+        let api_key = db::read::user(&ctx.db_pool, user.id)?.api_key;
+        let households = db::read::households(&ctx.db_pool, user.id)?.into_iter().filter(|h| h.can_edit).collect();
+        let profile = db::read::profile(&ctx.db_pool, user.id)?;
+        let today = Utc::now().date_naive();
+        let budgets = db::read::budgets(&ctx.db_pool, user.id, today, today)?.into_iter().filter(|b| b.warning).collect();
+        let body = HomeTmpl { profile, links, search_uses, reminders, api_key, households, budgets };
         tmpl!(req, res, ctx, Some("Home"), body);
     } else {
-        let body = include_str!("../../templates/login.html");
+        // A proxy name gets here only when it couldn't be logged in
+        let body = LoginTmpl { sso: ctx.oidc.is_some(), proxy_name: util::proxy_user(req, ctx) };
         tmpl!(req, res, ctx, Some("Login"), body);
     }
 }}
@@ -30,8 +38,8 @@ route!{home, req, res, ctx, {
 route!{signup, req, res, ctx, {
     if !ctx.signup {
         return not_found(req, res, ctx);
-    } else if util::check_login(ctx, &req.get_cookies())?.is_some() {
-        redirect!(res, ctx, "", "You already have an account");
+    } else if util::current_user(req, res, ctx)?.is_some() {
+        redirect!(req, res, ctx, "", "You already have an account");
     } else {
         let body = include_str!("../../templates/signup.html");
         tmpl!(req, res, ctx, Some("Signup"), body);
@@ -42,86 +50,132 @@ route!{signup, req, res, ctx, {
 route!{signup_post, req, res, ctx, {
     if !ctx.signup {
         return not_found(req, res, ctx);
-    } else if util::check_login(ctx, &req.get_cookies())?.is_some() {
-        redirect!(res, ctx, "", "You already have an account");
+    } else if util::current_user(req, res, ctx)?.is_some() {
+        redirect!(req, res, ctx, "", "You already have an account");
     }
 
-    let new_user = if let Some(user) = Login::new_user(req) {
-        user
-    } else {
-        redirect!(res, ctx, "signup", "Signup failed");
+    let new_user = match Login::new_user(req, &ctx.password_policy) {
+        Some(Ok(user)) => user,
+        Some(Err(e)) => {
+            error!(req, res, ctx, "signup", e.to_string());
+        }
+        None => {
+            error!(req, res, ctx, "signup", "Signup failed");
+        }
     };
 
-    db::create::user(&ctx.db_pool, &new_user, ctx.default_bang.clone())?;
-    util::login(new_user.username, &mut res.cookies(), ctx)?;
-    redirect!(res, ctx, "", "Signup successful");
+    let id = db::create::user(&ctx.db_pool, &new_user, ctx.default_bang.clone())?;
+    util::login(id, new_user.username, &mut res.cookies(), ctx)?;
+    redirect!(req, res, ctx, "", "Signup successful");
 }}
 
 // POST /login
 route!{login, req, res, ctx, {
-    if util::check_login(ctx, &req.get_cookies())?.is_some() {
-        redirect!(res, ctx, "", "You are already logged in");
+    if util::current_user(req, res, ctx)?.is_some() {
+        redirect!(req, res, ctx, "", "You are already logged in");
+    }
+
+    let ip = util::client_ip(req, ctx);
+    if ctx.throttle.is_locked(&Key::LoginIp(ip)) {
+        error!(req, res, ctx, "", "Too many failed logins, try again later");
     }
 
     let login = if let Some(login) = Login::new(req) {
         login
     } else {
-        redirect!(res, ctx, "", "Login failed");
+        error!(req, res, ctx, "", "Login failed");
     };
 
-    if !db::read::check_login(&ctx.db_pool, &login)? {
-        redirect!(res, ctx, "", "Login failed");
+    let user_key = Key::LoginUser(login.username.clone());
+    if ctx.throttle.is_locked(&user_key) {
+        error!(req, res, ctx, "", "Too many failed logins, try again later");
     }
 
-    util::login(login.username, &mut res.cookies(), ctx)?;
-    redirect!(res, ctx, "", "Login successful");
+    let (id, hash) = if let Some(user) = db::read::check_login(&ctx.db_pool, &login)? {
+        user
+    } else {
+        ctx.throttle.fail(Key::LoginIp(ip));
+        ctx.throttle.fail(user_key);
+        error!(req, res, ctx, "", "Login failed");
+    };
+
+    // The address keeps its failures, or logging into an account of one's own
+    // would reset them between guesses at others
+    ctx.throttle.clear(&user_key);
+    if password::needs_rehash(&hash) {
+        db::update::password(&ctx.db_pool, id, &password::hash(&login.password)?)?;
+    }
+    util::login(id, login.username, &mut res.cookies(), ctx)?;
+    redirect!(req, res, ctx, "", "Login successful");
 }}
 
-// GET /logout
+// POST /logout
 route!{logout, req, res, ctx, {
     util::logout(&req.get_cookies(), &mut res.cookies(), ctx);
-    redirect!(res, ctx, "", "Logout successful");
+    redirect!(req, res, ctx, "", "Logout successful");
 }}
 
 // POST /search/{api-key}
-route!{search, req, res, ctx, {
+route!{unprotected search, req, res, ctx, {
+    let ip = util::client_ip(req, ctx);
+    if ctx.throttle.is_locked(&Key::ApiKeyIp(ip)) {
+        return api_error(res, Status::TOO_MANY_REQUESTS, "Too many invalid api keys, try again later");
+    }
+
     let api_key = req.get_param("api-key");
-    let username = if let Some(u) = db::read::user_by_api_key(&ctx.db_pool, &api_key)? {
+    let owner = if let Some(u) = db::read::user_by_api_key(&ctx.db_pool, &api_key)? {
         u
     } else {
-        redirect!(res, ctx, "", "Invalid content");
+        ctx.throttle.fail(Key::ApiKeyIp(ip));
+        return api_error(res, Status::FORBIDDEN, "Invalid content");
     };
 
     let search = if let Some(s) = req.form_value("q") {
         s
     } else {
-        redirect!(res, ctx, "", "Invalid content");
+        return api_error(res, Status::BAD_REQUEST, "Invalid content");
     };
 
     if search.starts_with('!') {
         let terms: Vec<&str> = search.splitn(2, ' ').collect();
         let (bang, search): (&str, &str) = (&terms[0][1..], terms[1]);
-        let (bang_id, bang) = db::read::bang(&ctx.db_pool, &username, bang)?;
-        db::update::search_uses(&ctx.db_pool, &username, bang_id, false)?;
+        let (bang_id, bang) = db::read::bang(&ctx.db_pool, owner, bang)?;
+        db::update::search_uses(&ctx.db_pool, owner, bang_id, false)?;
         let url = bang + search;
         ok!(res.redirect(Status::FOUND, &url, "You are being redirected"));
     } else {
-        let (bang_id, bang) = db::read::bang(&ctx.db_pool, &username, "default")?;
-        db::update::search_uses(&ctx.db_pool, &username, bang_id, true)?;
+        let (bang_id, bang) = db::read::bang(&ctx.db_pool, owner, "default")?;
+        db::update::search_uses(&ctx.db_pool, owner, bang_id, true)?;
         ok!(res.redirect(Status::TEMPORARY_REDIRECT, &bang, "You are being redirected"));
     }
 }}
 
 // GET /opensearch/{api-key}/opensearch.xml
 route!{opensearch, req, res, ctx, {
+    let ip = util::client_ip(req, ctx);
+    if ctx.throttle.is_locked(&Key::ApiKeyIp(ip)) {
+        return not_found(req, res, ctx);
+    }
+
     let api_key = req.get_param("api-key");
+    if db::read::user_by_api_key(&ctx.db_pool, &api_key)?.is_none() {
+        ctx.throttle.fail(Key::ApiKeyIp(ip));
+        return not_found(req, res, ctx);
+    }
     let tmpl = include_str!("../../opensearch.xml");
     let mut tmpl: Vec<_> = tmpl.split("APIKEY").collect();
     tmpl.insert(1, &api_key);
     Ok(res.body(tmpl.into_iter().collect::<String>()))
 }}
 
-route!{not_found, req, res, ctx, {
+/// Answers a client of the search api. Those never render a page, so they
+/// get no flash message.
+fn api_error(res: &mut Response, status: Status, msg: &'static str) -> Result<()> {
+    res.status(status);
+    ok!(res.body(msg));
+}
+
+route!{unprotected not_found, req, res, ctx, {
     res.status(Status::NOT_FOUND);
     res.add_header(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
     ok!(res.body(include_str!("../../templates/404.html")));
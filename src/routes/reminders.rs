@@ -3,15 +3,16 @@ use types::*;
 
 // POST /reminders/new
 route!{new_reminder, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let reminder = Reminder::new(req);
     if reminder.is_none() {
-        redirect!(res, ctx, "", "Invalid input");
+        error!(req, res, ctx, "", "Invalid input");
     }
-    db::create::reminder(pool, owner, reminder.unwrap())?;
-    redirect!(res, ctx, "", "Reminder created");
+    if !db::create::reminder(pool, owner, reminder.unwrap())? {
+        error!(req, res, ctx, "", "You can't add reminders to that household");
+    }
+    redirect!(req, res, ctx, "", "Reminder created");
 }}
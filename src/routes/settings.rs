@@ -1,135 +1,261 @@
 use db;
+use routes::util;
 use templates::*;
 use types::*;
 
 // GET /settings
 route!{settings, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let bangs = db::read::bangs(pool, username)?;
-    let links = db::read::quick_links(pool, username)?;
-    let user = db::read::user(pool, username)?;
+    let bangs = db::read::bangs(pool, user.id)?;
+    let links = db::read::quick_links(pool, user.id)?;
+    let login = db::read::user(pool, user.id)?;
+    let households = db::read::households(pool, user.id)?;
+    let profile = db::read::profile(pool, user.id)?;
+    let proxy_link = match util::proxy_user(req, ctx) {
+        Some(name) => if db::read::proxy_user(pool, &name)?.is_none() { Some(name) } else { None },
+        None => None,
+    };
     let body = SettingsTmpl {
+        profile: profile,
         bangs: bangs,
         links: links,
-        api_key: user.api_key,
+        households: households,
+        api_key: login.api_key,
+        admin: util::is_admin(ctx, &user.username),
+        sso: ctx.oidc.is_some(),
+        proxy_link: proxy_link,
+    };
+    tmpl!(req, res, ctx, Some("Settings"), body);
+}}
+
+// POST /settings/profile
+route!{profile, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let profile = UpdateProfile::new(req);
+    if profile.is_none() {
+        error!(req, res, ctx, "settings", "Invalid data");
+    }
+    let profile = profile.unwrap();
+    let proxied = util::proxy_user(req, ctx).is_some();
+    // A name linked to someone else's proxy login belongs to them
+    let proxy_name = match ctx.proxy_auth {
+        Some(_) => db::read::proxy_user(&ctx.db_pool, &profile.username)?.map_or(false, |owner| owner != user.id),
+        None => false,
     };
-    let tmpl = Template::new(Some("Settings"), body);
-    Ok(res.fmt_body(tmpl))
+    if let Some(msg) = util::rename_refusal(&ctx.admins, proxied, proxy_name, &user.username, &profile.username) {
+        error!(req, res, ctx, "settings", msg);
+    }
+    if !db::update::profile(&ctx.db_pool, user.id, &profile)? {
+        error!(req, res, ctx, "settings", "That username is already taken");
+    }
+    redirect!(req, res, ctx, "settings", "Profile updated");
+}}
+
+// POST /settings/proxy
+route!{link_proxy, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let name = if let Some(name) = util::proxy_user(req, ctx) {
+        name
+    } else {
+        error!(req, res, ctx, "settings", "You didn't log in through a login proxy");
+    };
+    if !db::create::proxy_identity(&ctx.db_pool, user.id, &name)? {
+        error!(req, res, ctx, "settings", "This proxy login is linked to another user");
+    }
+    redirect!(req, res, ctx, "settings", "Proxy login linked");
 }}
 
 // POST /settings/password
 route!{password, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let user = db::read::user(pool, username)?;
-    let password = Login::change_password(req, username.to_string(), user.password);
-    if password.is_none() {
-        redirect!(res, ctx, "settings", "Invalid data");
-    }
-    let password = password.unwrap();
+    let login = db::read::user(pool, user.id)?;
+    let password = match Login::change_password(req, login.username, login.password, &ctx.password_policy) {
+        Some(Ok(password)) => password,
+        Some(Err(e)) => {
+            error!(req, res, ctx, "settings", e.to_string());
+        }
+        None => {
+            error!(req, res, ctx, "settings", "Invalid data");
+        }
+    };
 
-    db::update::password(&ctx.db_pool, &password)?;
+    db::update::password(&ctx.db_pool, user.id, &password.password)?;
 
-    redirect!(res, ctx, "settings", "Password updated");
+    redirect!(req, res, ctx, "settings", "Password updated");
 }}
 
 // GET /settings/new-api-key
+route!{confirm_new_api_key, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+    let body = ConfirmTmpl {
+        message: "Generate a new api key? Your current search links will stop working.",
+        action: "/settings/new-api-key".to_string(),
+        back: "/settings",
+    };
+    tmpl!(req, res, ctx, Some("New Api Key"), body);
+}}
+
+// POST /settings/new-api-key
 route!{new_api_key, req, res, ctx, {
-    db::update::new_api_key(&ctx.db_pool, &check_login!(&req.get_cookies(), res, ctx))?;
-    redirect!(res, ctx, "settings", "Api key changed");
+    db::update::new_api_key(&ctx.db_pool, check_login!(req, res, ctx).id)?;
+    redirect!(req, res, ctx, "settings", "Api key changed");
 }}
 
 // POST /settings/bangs
 route!{create_bang, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let bang = NewBang::new(req, owner);
     if bang.is_none() {
-        redirect!(res, ctx, "settings", "Invalid input");
+        error!(req, res, ctx, "settings", "Invalid input");
     }
     let bang = bang.unwrap();
     db::create::bang(pool, &bang)?;
-    redirect!(res, ctx, "settings", "Bang created");
+    redirect!(req, res, ctx, "settings", "Bang created");
 }}
 
 // POST /settings/bangs/{id}
 route!{edit_bang, req, res, ctx, {
     let id = parse_param!(req, res, ctx, "id", i64);
 
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let bang = Bang::new(req, owner, id);
     if bang.is_none() {
-        redirect!(res, ctx, "settings", "Invalid data");
+        error!(req, res, ctx, "settings", "Invalid data");
     }
     let bang = bang.unwrap();
     db::update::bang(pool, &bang)?;
-    redirect!(res, ctx, "settings", "Bang updated");
+    redirect!(req, res, ctx, "settings", "Bang updated");
 }}
 
 // GET /settings/bangs/{id}/delete
+route!{confirm_delete_bang, req, res, ctx, {
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    let user = check_login!(req, res, ctx);
+    let body = ConfirmTmpl {
+        message: "Delete this bang?",
+        action: format!("/settings/bangs/{}/delete", id),
+        back: "/settings",
+    };
+    tmpl!(req, res, ctx, Some("Delete Bang"), body);
+}}
+
+// POST /settings/bangs/{id}/delete
 route!{delete_bang, req, res, ctx, {
     let id = parse_param!(req, res, ctx, "id", i64);
 
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
-    db::delete::bang(&ctx.db_pool, username, id)?;
-    redirect!(res, ctx, "settings", "Bang deleted if it existed");
+    db::delete::bang(&ctx.db_pool, user.id, id)?;
+    redirect!(req, res, ctx, "settings", "Bang deleted if it existed");
 }}
 
 // POST /settings/links
 route!{create_link, req, res, ctx, {
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let link = NewLink::new(req, owner);
     if link.is_none() {
-        redirect!(res, ctx, "settings", "Invalid data");
+        error!(req, res, ctx, "settings", "Invalid data");
     }
     let link = link.unwrap();
     db::create::quick_link(pool, &link)?;
-    redirect!(res, ctx, "settings", "Link created");
+    redirect!(req, res, ctx, "settings", "Link created");
 }}
 
 // POST /settings/links/{id}
 route!{edit_link, req, res, ctx, {
     let id = parse_param!(req, res, ctx, "id", i64);
 
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
 
     let pool = &ctx.db_pool;
-    let owner = db::read::user_id(pool, username)?;
+    let owner = user.id;
     let link = Link::new(req, owner, id);
     if link.is_none() {
-        redirect!(res, ctx, "settings", "Invalid data");
+        error!(req, res, ctx, "settings", "Invalid data");
     }
     let link = link.unwrap();
     db::update::quick_link(pool, &link)?;
-    redirect!(res, ctx, "settings", "Link updated");
+    redirect!(req, res, ctx, "settings", "Link updated");
 }}
 
 // GET /settings/links/{id}/delete
+route!{confirm_delete_link, req, res, ctx, {
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    let user = check_login!(req, res, ctx);
+    let body = ConfirmTmpl {
+        message: "Delete this quick link?",
+        action: format!("/settings/links/{}/delete", id),
+        back: "/settings",
+    };
+    tmpl!(req, res, ctx, Some("Delete Quick Link"), body);
+}}
+
+// POST /settings/links/{id}/delete
 route!{delete_link, req, res, ctx, {
     let id = parse_param!(req, res, ctx, "id", i64);
 
-    let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let user = check_login!(req, res, ctx);
+
+    db::delete::quick_link(&ctx.db_pool, user.id, id)?;
+    redirect!(req, res, ctx, "settings", "Link deleted if it existed");
+}}
+
+// POST /settings/households
+route!{create_household, req, res, ctx, {
+    let user = check_login!(req, res, ctx);
+
+    let name = match req.form_value("name") {
+        Some(ref name) if !name.is_empty() => name.clone(),
+        _ => {
+            error!(req, res, ctx, "settings", "Invalid data");
+        }
+    };
+    db::create::household(&ctx.db_pool, user.id, &name)?;
+    redirect!(req, res, ctx, "settings", "Household created");
+}}
+
+// POST /settings/households/{id}/members
+route!{add_member, req, res, ctx, {
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    let user = check_login!(req, res, ctx);
 
-    db::delete::quick_link(&ctx.db_pool, username, id)?;
-    redirect!(res, ctx, "settings", "Link deleted if it existed");
+    let member = NewMember::new(req);
+    if member.is_none() {
+        error!(req, res, ctx, "settings", "Invalid data");
+    }
+    if !db::create::household_member(&ctx.db_pool, user.id, id, &member.unwrap())? {
+        error!(req, res, ctx, "settings", "Only household owners can add existing users as members, and a household must keep an owner");
+    }
+    redirect!(req, res, ctx, "settings", "Member updated");
+}}
+
+// POST /settings/households/{id}/members/{member}/delete
+route!{remove_member, req, res, ctx, {
+    let id = parse_param!(req, res, ctx, "id", i64);
+    let member = parse_param!(req, res, ctx, "member", i32);
+
+    let user = check_login!(req, res, ctx);
+
+    if !db::delete::household_member(&ctx.db_pool, user.id, id, member)? {
+        error!(req, res, ctx, "settings", "You can't remove that member, and a household must keep an owner");
+    }
+    redirect!(req, res, ctx, "settings", "Member removed");
 }}
@@ -0,0 +1,140 @@
+use {db, Context, Result};
+use oidc::Oidc;
+use routes::util;
+use types::*;
+
+use hayaku::{Cookie, Request, Response, Status};
+use time;
+
+// GET /oidc/login
+route!{login, req, res, ctx, {
+    let oidc = if let Some(ref oidc) = ctx.oidc {
+        oidc
+    } else {
+        return super::not_found(req, res, ctx);
+    };
+    if util::current_user(req, res, ctx)?.is_some() {
+        redirect!(req, res, ctx, "", "You are already logged in");
+    }
+    start(req, res, ctx, oidc, None)
+}}
+
+// POST /settings/oidc
+route!{link, req, res, ctx, {
+    let oidc = if let Some(ref oidc) = ctx.oidc {
+        oidc
+    } else {
+        return super::not_found(req, res, ctx);
+    };
+    let user = check_login!(req, res, ctx);
+    start(req, res, ctx, oidc, Some(user.id))
+}}
+
+// GET /oidc/callback
+route!{callback, req, res, ctx, {
+    let oidc = if let Some(ref oidc) = ctx.oidc {
+        oidc
+    } else {
+        return super::not_found(req, res, ctx);
+    };
+
+    // The state is only good for one callback, whatever its outcome
+    let expected_state = req.get_cookies().get("oidc_state").map(|c| c.value().to_string());
+    let del_cookie = Cookie::build("oidc_state", "")
+        .secure(true)
+        .http_only(true)
+        .path("/")
+        .max_age(time::Duration::seconds(0))
+        .expires(time::OffsetDateTime::UNIX_EPOCH)
+        .finish();
+    res.cookies().add(del_cookie);
+
+    let (state, code) = match (req.form_value("state"), req.form_value("code")) {
+        (Some(state), Some(code)) => (state, code),
+        _ => {
+            error!(req, res, ctx, "", "SSO login failed");
+        }
+    };
+    if expected_state.as_ref() != Some(&state) {
+        error!(req, res, ctx, "", "SSO login failed: it was started in another browser");
+    }
+    let identity = match oidc.finish(&state, &code) {
+        Ok(identity) => identity,
+        Err(e) => {
+            error!(req, res, ctx, "", e.to_string());
+        }
+    };
+
+    let pool = &ctx.db_pool;
+    let issuer = oidc.issuer();
+    let linked = db::read::oidc_user(pool, issuer, &identity.subject)?;
+
+    if let Some(owner) = identity.link_user {
+        match util::current_user(req, res, ctx)? {
+            Some(ref user) if user.id == owner => (),
+            _ => {
+                error!(req, res, ctx, "", "You must be logged in for this");
+            }
+        }
+        match linked {
+            Some(id) if id == owner => {
+                redirect!(req, res, ctx, "settings", "SSO account already linked");
+            }
+            Some(_) => {
+                error!(req, res, ctx, "settings", "This SSO account is linked to another user");
+            }
+            None => {
+                db::create::oidc_identity(pool, owner, issuer, &identity.subject)?;
+                redirect!(req, res, ctx, "settings", "SSO account linked");
+            }
+        }
+    }
+
+    let id = match linked {
+        Some(id) => id,
+        None if oidc.config.auto_provision => {
+            let username = if let Some(username) = identity.username {
+                username
+            } else {
+                error!(req, res, ctx, "", "SSO login did not provide a username");
+            };
+            // Admins are known by their username, so the provider can't name one
+            if util::is_admin(ctx, &username) || db::read::user_exists(pool, &username)? {
+                error!(req, res, ctx, "", format!("The username {} is taken, log in and link your SSO account from settings", username));
+            }
+            let new_user = Login {
+                username: username,
+                password: String::new(),
+                api_key: Login::gen_api_key(),
+            };
+            let id = db::create::user(pool, &new_user, ctx.default_bang.clone())?;
+            db::create::oidc_identity(pool, id, issuer, &identity.subject)?;
+            id
+        }
+        None => {
+            error!(req, res, ctx, "", "No user is linked to this SSO account");
+        }
+    };
+
+    let username = db::read::user(pool, id)?.username;
+    util::login(id, username, &mut res.cookies(), ctx)?;
+    redirect!(req, res, ctx, "", "Login successful");
+}}
+
+/// Remembers the login state in the browser and sends it to the provider.
+fn start(req: &Request, res: &mut Response, ctx: &Context, oidc: &Oidc, link_user: Option<i32>) -> Result<()> {
+    let (state, url) = match oidc.authorize_url(link_user) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(req, res, ctx, "", e.to_string());
+        }
+    };
+    let cookie = Cookie::build("oidc_state", state)
+        .secure(true)
+        .http_only(true)
+        .path("/")
+        .max_age(time::Duration::minutes(10))
+        .finish();
+    res.cookies().add(cookie);
+    ok!(res.redirect(Status::FOUND, &url, "You are being redirected"));
+}
@@ -1,44 +1,278 @@
-use {Context, Result};
+use {db, Context, Error, Result};
+use templates::{Flash, FlashKind};
+use types::{CurrentUser, Login};
 
 use chrono::Duration;
-use hayaku::{Cookie, CookieJar};
+use hayaku::{Cookie, CookieJar, Request, Response};
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+use sha2::{Digest, Sha256};
 use time;
 
-pub fn check_login<'a>(ctx: &Context, cookies: &'a CookieJar) -> Result<Option<&'a str>> {
-    if let Some(cookie) = cookies.get("session_key") {
-        if let Some(session) = ctx.logins.lock().unwrap().read(cookie.value()) {
-            let name: String = session.metadata()?;
-            if let Some(cookie) = cookies.get("dotcom_user") {
-                if cookie.value() == name {
-                    return Ok(Some(cookie.value()));
+use std::cell::{Cell, RefCell};
+use std::net::IpAddr;
+
+/// The address of the client, as reported by a trusted proxy if there is one.
+pub fn client_ip(req: &Request, ctx: &Context) -> IpAddr {
+    let peer = req.remote_addr().ip();
+    if !is_trusted_proxy(ctx, peer) {
+        return peer;
+    }
+    req.headers().get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+fn is_trusted_proxy(ctx: &Context, ip: IpAddr) -> bool {
+    match ctx.proxy_auth {
+        Some(ref proxy) => proxy.trusted_proxies.contains(&ip),
+        None => false,
+    }
+}
+
+/// The user named by the proxy auth header, if the request came through a trusted proxy.
+pub fn proxy_user(req: &Request, ctx: &Context) -> Option<String> {
+    let proxy = ctx.proxy_auth.as_ref()?;
+    if !proxy.trusted_proxies.contains(&req.remote_addr().ip()) {
+        return None;
+    }
+    let name = req.headers().get(proxy.header.as_str())?.to_str().ok()?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+thread_local! {
+    /// How many routes this thread is inside; nested routes serve the same request.
+    static ROUTE_DEPTH: Cell<usize> = Cell::new(0);
+    /// The user of the request this thread is serving, once looked up.
+    static REQUEST_USER: RefCell<Option<Option<CurrentUser>>> = RefCell::new(None);
+}
+
+/// Held by `route!` while a route runs, so the request's user is looked up
+/// once however many times it's asked for.
+pub struct RequestScope;
+
+impl RequestScope {
+    pub fn enter() -> Self {
+        ROUTE_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        RequestScope
+    }
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        let depth = ROUTE_DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+        if depth == 0 {
+            REQUEST_USER.with(|user| *user.borrow_mut() = None);
+        }
+    }
+}
+
+/// Remembers the request's user until its route returns. Outside a route
+/// there's no request to remember it for.
+fn remember_user(user: Option<&CurrentUser>) {
+    if ROUTE_DEPTH.with(|depth| depth.get()) > 0 {
+        REQUEST_USER.with(|cached| *cached.borrow_mut() = Some(user.cloned()));
+    }
+}
+
+/// Returns the logged in user, logging in the user named by a trusted proxy first if needed.
+pub fn current_user(req: &Request, res: &mut Response, ctx: &Context) -> Result<Option<CurrentUser>> {
+    if let Some(user) = REQUEST_USER.with(|cached| cached.borrow().clone()) {
+        return Ok(user);
+    }
+    let user = resolve_user(req, res, ctx)?;
+    remember_user(user.as_ref());
+    Ok(user)
+}
+
+fn resolve_user(req: &Request, res: &mut Response, ctx: &Context) -> Result<Option<CurrentUser>> {
+    let session = check_login(ctx, &req.get_cookies(), &mut res.cookies())?;
+    let name = match proxy_user(req, ctx) {
+        Some(name) => name,
+        None => return Ok(session),
+    };
+
+    let id = match db::read::proxy_user(&ctx.db_pool, &name)? {
+        Some(id) => id,
+        // Whoever holds the name may have registered it first, so only its
+        // owner can link it, by logging in and linking it from settings
+        None if db::read::user_exists(&ctx.db_pool, &name)? => return Ok(session),
+        None => {
+            // Proxy users log in through the proxy, so they get no usable password
+            let new_user = Login {
+                username: name.clone(),
+                password: String::new(),
+                api_key: Login::gen_api_key(),
+            };
+            let id = db::create::user(&ctx.db_pool, &new_user, ctx.default_bang.clone())?;
+            db::create::proxy_identity(&ctx.db_pool, id, &name)?;
+            id
+        }
+    };
+    if let Some(user) = session {
+        if user.id == id {
+            return Ok(Some(user));
+        }
+    }
+    let username = match db::read::username(&ctx.db_pool, id)? {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    Ok(Some(login(id, username, &mut res.cookies(), ctx)?))
+}
+
+/// Data stored with each login session.
+#[derive(Serialize, Deserialize)]
+pub struct SessionData {
+    /// Missing from sessions created before ids were stored; looked up by username instead.
+    #[serde(default)]
+    pub id: i32,
+    /// The username at login. Users can rename themselves, so only `id` is trusted afterwards.
+    pub username: String,
+    pub csrf_token: String,
+}
+
+pub fn check_login(ctx: &Context, req_cookies: &CookieJar, res_cookies: &mut CookieJar) -> Result<Option<CurrentUser>> {
+    // A session started earlier in this request replaces the one it came with
+    let key = match res_cookies.get("session_key").or_else(|| req_cookies.get("session_key")) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Ok(None),
+    };
+    let data = match ctx.logins.lock().unwrap().read(&key) {
+        Some(session) => {
+            let data: ::std::result::Result<SessionData, _> = session.metadata();
+            match data {
+                Ok(data) => Ok(data),
+                // Sessions from before ids and csrf tokens were stored hold just the username
+                Err(_) => {
+                    let username: String = session.metadata()?;
+                    Err(username)
                 }
             }
         }
+        None => return Ok(None),
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(username) => {
+            ctx.logins.lock().unwrap().remove(&key);
+            let id = match db::read::user_id(&ctx.db_pool, &username) {
+                Ok(id) => id,
+                Err(Error::Sqlite(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            return login(id, username, res_cookies, ctx).map(Some);
+        }
+    };
+
+    let id = if data.id == 0 {
+        match db::read::user_id(&ctx.db_pool, &data.username) {
+            Ok(id) => id,
+            Err(Error::Sqlite(rusqlite::Error::QueryReturnedNoRows)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    } else {
+        data.id
+    };
+    let username = match db::read::username(&ctx.db_pool, id)? {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    Ok(Some(CurrentUser {
+        id: id,
+        username: username,
+        csrf_token: data.csrf_token,
+    }))
+}
+
+/// Checks the `csrf_token` form value of a POST against the one stored in the
+/// session. POSTs without a logged in user, like logging in, have nothing to
+/// check and pass.
+pub fn check_csrf(req: &mut Request, res: &mut Response, ctx: &Context) -> Result<bool> {
+    if req.method().as_str() != "POST" {
+        return Ok(true);
     }
-    Ok(None)
+    let user = match current_user(req, res, ctx)? {
+        Some(user) => user,
+        None => return Ok(true),
+    };
+    Ok(match req.form_value("csrf_token") {
+        Some(token) => constant_time_eq(token.as_bytes(), user.csrf_token.as_bytes()),
+        None => false,
+    })
 }
 
-pub fn login(username: String, cookies: &mut CookieJar, ctx: &Context) -> Result<()> {
-    let key = ctx.logins.lock().unwrap().generate(Duration::days(30), username.clone());
-    let cookie = Cookie::build("session_key", key)
-        .secure(true)
-        .http_only(true)
-        .path("/")
-        .max_age(time::Duration::days(30))
-        .finish();
-    cookies.add(cookie);
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
-    let cookie = Cookie::build("dotcom_user", username)
+fn gen_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+pub fn is_admin(ctx: &Context, username: &str) -> bool {
+    ctx.admins.iter().any(|a| a == username)
+}
+
+/// Why a user may not change their username from `current` to `new`, if they
+/// may not. Admins and proxy users are identified by their username, so they
+/// keep it, and nobody else may take an admin's name or a login proxy name
+/// linked to someone else, `proxy_name`.
+pub fn rename_refusal(admins: &[String], proxied: bool, proxy_name: bool, current: &str, new: &str) -> Option<&'static str> {
+    if current == new {
+        None
+    } else if proxied {
+        Some("Your username is set by your login proxy")
+    } else if admins.iter().any(|a| a == current) {
+        Some("Admins can't change their username")
+    } else if proxy_name || admins.iter().any(|a| a == new) {
+        Some("That username is already taken")
+    } else {
+        None
+    }
+}
+
+pub fn login(id: i32, username: String, cookies: &mut CookieJar, ctx: &Context) -> Result<CurrentUser> {
+    let data = SessionData {
+        id: id,
+        username: username,
+        csrf_token: gen_token(),
+    };
+    let user = CurrentUser {
+        id: id,
+        username: data.username.clone(),
+        csrf_token: data.csrf_token.clone(),
+    };
+    let key = ctx.logins.lock().unwrap().generate(Duration::days(30), data);
+    let cookie = Cookie::build("session_key", key)
         .secure(true)
         .http_only(true)
         .path("/")
         .max_age(time::Duration::days(30))
         .finish();
     cookies.add(cookie);
-    Ok(())
+    remember_user(Some(&user));
+    Ok(user)
 }
 
 pub fn logout(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Context) {
+    remember_user(None);
     if let Some(cookie) = req_cookies.get("session_key") {
         ctx.logins.lock().unwrap().remove(cookie.value());
         let del_cookie = Cookie::build("session_key", "")
@@ -47,6 +281,7 @@ pub fn logout(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Contex
             .finish();
         res_cookies.add(del_cookie);
 
+        // No longer set, but may still be around from older logins
         let del_cookie = Cookie::build("dotcom_user", "")
             .max_age(time::Duration::seconds(0))
             .expires(time::OffsetDateTime::UNIX_EPOCH)
@@ -54,3 +289,105 @@ pub fn logout(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Contex
         res_cookies.add(del_cookie);
     }
 }
+
+/// Stores a message to be shown on the next rendered page. The message is kept
+/// in the database under the session key, or under a random flash key for
+/// visitors without a session, so the cookie never carries the text itself.
+/// Only routes that render pages should leave flashes.
+pub fn create_flash(req: &Request, res: &mut Response, ctx: &Context, kind: FlashKind, msg: &str) -> Result<()> {
+    // A login or logout during this request decides which session the next page has
+    let session = match res.cookies().get("session_key") {
+        Some(cookie) => Some(cookie.value().to_string()).filter(|key| !key.is_empty()),
+        None => session_key(req, ctx),
+    };
+    let key = match session {
+        Some(key) => key,
+        None => match req.get_cookies().get("flash_key") {
+            Some(cookie) => cookie.value().to_string(),
+            None => {
+                let key = gen_token();
+                let cookie = Cookie::build("flash_key", key.clone())
+                    .secure(true)
+                    .http_only(true)
+                    .path("/")
+                    .finish();
+                res.cookies().add(cookie);
+                key
+            }
+        },
+    };
+    db::create::flash(&ctx.db_pool, &flash_row(&key), kind.as_str(), msg)
+}
+
+/// Takes the pending flash message, if any, so it is only shown once.
+pub fn get_flash(req: &Request, ctx: &Context) -> Result<Option<Flash>> {
+    let mut keys = Vec::new();
+    keys.extend(session_key(req, ctx));
+    keys.extend(req.get_cookies().get("flash_key").map(|c| c.value().to_string()));
+    for key in keys {
+        if let Some((kind, message)) = db::delete::flash(&ctx.db_pool, &flash_row(&key))? {
+            return Ok(FlashKind::from_str(&kind).map(|kind| Flash {
+                kind: kind.as_str(),
+                message: message,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Flashes are stored under a hash of their key, so reading the database
+/// doesn't hand out session keys.
+fn flash_row(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// The request's session key, if it names a live session.
+fn session_key(req: &Request, ctx: &Context) -> Option<String> {
+    let key = req.get_cookies().get("session_key")?.value().to_string();
+    if ctx.logins.lock().unwrap().read(&key).is_some() {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, for query strings.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_keep_their_names() {
+        let admins = vec!["root".to_string()];
+        assert!(rename_refusal(&admins, false, false, "root", "alice").is_some());
+        assert!(rename_refusal(&admins, false, false, "alice", "root").is_some());
+        assert_eq!(rename_refusal(&admins, false, false, "root", "root"), None);
+        assert_eq!(rename_refusal(&admins, false, false, "alice", "bob"), None);
+    }
+
+    #[test]
+    fn proxy_users_keep_their_names() {
+        let admins = Vec::new();
+        assert!(rename_refusal(&admins, true, false, "alice", "bob").is_some());
+        // Other profile fields can still be saved
+        assert_eq!(rename_refusal(&admins, true, false, "alice", "alice"), None);
+    }
+
+    #[test]
+    fn proxy_names_are_taken() {
+        let admins = Vec::new();
+        assert!(rename_refusal(&admins, false, true, "mallory", "carol").is_some());
+    }
+}
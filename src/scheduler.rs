@@ -0,0 +1,56 @@
+use {db, Error, Result};
+
+use chrono::Utc;
+
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+/// How often to look for scheduled transactions that came due.
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Starts a thread posting scheduled transactions as they come due. The first
+/// pass runs straight away, catching up on anything that came due while the
+/// server was down.
+pub fn spawn(pool: db::Pool) {
+    thread::spawn(move || loop {
+        if let Err(e) = post_due(&pool) {
+            eprintln!("Failed to post scheduled transactions: {:?}", e);
+        }
+        thread::sleep(INTERVAL);
+    });
+}
+
+/// Posts every occurrence due by today, dated on the day it was due. Returns
+/// the number of transactions posted.
+pub fn post_due(pool: &db::Pool) -> Result<usize> {
+    let today = Utc::now().date_naive();
+    let mut posted = 0;
+    // Occurrences that can't be posted stay due and are tried again next pass
+    let mut stuck = HashSet::new();
+    // Each pass posts one occurrence per schedule, so schedules that missed
+    // several take several passes
+    loop {
+        let due: Vec<i64> = db::read::due_schedules(pool, today)?.into_iter()
+            .filter(|id| !stuck.contains(id))
+            .collect();
+        if due.is_empty() {
+            return Ok(posted);
+        }
+        for id in due {
+            match db::create::scheduled_occurrence(pool, id, None, None) {
+                Ok(Some(true)) => posted += 1,
+                Ok(Some(false)) => {
+                    eprintln!("Can't post scheduled transaction {}: its owner can't post to its accounts", id);
+                    stuck.insert(id);
+                }
+                Ok(None) => {}
+                Err(e @ Error::NoExchangeRate(..)) => {
+                    eprintln!("Can't post scheduled transaction {}: {}", id, e);
+                    stuck.insert(id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
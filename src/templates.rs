@@ -1,17 +1,52 @@
+use money::Money;
+use throttle::Lockout;
 use types::*;
 
-use std::fmt::Display;
+use chrono::NaiveDate;
+
+use std::fmt::{self, Display};
+
+#[derive(Clone, Copy)]
+pub enum FlashKind {
+    Success,
+    Error,
+}
+
+impl FlashKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FlashKind::Success => "success",
+            FlashKind::Error => "error",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "success" => Some(FlashKind::Success),
+            "error" => Some(FlashKind::Error),
+            _ => None,
+        }
+    }
+}
+
+/// A message carried across a redirect and shown on the next page.
+pub struct Flash {
+    pub kind: &'static str,
+    pub message: String,
+}
 
 #[derive(BartDisplay)]
 #[template = "templates/head.html"]
 pub struct TemplateHead<'a> {
     pub title: Option<&'a str>,
+    pub flash: Option<Flash>,
 }
 
 impl<'a> TemplateHead<'a> {
-    pub fn new(title: Option<&'a str>) -> Self {
+    pub fn new(title: Option<&'a str>, flash: Option<Flash>) -> Self {
         TemplateHead {
             title: title,
+            flash: flash,
         }
     }
 }
@@ -20,60 +55,365 @@ impl<'a> TemplateHead<'a> {
 #[template = "templates/foot.html"]
 pub struct TemplateFoot;
 
+/// The hidden csrf field added to every POST form of a page.
+#[derive(BartDisplay)]
+#[template = "templates/csrf.html"]
+pub struct CsrfField {
+    pub csrf_token: String,
+}
+
+/// A page body with the csrf field added to its POST forms, so pages don't
+/// each have to.
+pub struct Forms<T: Display> {
+    body: T,
+    csrf: Option<CsrfField>,
+}
+
+impl<T: Display> Display for Forms<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let csrf = match self.csrf {
+            Some(ref csrf) => csrf,
+            None => return write!(f, "{}", self.body),
+        };
+        let body = self.body.to_string();
+        let mut rest = body.as_str();
+        while let Some(start) = rest.find("<form") {
+            let end = match rest[start..].find('>') {
+                Some(end) => start + end + 1,
+                None => break,
+            };
+            f.write_str(&rest[..end])?;
+            let tag: String = rest[start..end].chars().filter(|c| !c.is_whitespace()).collect();
+            if tag.to_lowercase().contains("method=\"post\"") {
+                write!(f, "{}", csrf)?;
+            }
+            rest = &rest[end..];
+        }
+        f.write_str(rest)
+    }
+}
+
 #[derive(BartDisplay)]
 #[template_string = "{{{head}}}{{{body}}}{{{foot}}}"]
 pub struct Template<'a, T: Display> {
     head: TemplateHead<'a>,
-    body: T,
+    body: Forms<T>,
     foot: TemplateFoot,
 }
 
 impl<'a, T: Display> Template<'a, T> {
     pub fn new(title: Option<&'a str>,
+               flash: Option<Flash>,
+               csrf_token: Option<String>,
                body: T)
         -> Self
     {
-        let head = TemplateHead::new(title);
+        let head = TemplateHead::new(title, flash);
 
         Template {
             head: head,
-            body: body,
+            body: Forms {
+                body: body,
+                csrf: csrf_token.map(|csrf_token| CsrfField { csrf_token }),
+            },
             foot: TemplateFoot,
         }
     }
 }
 
+/// Plot area of a line chart within its 600 by 200 SVG.
+const CHART_LEFT: f64 = 90.0;
+const CHART_RIGHT: f64 = 590.0;
+const CHART_TOP: f64 = 10.0;
+const CHART_BOTTOM: f64 = 175.0;
+/// More points than this are thinned out, as they'd be narrower than a pixel.
+const CHART_MAX_POINTS: usize = 500;
+
+/// A line chart of daily amounts, drawn when there are at least two days.
+#[derive(BartDisplay)]
+#[template = "templates/chart.html"]
+pub struct LineChart {
+    drawn: bool,
+    points: String,
+    /// Height of the zero line, when the amounts cross zero.
+    zero: Option<String>,
+    high: Money,
+    low: Money,
+    start: String,
+    end: String,
+}
+
+impl LineChart {
+    pub fn new(series: &[(NaiveDate, Money)]) -> Self {
+        let high = series.iter().map(|p| p.1).max().unwrap_or_default();
+        let low = series.iter().map(|p| p.1).min().unwrap_or_default();
+        let span = ((high - low).minor() as f64).max(1.0);
+        let y = |amount: Money| CHART_BOTTOM - (amount - low).minor() as f64 / span * (CHART_BOTTOM - CHART_TOP);
+
+        let step = (series.len() + CHART_MAX_POINTS - 1) / CHART_MAX_POINTS;
+        let last = series.len().saturating_sub(1);
+        let points = series.iter()
+            .enumerate()
+            .filter(|&(i, _)| i % step.max(1) == 0 || i == last)
+            .map(|(i, p)| format!("{:.1},{:.1}", CHART_LEFT + i as f64 / last.max(1) as f64 * (CHART_RIGHT - CHART_LEFT), y(p.1)))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        LineChart {
+            drawn: series.len() >= 2,
+            points,
+            zero: if low < Money::ZERO && high > Money::ZERO { Some(format!("{:.1}", y(Money::ZERO))) } else { None },
+            high,
+            low,
+            start: series.first().map(|p| p.0.to_string()).unwrap_or_default(),
+            end: series.last().map(|p| p.0.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/confirm.html"]
+pub struct ConfirmTmpl<'a> {
+    pub message: &'a str,
+    pub action: String,
+    pub back: &'a str,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/login.html"]
+pub struct LoginTmpl {
+    pub sso: bool,
+    /// A login proxy name that isn't linked to an account yet.
+    pub proxy_name: Option<String>,
+}
+
 #[derive(BartDisplay)]
 #[template = "templates/home.html"]
 pub struct HomeTmpl {
+    pub profile: Profile,
     pub links: Vec<Link>,
     pub search_uses: (u32, u32),
     pub reminders: Reminders,
     pub api_key: String,
+    pub households: Vec<Household>,
+    /// Budgets at least 80% spent this month.
+    pub budgets: Vec<Budget>,
 }
 
 #[derive(BartDisplay)]
 #[template = "templates/settings.html"]
 pub struct SettingsTmpl {
+    pub profile: Profile,
     pub bangs: Vec<Bang>,
     pub links: Vec<Link>,
+    pub households: Vec<Household>,
     pub api_key: String,
+    pub admin: bool,
+    pub sso: bool,
+    /// The login proxy name of this request, if it can be linked to the user.
+    pub proxy_link: Option<String>,
 }
 
 #[derive(BartDisplay)]
 #[template = "templates/finance/home.html"]
 pub struct FinanceHomeTmpl {
     pub accounts: Vec<Account>,
+    /// Ids and names of accounts the user can post transactions to.
+    pub writable: Vec<(i64, String)>,
+    pub households: Vec<Household>,
+    pub archived: Vec<Account>,
+    pub categories: Vec<Category>,
+    pub home_currency: String,
+    /// Total of the accounts in the home currency.
+    pub net_worth: Money,
+    /// Currencies left out of the total for lack of an exchange rate.
+    pub unconverted: Vec<String>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/net_worth.html"]
+pub struct NetWorthTmpl {
+    pub home_currency: String,
+    pub assets: Money,
+    pub liabilities: Money,
+    pub net_worth: Money,
+    /// Currencies left out of the totals for lack of an exchange rate.
+    pub unconverted: Vec<String>,
+    /// Value, name and whether it's chosen, of each range.
+    pub ranges: Vec<(&'static str, &'static str, bool)>,
+    pub chart: LineChart,
 }
 
 #[derive(BartDisplay)]
 #[template = "templates/finance/account.html"]
 pub struct FinanceAccountTmpl {
     pub transactions: Transactions,
+    pub from: String,
+    pub to: String,
+    pub min: String,
+    pub max: String,
+    pub reason: String,
+    pub counterparties: Vec<AccountChoice>,
+    /// Balance over the last year.
+    pub chart: LineChart,
+    /// Query strings of the first and next pages, when there are other pages.
+    pub newest: Option<String>,
+    pub older: Option<String>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/reconcile.html"]
+pub struct ReconcileTmpl {
+    pub id: i64,
+    pub name: String,
+    pub currency: String,
+    /// Statement end date and balance as entered, empty before starting.
+    pub date: String,
+    pub balance: String,
+    pub started: bool,
+    pub reconciled: Money,
+    pub cleared: Money,
+    pub difference: Money,
+    pub balanced: bool,
+    pub transactions: Vec<ReconcileRow>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/delete.html"]
+pub struct DeleteAccountTmpl {
+    pub id: i64,
+    pub name: String,
+    /// Whether the account has transactions that must be moved or removed.
+    pub has_transactions: bool,
+    pub transaction_count: i64,
+    /// Ids and names of accounts the transactions can be moved to.
+    pub targets: Vec<(i64, String)>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/import.html"]
+pub struct ImportTmpl {
+    pub id: i64,
+    pub name: String,
+    /// Ids and names of the user's import profiles.
+    pub profiles: Vec<(i64, String)>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/import_preview.html"]
+pub struct ImportPreviewTmpl {
+    pub id: i64,
+    pub name: String,
+    /// `csv` or `ofx`.
+    pub format: &'static str,
+    pub profile: Option<i64>,
+    /// The statement, sent again when the rows are accepted.
+    pub statement: String,
+    pub rows: Vec<PreviewRow>,
+    pub duplicates: usize,
+    pub balance: Money,
+    /// Balance of the account if every row that isn't a duplicate is posted.
+    pub balance_after: Money,
+    /// Closing balance according to the statement.
+    pub statement_balance: Option<Money>,
+    /// Statement balance minus the account's balance now and after the import.
+    pub difference: Option<Money>,
+    pub difference_after: Option<Money>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/transaction.html"]
+pub struct FinanceTransactionTmpl {
+    pub transaction: TransactionDetails,
+    pub from: Vec<AccountChoice>,
+    pub to: Vec<AccountChoice>,
+    pub categories: Vec<CategoryChoice>,
+    /// The split form's rows, for transactions with more than two legs.
+    pub rows: Vec<SplitRow>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/split.html"]
+pub struct SplitTmpl {
+    pub rows: Vec<SplitRow>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/budgets.html"]
+pub struct BudgetsTmpl {
+    pub month: String,
+    pub previous: String,
+    pub next: String,
+    pub budgets: Vec<Budget>,
+    pub home_currency: String,
+    pub categories: Vec<Category>,
+    /// Ids and names of accounts budgets can be set on.
+    pub accounts: Vec<(i64, String)>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/scheduled.html"]
+pub struct ScheduledTmpl {
+    pub scheduled: Vec<ScheduledTransaction>,
+    /// Ids and names of accounts the user can post transactions to.
+    pub writable: Vec<(i64, String)>,
+    pub categories: Vec<Category>,
+    pub today: NaiveDate,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/categories.html"]
+pub struct CategoriesTmpl {
+    pub categories: Vec<Category>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/journal.html"]
+pub struct JournalTmpl {
+    /// What the last import did, if the page shows its result.
+    pub summary: Option<String>,
+    pub problems: Vec<String>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/rates.html"]
+pub struct RatesTmpl {
+    pub home_currency: String,
+    pub unconverted: Vec<String>,
+    pub rates: Vec<ExchangeRate>,
+    pub today: NaiveDate,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/export.html"]
+pub struct ExportTmpl {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/finance/report.html"]
+pub struct ReportTmpl {
+    pub from: String,
+    pub to: String,
+    pub categories: Vec<Total>,
+    pub tags: Vec<Total>,
+    pub home_currency: String,
+    pub unconverted: Vec<String>,
+    /// Name of the total being drilled into, if any.
+    pub selected: Option<String>,
+    pub transactions: Vec<Transaction>,
 }
 
 #[derive(BartDisplay)]
 #[template = "templates/inventory/home.html"]
 pub struct InventoryHomeTmpl {
     pub inventory: Vec<Item>,
+    pub households: Vec<Household>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/admin/lockouts.html"]
+pub struct LockoutsTmpl {
+    pub lockouts: Vec<Lockout>,
 }
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Failures allowed before a key is locked out. The next one locks it.
+    pub max_failures: u32,
+    /// Length of the first lockout, doubled for every further failure.
+    pub lockout_secs: u64,
+    /// Upper bound on a single lockout.
+    pub max_lockout_secs: u64,
+    /// Upper bound on a single lockout of a username. Kept short so that
+    /// guessing at someone's name slows the guesses without locking its owner
+    /// out for long.
+    #[serde(default = "default_max_user_lockout_secs")]
+    pub max_user_lockout_secs: u64,
+    /// Failures older than this are forgotten.
+    pub window_secs: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            max_failures: 5,
+            lockout_secs: 30,
+            max_lockout_secs: 60 * 60,
+            max_user_lockout_secs: default_max_user_lockout_secs(),
+            window_secs: 60 * 60,
+        }
+    }
+}
+
+fn default_max_user_lockout_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    LoginIp(IpAddr),
+    /// A username tried from any address.
+    LoginUser(String),
+    ApiKeyIp(IpAddr),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Key::LoginIp(ref ip) => write!(f, "login from {}", ip),
+            Key::LoginUser(ref name) => write!(f, "login as {}", name),
+            Key::ApiKeyIp(ref ip) => write!(f, "api key from {}", ip),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+pub struct Lockout {
+    pub id: String,
+    pub key: String,
+    pub failures: u32,
+    pub remaining_secs: u64,
+}
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle {
+            config: config,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if `key` is currently locked out.
+    pub fn is_locked(&self, key: &Key) -> bool {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        match failures.get(key).and_then(|f| f.locked_until) {
+            Some(until) => until > now,
+            None => false,
+        }
+    }
+
+    /// Records a failed attempt for `key`, locking it out with exponential backoff
+    /// once it has failed more than `max_failures` times. Usernames back off up
+    /// to `max_user_lockout_secs`, everything else up to `max_lockout_secs`.
+    pub fn fail(&self, key: Key) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_secs);
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, f| now.duration_since(f.last) < window
+                               || f.locked_until.map_or(false, |until| until > now));
+
+        let max_lockout_secs = match key {
+            Key::LoginUser(_) => self.config.max_user_lockout_secs,
+            _ => self.config.max_lockout_secs,
+        };
+        let entry = failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        entry.count += 1;
+        entry.last = now;
+        if entry.count > self.config.max_failures {
+            let exponent = (entry.count - self.config.max_failures - 1).min(31);
+            let secs = self.config.lockout_secs.saturating_mul(1 << exponent)
+                .min(max_lockout_secs);
+            entry.locked_until = Some(now + Duration::from_secs(secs));
+        }
+    }
+
+    /// Forgets all failures for `key`.
+    pub fn clear(&self, key: &Key) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    /// Removes the lockout with the given id, as listed by `lockouts`.
+    pub fn unlock(&self, id: &str) {
+        self.failures.lock().unwrap().retain(|k, _| Self::key_id(k) != id);
+    }
+
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        let mut lockouts: Vec<_> = failures.iter()
+            .filter_map(|(k, f)| {
+                let until = f.locked_until?;
+                if until <= now {
+                    return None;
+                }
+                Some(Lockout {
+                    id: Self::key_id(k),
+                    key: k.to_string(),
+                    failures: f.count,
+                    remaining_secs: until.duration_since(now).as_secs(),
+                })
+            })
+            .collect();
+        lockouts.sort_unstable_by(|a, b| b.remaining_secs.cmp(&a.remaining_secs));
+        lockouts
+    }
+
+    fn key_id(key: &Key) -> String {
+        match *key {
+            Key::LoginIp(ref ip) => format!("login-ip-{}", ip),
+            Key::LoginUser(ref name) => format!("login-user-{}", name),
+            Key::ApiKeyIp(ref ip) => format!("api-ip-{}", ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> Throttle {
+        Throttle::new(ThrottleConfig {
+            max_failures: 3,
+            ..ThrottleConfig::default()
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allows_max_failures_then_locks() {
+        let throttle = throttle();
+        let key = Key::LoginIp(ip("10.0.0.1"));
+        for _ in 0..3 {
+            throttle.fail(key.clone());
+        }
+        assert!(!throttle.is_locked(&key));
+        throttle.fail(key.clone());
+        assert!(throttle.is_locked(&key));
+    }
+
+    #[test]
+    fn first_lockout_is_lockout_secs() {
+        let throttle = throttle();
+        let key = Key::LoginIp(ip("10.0.0.1"));
+        for _ in 0..4 {
+            throttle.fail(key.clone());
+        }
+        let lockouts = throttle.lockouts();
+        assert_eq!(lockouts.len(), 1);
+        assert!(lockouts[0].remaining_secs <= 30 && lockouts[0].remaining_secs >= 29);
+    }
+
+    #[test]
+    fn clear_forgets_failures() {
+        let throttle = throttle();
+        let key = Key::LoginIp(ip("10.0.0.1"));
+        for _ in 0..4 {
+            throttle.fail(key.clone());
+        }
+        throttle.clear(&key);
+        assert!(!throttle.is_locked(&key));
+    }
+
+    #[test]
+    fn user_lockouts_back_off_briefly() {
+        let throttle = Throttle::new(ThrottleConfig {
+            max_failures: 0,
+            lockout_secs: 30,
+            max_lockout_secs: 60 * 60,
+            max_user_lockout_secs: 60,
+            window_secs: 60 * 60,
+        });
+        let user = Key::LoginUser("alice".to_string());
+        let address = Key::LoginIp(ip("10.0.0.1"));
+        for _ in 0..4 {
+            throttle.fail(user.clone());
+            throttle.fail(address.clone());
+        }
+        assert!(throttle.is_locked(&user));
+        let lockouts = throttle.lockouts();
+        assert_eq!(lockouts[0].key, "login from 10.0.0.1");
+        assert!(lockouts[0].remaining_secs > 60);
+        assert_eq!(lockouts[1].key, "login as alice");
+        assert!(lockouts[1].remaining_secs <= 60);
+    }
+}
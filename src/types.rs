@@ -1,9 +1,74 @@
-use bcrypt::{self, DEFAULT_COST};
-use chrono::NaiveDate;
+use money::Money;
+use password::{self, PasswordError, Policy};
+
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
 use hayaku::Request;
 use rand::Rng;
 use rand::distributions::Alphanumeric;
 
+/// The logged in user, as stored in their session.
+#[derive(Clone)]
+pub struct CurrentUser {
+    pub id: i32,
+    pub username: String,
+    pub csrf_token: String,
+}
+
+pub struct Profile {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    /// The display name if set, otherwise the username.
+    pub name: String,
+    pub initials: String,
+}
+
+impl Profile {
+    pub fn new(username: String, display_name: Option<String>, email: Option<String>) -> Self {
+        let name = display_name.clone().unwrap_or_else(|| username.clone());
+        let initials = name.split_whitespace()
+            .filter_map(|w| w.chars().next())
+            .flat_map(char::to_uppercase)
+            .take(2)
+            .collect();
+
+        Profile {
+            username,
+            display_name,
+            email,
+            name,
+            initials,
+        }
+    }
+}
+
+pub struct UpdateProfile {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+}
+
+impl UpdateProfile {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let (username, display_name, email) = form_values!(req, "username", "display_name", "email");
+        let username = username.trim().to_string();
+        if username.is_empty() || username.contains(char::is_whitespace) {
+            return None;
+        }
+        let display_name = display_name.trim().to_string();
+        let email = email.trim().to_string();
+        if !email.is_empty() && !email.contains('@') {
+            return None;
+        }
+
+        Some(UpdateProfile {
+            username,
+            display_name: if display_name.is_empty() { None } else { Some(display_name) },
+            email: if email.is_empty() { None } else { Some(email) },
+        })
+    }
+}
+
 pub struct Login {
     pub username: String,
     pub password: String,
@@ -21,37 +86,45 @@ impl Login {
         })
     }
 
-    pub fn new_user(req: &mut Request) -> Option<Self> {
+    pub fn new_user(req: &mut Request, policy: &Policy) -> Option<Result<Self, PasswordError>> {
         let (username, password, confirm) = form_values!(req, "username", "password", "password_confirm");
 
         if password != confirm {
-            return None;
+            return Some(Err(PasswordError::Mismatch));
+        }
+        if let Err(e) = policy.check(&password) {
+            return Some(Err(e));
         }
 
-        let password_hash = try_opt!(bcrypt::hash(&password, DEFAULT_COST).ok());
-        Some(Login {
+        let password_hash = password::hash(&password).ok()?;
+        Some(Ok(Login {
             username: username,
             password: password_hash,
             api_key: Self::gen_api_key(),
-        })
+        }))
     }
 
-    pub fn change_password(req: &mut Request, username: String, old_password_hash: String) -> Option<Self> {
-        let (old, new, new_confirm) = form_values!(req, "ld_password", "new_password", "confirm_password");
+    pub fn change_password(req: &mut Request, username: String, old_password_hash: String, policy: &Policy)
+        -> Option<Result<Self, PasswordError>>
+    {
+        let (old, new, new_confirm) = form_values!(req, "old_password", "new_password", "confirm_password");
 
         if new != new_confirm {
-            return None;
+            return Some(Err(PasswordError::Mismatch));
         }
 
-        if !try_opt!(bcrypt::verify(&old, &old_password_hash).ok()) {
-            return None;
+        if !password::verify(&old, &old_password_hash).ok()? {
+            return Some(Err(PasswordError::Incorrect));
         }
-        let password_hash = try_opt!(bcrypt::hash(&new, DEFAULT_COST).ok());
-        Some(Login {
+        if let Err(e) = policy.check(&new) {
+            return Some(Err(e));
+        }
+        let password_hash = password::hash(&new).ok()?;
+        Some(Ok(Login {
             username: username,
             password: password_hash,
             api_key: String::new(),
-        })
+        }))
     }
 
     pub fn gen_api_key() -> String {
@@ -141,79 +214,659 @@ impl Link {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+    Viewer = 0,
+    Editor = 1,
+    Owner = 2,
+}
+
+impl Role {
+    pub fn from_i32(role: i32) -> Option<Self> {
+        match role {
+            0 => Some(Role::Viewer),
+            1 => Some(Role::Editor),
+            2 => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn from_str(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+pub struct Member {
+    pub id: i32,
+    pub username: String,
+    pub role: &'static str,
+}
+
+pub struct Household {
+    pub id: i64,
+    pub name: String,
+    pub role: &'static str,
+    pub can_edit: bool,
+    pub is_owner: bool,
+    pub members: Vec<Member>,
+}
+
+pub struct NewMember {
+    pub username: String,
+    pub role: Role,
+}
+
+impl NewMember {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let (username, role) = form_values!(req, "username", "role");
+        let role = Role::from_str(&role)?;
+
+        Some(NewMember {
+            username,
+            role,
+        })
+    }
+}
+
+/// Reads the optional `household` form value; an empty value means personal.
+fn household_value(req: &mut Request) -> Option<Option<i64>> {
+    match req.form_value("household") {
+        Some(ref h) if !h.is_empty() => Some(Some(h.parse().ok()?)),
+        _ => Some(None),
+    }
+}
+
 pub struct NewAccount {
     pub name: String,
     pub owner: i32,
+    pub household: Option<i64>,
+    pub currency: String,
 }
 
 impl NewAccount {
     pub fn new(req: &mut Request, owner: i32,) -> Option<Self> {
         let name = form_values!(req, "name");
+        let household = household_value(req)?;
+        let currency = match req.form_value("currency") {
+            Some(ref c) if !c.trim().is_empty() => parse_currency(c)?,
+            _ => "USD".to_string(),
+        };
 
         Some(NewAccount {
             name,
             owner,
+            household,
+            currency,
         })
     }
 }
 
+/// Parses a three letter currency code like `usd` into `USD`.
+pub fn parse_currency(code: &str) -> Option<String> {
+    let code = code.trim();
+    if code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(code.to_ascii_uppercase())
+    } else {
+        None
+    }
+}
+
 pub struct Account {
     pub id: i64,
     pub name: String,
-    pub dollars: i64,
-    pub cents: u8,
+    pub household: Option<String>,
+    pub writable: bool,
+    pub currency: String,
+    pub balance: Money,
+}
+
+pub struct EditAccount {
+    pub name: String,
+    pub archived: bool,
+}
+
+impl EditAccount {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let name = form_values!(req, "name");
+        if name.is_empty() || name == "__none" {
+            return None;
+        }
+        let archived = req.form_value("archived").is_some();
+
+        Some(EditAccount {
+            name,
+            archived,
+        })
+    }
+}
+
+/// What to do with the transactions of a deleted account.
+pub enum DeleteAccount {
+    /// Move them to another account, which takes over the balance.
+    Move(i64),
+    /// Delete them, undoing their effect on the other accounts involved.
+    Remove,
+}
+
+impl DeleteAccount {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        match req.form_value("mode")?.as_str() {
+            "move" => Some(DeleteAccount::Move(req.form_value("target")?.parse().ok()?)),
+            "remove" => Some(DeleteAccount::Remove),
+            _ => None,
+        }
+    }
 }
 
 pub struct NewTransaction {
     pub owner: i32,
     pub from: String,
     pub to: String,
-    pub dollars: i64,
-    pub cents: u8,
+    pub amount: Money,
+    /// Amount received by `to` when it's in another currency than `from`. If
+    /// None, the amount is converted at the exchange rate of the day.
+    pub to_amount: Option<Money>,
     pub reason: String,
+    pub category: Option<i64>,
+    pub tags: Vec<String>,
+    pub time: DateTime<Utc>,
 }
 
 impl NewTransaction {
     pub fn new(req: &mut Request, owner: i32,) -> Option<Self> {
         let (from, to, amount) = form_values!(req, "from", "to", "amount");
         let reason = req.form_value("reason")?;
-        let amount: Vec<_> = amount.split('.').collect();
-        let (dollars, cents) = if amount.len() > 2 {
-            return None;
-        } else if amount.len() == 1 {
-            (amount[0].parse().ok()?, 0)
-        } else {
-            (amount[0].parse().ok()?, amount[1].parse().ok()?)
+        let amount = parse_amount(&amount)?;
+        let to_amount = match req.form_value("to_amount") {
+            Some(ref a) if !a.trim().is_empty() => Some(parse_amount(a)?),
+            _ => None,
+        };
+        let category = match req.form_value("category") {
+            Some(ref c) if !c.is_empty() => Some(c.parse().ok()?),
+            _ => None,
         };
+        let tags = parse_tags(&req.form_value("tags").unwrap_or_default());
 
         Some(NewTransaction {
             owner,
             from,
             to,
-            dollars,
-            cents,
+            amount,
+            to_amount,
+            reason,
+            category,
+            tags,
+            time: Utc::now(),
+        })
+    }
+}
+
+/// Parses a non-negative amount entered in a number input, which submits a
+/// decimal point whatever the browser's locale.
+fn parse_amount(amount: &str) -> Option<Money> {
+    Money::parse(amount).filter(|a| !a.is_negative())
+}
+
+/// Splits a comma separated list of tags, dropping empty and repeated ones.
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = Vec::new();
+    for tag in tags.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if !parsed.iter().any(|t| t == tag) {
+            parsed.push(tag.to_string());
+        }
+    }
+    parsed
+}
+
+/// Rows of the split transaction form. Transactions with more legs, like
+/// imported ones, get a row per leg and one empty row.
+pub const SPLIT_LEGS: usize = 8;
+
+/// One leg of a split transaction, moving money into the account, or out of it
+/// when negative.
+pub struct NewPosting {
+    /// An account id or `__none` for outside money.
+    pub account: String,
+    pub amount: Money,
+    pub category: Option<i64>,
+}
+
+/// A transaction with any number of legs, entered as rows `account-N`,
+/// `amount-N` and `category-N` of a form, numbered from 0 up to the first
+/// missing `amount-N`. Rows without an amount are skipped.
+pub struct NewSplit {
+    pub owner: i32,
+    pub postings: Vec<NewPosting>,
+    pub reason: String,
+    pub tags: Vec<String>,
+    pub time: DateTime<Utc>,
+}
+
+impl NewSplit {
+    /// Returns None unless there are at least two legs and, as they share a
+    /// currency, they add up to zero.
+    pub fn new(req: &mut Request, owner: i32) -> Option<Self> {
+        let reason = req.form_value("reason")?;
+        let tags = parse_tags(&req.form_value("tags").unwrap_or_default());
+        let mut postings = Vec::new();
+        for n in 0.. {
+            let amount = match req.form_value(&format!("amount-{}", n)) {
+                Some(ref a) if !a.trim().is_empty() => Money::parse(a)?,
+                Some(_) => continue,
+                None => break,
+            };
+            let account = req.form_value(&format!("account-{}", n))?;
+            let category = match req.form_value(&format!("category-{}", n)) {
+                Some(ref c) if !c.is_empty() => Some(c.parse().ok()?),
+                _ => None,
+            };
+            postings.push(NewPosting {
+                account,
+                amount,
+                category,
+            });
+        }
+        if postings.len() < 2 || postings.iter().map(|p| p.amount).sum::<Money>() != Money::ZERO {
+            return None;
+        }
+
+        Some(NewSplit {
+            owner,
+            postings,
             reason,
+            tags,
+            time: Utc::now(),
         })
     }
 }
 
 pub struct Transaction {
+    pub id: i64,
+    pub voided: bool,
+    /// Accounts money left, comma separated.
     pub from: String,
+    /// Accounts money went to, comma separated.
     pub to: String,
-    pub dollars: i64,
-    pub cents: u8,
+    /// Total that left the `from` accounts.
+    pub amount: Money,
+    pub currency: String,
+    /// Amount and currency received, for transfers between currencies.
+    pub received: Option<String>,
     pub reason: String,
+    /// Category paths of the legs, comma separated.
+    pub category: Option<String>,
+    /// Comma separated, as entered in the transaction forms.
+    pub tags: String,
+    pub date: String,
+    /// Balance of the account after this transaction, when listed under an
+    /// account.
+    pub balance: Option<Money>,
+    /// Name of the status on the account's side, when listed under an account.
+    pub status: Option<&'static str>,
+}
+
+/// How far one side of a transaction has been checked against its account's
+/// statements.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Status {
+    Pending = 0,
+    Cleared = 1,
+    /// Matched to a statement, after which the transaction can't be changed.
+    Reconciled = 2,
+}
+
+impl Status {
+    pub fn from_i32(status: i32) -> Option<Self> {
+        match status {
+            0 => Some(Status::Pending),
+            1 => Some(Status::Cleared),
+            2 => Some(Status::Reconciled),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Status::Pending => "pending",
+            Status::Cleared => "cleared",
+            Status::Reconciled => "reconciled",
+        }
+    }
+}
+
+/// The end of a statement an account is reconciled against.
+pub struct StatementEnd {
+    pub date: NaiveDate,
+    pub balance: Money,
+}
+
+impl StatementEnd {
+    pub fn new(req: &mut Request, decimal: char) -> Option<Self> {
+        let (date, balance) = form_values!(req, "date", "balance");
+        let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()?;
+        let balance = Money::parse_with(&balance, decimal)?;
+
+        Some(StatementEnd {
+            date,
+            balance,
+        })
+    }
+}
+
+/// An unreconciled transaction listed for reconciling an account.
+pub struct ReconcileRow {
+    pub id: i64,
     pub date: String,
+    /// The other account.
+    pub account: String,
+    /// Amount moved into the account, negative if moved out.
+    pub amount: Money,
+    pub reason: String,
+    pub cleared: bool,
+}
+
+/// An account's transactions up to a statement's end that aren't reconciled
+/// yet.
+pub struct Reconciliation {
+    pub account: String,
+    pub currency: String,
+    /// Balance of the transactions reconciled before.
+    pub reconciled: Money,
+    pub transactions: Vec<ReconcileRow>,
+}
+
+pub struct NewCategory {
+    pub name: String,
+    pub parent: Option<i64>,
+}
+
+impl NewCategory {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let name = form_values!(req, "name");
+        // Colons separate the levels of category paths
+        if name.is_empty() || name.contains(':') {
+            return None;
+        }
+        let parent = match req.form_value("parent") {
+            Some(ref p) if !p.is_empty() => Some(p.parse().ok()?),
+            _ => None,
+        };
+
+        Some(NewCategory {
+            name,
+            parent,
+        })
+    }
+}
+
+pub struct Category {
+    pub id: i64,
+    pub parent: Option<i64>,
+    /// Names from the top level category down, joined by colons.
+    pub path: String,
+}
+
+/// A category option of a select, with whether it's currently chosen.
+pub struct CategoryChoice {
+    pub id: i64,
+    pub path: String,
+    pub selected: bool,
+}
+
+/// A range of dates, both ends included.
+pub struct Period {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+impl Period {
+    /// Reads `from` and `to`, defaulting to the current month.
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let today = Utc::now().date_naive();
+        let from = match req.form_value("from") {
+            Some(ref d) if !d.is_empty() => NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()?,
+            _ => today.with_day(1)?,
+        };
+        let to = match req.form_value("to") {
+            Some(ref d) if !d.is_empty() => NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()?,
+            _ => today,
+        };
+        if from > to {
+            return None;
+        }
+
+        Some(Period {
+            from,
+            to,
+        })
+    }
+}
+
+/// How far back a chart goes.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChartRange {
+    Month,
+    Quarter,
+    Year,
+    FiveYears,
+    All,
+}
+
+impl ChartRange {
+    pub const ALL: [ChartRange; 5] = [ChartRange::Month, ChartRange::Quarter, ChartRange::Year, ChartRange::FiveYears, ChartRange::All];
+
+    /// Reads `range`, defaulting to a year.
+    pub fn new(req: &mut Request) -> Option<Self> {
+        match req.form_value("range") {
+            Some(ref r) if !r.is_empty() => ChartRange::ALL.iter().cloned().find(|c| c.value() == r),
+            _ => Some(ChartRange::Year),
+        }
+    }
+
+    pub fn value(self) -> &'static str {
+        match self {
+            ChartRange::Month => "month",
+            ChartRange::Quarter => "quarter",
+            ChartRange::Year => "year",
+            ChartRange::FiveYears => "5years",
+            ChartRange::All => "all",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChartRange::Month => "Last month",
+            ChartRange::Quarter => "Last 3 months",
+            ChartRange::Year => "Last year",
+            ChartRange::FiveYears => "Last 5 years",
+            ChartRange::All => "All time",
+        }
+    }
+
+    /// The first day charted, or None to start at the first transaction.
+    pub fn start(self, today: NaiveDate) -> Option<NaiveDate> {
+        let months = match self {
+            ChartRange::Month => 1,
+            ChartRange::Quarter => 3,
+            ChartRange::Year => 12,
+            ChartRange::FiveYears => 60,
+            ChartRange::All => return None,
+        };
+        today.checked_sub_months(Months::new(months))
+    }
+}
+
+/// Which transactions a report lists.
+pub enum ReportFilter {
+    /// The category and its subcategories.
+    Category(i64),
+    Tag(String),
+    Uncategorized,
+}
+
+impl ReportFilter {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        if let Some(category) = req.form_value("category") {
+            if category == "none" {
+                return Some(ReportFilter::Uncategorized);
+            }
+            return category.parse().ok().map(ReportFilter::Category);
+        }
+        req.form_value("tag").map(ReportFilter::Tag)
+    }
+}
+
+/// Spending in a category or with a tag over a report's period.
+pub struct Total {
+    pub name: String,
+    /// Query string selecting the matching transactions.
+    pub query: String,
+    /// Nesting depth of categories, for indentation.
+    pub depth: usize,
+    pub amount: Money,
+}
+
+/// A transaction as shown on its edit page. Transactions with two legs are
+/// edited as a transfer from one account to the other.
+pub struct TransactionDetails {
+    pub id: i64,
+    /// The leg money left, or the first leg when nothing did.
+    pub from: i64,
+    pub to: i64,
+    pub category: Option<i64>,
+    pub tags: String,
+    /// Amount formatted for the amount input, e.g. `12.05`.
+    pub amount: String,
+    /// Amount received for transfers between currencies, or empty.
+    pub to_amount: String,
+    pub reason: String,
+    pub voided: bool,
+    /// Reconciled on any leg, so it can't be changed.
+    pub locked: bool,
+    pub date: String,
+    /// Has more than two legs.
+    pub split: bool,
+    pub postings: Vec<PostingDetails>,
+}
+
+pub struct PostingDetails {
+    pub account: i64,
+    pub amount: Money,
+    pub category: Option<i64>,
+}
+
+/// A row of the split transaction form.
+pub struct SplitRow {
+    pub n: usize,
+    pub accounts: Vec<AccountChoice>,
+    pub categories: Vec<CategoryChoice>,
+    /// Amount formatted for the amount input, or empty.
+    pub amount: String,
+}
+
+/// An account option of a select, with whether it's currently chosen.
+pub struct AccountChoice {
+    pub id: i64,
+    pub name: String,
+    pub selected: bool,
 }
 
 pub struct Transactions {
     pub account: String,
     pub id: i64,
-    pub dollars: i64,
-    pub cents: u8,
+    pub archived: bool,
+    pub currency: String,
+    pub balance: Money,
+    /// Balance of the cleared and reconciled transactions.
+    pub cleared: Money,
     pub transactions: Vec<Transaction>,
+    /// Id to pass as `before` for the next page, if there is one.
+    pub older: Option<i64>,
+}
+
+/// Which of an account's transactions its page lists, newest first.
+#[derive(Default)]
+pub struct AccountFilter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Bounds on the amount moved into or out of the account.
+    pub min: Option<Money>,
+    pub max: Option<Money>,
+    /// The other account. Any outside money account matches all of them.
+    pub counterparty: Option<i64>,
+    /// Text the reason contains, ignoring case.
+    pub reason: Option<String>,
+    /// Only transactions listed after this one.
+    pub before: Option<i64>,
+}
+
+impl AccountFilter {
+    /// Reads the filter from the query string, skipping empty values. Amounts
+    /// use the given decimal separator.
+    pub fn new(req: &mut Request, decimal: char) -> Option<Self> {
+        let mut value = |name: &str| req.form_value(name).filter(|v| !v.trim().is_empty());
+        let (from, to, min, max, counterparty, reason, before) =
+            (value("from"), value("to"), value("min"), value("max"), value("counterparty"), value("reason"), value("before"));
+        let date = |d: &str| NaiveDate::parse_from_str(d.trim(), "%Y-%m-%d").ok();
+        let amount = |a: &str| Money::parse_with(a, decimal).filter(|a| !a.is_negative());
+
+        let filter = AccountFilter {
+            from: match from { Some(d) => Some(date(&d)?), None => None },
+            to: match to { Some(d) => Some(date(&d)?), None => None },
+            min: match min { Some(a) => Some(amount(&a)?), None => None },
+            max: match max { Some(a) => Some(amount(&a)?), None => None },
+            counterparty: match counterparty { Some(c) => Some(c.parse().ok()?), None => None },
+            reason: reason.map(|r| r.trim().to_string()),
+            before: match before { Some(b) => Some(b.parse().ok()?), None => None },
+        };
+        if filter.from.is_some() && filter.to.is_some() && filter.from > filter.to {
+            return None;
+        }
+        if filter.min.is_some() && filter.max.is_some() && filter.min > filter.max {
+            return None;
+        }
+        Some(filter)
+    }
+
+    /// The filter as a query string, leaving out `before`.
+    pub fn query(&self, decimal: char) -> String {
+        let mut query = Vec::new();
+        if let Some(from) = self.from {
+            query.push(format!("from={}", from));
+        }
+        if let Some(to) = self.to {
+            query.push(format!("to={}", to));
+        }
+        if let Some(min) = self.min {
+            query.push(format!("min={}", min.plain_with(decimal)));
+        }
+        if let Some(max) = self.max {
+            query.push(format!("max={}", max.plain_with(decimal)));
+        }
+        if let Some(counterparty) = self.counterparty {
+            query.push(format!("counterparty={}", counterparty));
+        }
+        if let Some(ref reason) = self.reason {
+            query.push(format!("reason={}", ::routes::util::percent_encode(reason)));
+        }
+        query.join("&")
+    }
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum Recurrence {
     None = 0,
     Day = 1,
@@ -222,29 +875,71 @@ pub enum Recurrence {
     Year = 4,
 }
 
+impl Recurrence {
+    pub fn from_i32(recurrence: i32) -> Option<Self> {
+        match recurrence {
+            0 => Some(Recurrence::None),
+            1 => Some(Recurrence::Day),
+            2 => Some(Recurrence::Week),
+            3 => Some(Recurrence::Month),
+            4 => Some(Recurrence::Year),
+            _ => None,
+        }
+    }
+
+    pub fn from_str(recurrence: &str) -> Option<Self> {
+        match recurrence {
+            "none" => Some(Recurrence::None),
+            "day" => Some(Recurrence::Day),
+            "week" => Some(Recurrence::Week),
+            "month" => Some(Recurrence::Month),
+            "year" => Some(Recurrence::Year),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Recurrence::None => "once",
+            Recurrence::Day => "daily",
+            Recurrence::Week => "weekly",
+            Recurrence::Month => "monthly",
+            Recurrence::Year => "yearly",
+        }
+    }
+
+    /// The date of occurrence `n`, counting from 0 at `start`. Counting from the
+    /// start keeps e.g. monthly dates on the 31st from drifting after February.
+    pub fn nth(&self, start: NaiveDate, n: u32) -> Option<NaiveDate> {
+        match *self {
+            Recurrence::None => if n == 0 { Some(start) } else { None },
+            Recurrence::Day => start.checked_add_days(Days::new(n as u64)),
+            Recurrence::Week => start.checked_add_days(Days::new(7 * n as u64)),
+            Recurrence::Month => start.checked_add_months(Months::new(n)),
+            Recurrence::Year => start.checked_add_months(Months::new(12 * n)),
+        }
+    }
+}
+
 pub struct Reminder {
     pub recurrence: Recurrence,
     pub reason: String,
     pub date: NaiveDate,
+    pub household: Option<i64>,
 }
 
 impl Reminder {
     pub fn new(req: &mut Request) -> Option<Self> {
         let (reason, date, recurrence) = form_values!(req, "reason", "date", "recurrence");
-        let recurrence = match recurrence.as_str() {
-            "none" => Recurrence::None,
-            "day" => Recurrence::Day,
-            "week" => Recurrence::Week,
-            "month" => Recurrence::Month,
-            "year" => Recurrence::Year,
-            _ => return None,
-        };
+        let recurrence = Recurrence::from_str(&recurrence)?;
         let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()?;
+        let household = household_value(req)?;
 
         Some(Reminder {
             recurrence,
             reason,
             date: date.into(),
+            household,
         })
     }
 }
@@ -264,6 +959,7 @@ pub struct Item {
     pub quantity: i32,
     pub unit: String,
     pub low_reminder: i32,
+    pub household: Option<i64>,
 }
 
 impl Item {
@@ -275,6 +971,7 @@ impl Item {
         if unit.is_empty() {
             unit = "units".to_string();
         }
+        let household = household_value(req)?;
 
         Some(Item {
             id: 0,
@@ -282,7 +979,390 @@ impl Item {
             name,
             quantity,
             unit,
-            low_reminder
+            low_reminder,
+            household,
+        })
+    }
+}
+
+/// What a budget limits spending on.
+pub enum BudgetTarget {
+    /// Spending in the category and its subcategories.
+    Category(i64),
+    /// Everything moved into the account.
+    Account(i64),
+}
+
+pub struct NewBudget {
+    pub target: BudgetTarget,
+    /// Monthly amount.
+    pub amount: Money,
+    /// Carry unused amounts over to the next month.
+    pub rollover: bool,
+}
+
+impl NewBudget {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let (target, amount) = form_values!(req, "target", "amount");
+        let mut target = target.splitn(2, ':');
+        let target = match (target.next()?, target.next()?.parse().ok()?) {
+            ("category", id) => BudgetTarget::Category(id),
+            ("account", id) => BudgetTarget::Account(id),
+            _ => return None,
+        };
+        let amount = parse_amount(&amount)?;
+        let rollover = req.form_value("rollover").is_some();
+
+        Some(NewBudget {
+            target,
+            amount,
+            rollover,
+        })
+    }
+}
+
+/// A budget's state for one month.
+pub struct Budget {
+    pub id: i64,
+    pub name: String,
+    pub rollover: bool,
+    /// The monthly amount plus anything rolled over.
+    pub available: Money,
+    pub spent: Money,
+    pub remaining: Money,
+    /// How far spending at the current pace would exceed the budget.
+    pub projected_overrun: Option<Money>,
+    pub percent: i64,
+    /// At least 80% spent.
+    pub warning: bool,
+    /// At least 100% spent.
+    pub over: bool,
+}
+
+pub struct NewSchedule {
+    pub transaction: NewTransaction,
+    pub recurrence: Recurrence,
+    pub start: NaiveDate,
+    /// Last date an occurrence may fall on.
+    pub end: Option<NaiveDate>,
+}
+
+impl NewSchedule {
+    pub fn new(req: &mut Request, owner: i32) -> Option<Self> {
+        let transaction = NewTransaction::new(req, owner)?;
+        let (recurrence, start) = form_values!(req, "recurrence", "start");
+        let recurrence = Recurrence::from_str(&recurrence)?;
+        let start = NaiveDate::parse_from_str(&start, "%Y-%m-%d").ok()?;
+        let end = match req.form_value("end") {
+            Some(ref d) if !d.is_empty() => Some(NaiveDate::parse_from_str(d, "%Y-%m-%d").ok()?),
+            _ => None,
+        };
+        if end.map_or(false, |end| end < start) {
+            return None;
+        }
+
+        Some(NewSchedule {
+            transaction,
+            recurrence,
+            start,
+            end,
         })
     }
 }
+
+pub struct ScheduledTransaction {
+    pub id: i64,
+    pub from: String,
+    pub to: String,
+    pub amount: Money,
+    pub reason: String,
+    pub recurrence: &'static str,
+    pub next: NaiveDate,
+    pub end: Option<NaiveDate>,
+    pub due: bool,
+}
+
+/// How a statement tells money out from money in.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AmountSign {
+    /// Negative amounts are money out of the account.
+    Normal = 0,
+    /// Positive amounts are money out, as on most credit card statements.
+    Inverted = 1,
+    /// The amount column is money out and a credit column is money in.
+    Split = 2,
+}
+
+impl AmountSign {
+    pub fn from_i32(sign: i32) -> Option<Self> {
+        match sign {
+            0 => Some(AmountSign::Normal),
+            1 => Some(AmountSign::Inverted),
+            2 => Some(AmountSign::Split),
+            _ => None,
+        }
+    }
+
+    pub fn from_str(sign: &str) -> Option<Self> {
+        match sign {
+            "normal" => Some(AmountSign::Normal),
+            "inverted" => Some(AmountSign::Inverted),
+            "split" => Some(AmountSign::Split),
+            _ => None,
+        }
+    }
+}
+
+/// Where the fields of a statement are, with columns counted from 0.
+pub struct ImportColumns {
+    pub delimiter: u8,
+    pub has_header: bool,
+    pub date: usize,
+    /// A chrono format string such as `%m/%d/%Y`.
+    pub date_format: String,
+    pub amount: usize,
+    pub sign: AmountSign,
+    /// Money in, for `AmountSign::Split`.
+    pub credit: Option<usize>,
+    pub description: usize,
+    /// Running balance after each row, if the statement has one.
+    pub balance: Option<usize>,
+    /// The decimal separator of amounts, `.` or `,`.
+    pub decimal: char,
+}
+
+pub struct NewImportProfile {
+    pub name: String,
+    pub columns: ImportColumns,
+}
+
+impl NewImportProfile {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let (name, delimiter, date, date_format, amount, sign, description) =
+            form_values!(req, "name", "delimiter", "date", "date_format", "amount", "sign", "description");
+        // Columns are numbered from 1 in the form
+        let column = |c: &str| c.trim().parse::<usize>().ok().and_then(|c| c.checked_sub(1));
+        let optional_column = |c: Option<String>| match c {
+            Some(ref c) if !c.trim().is_empty() => column(c).map(Some),
+            _ => Some(None),
+        };
+        let delimiter = match delimiter.as_str() {
+            "tab" => b'\t',
+            d if d.len() == 1 => d.as_bytes()[0],
+            _ => return None,
+        };
+        let sign = AmountSign::from_str(&sign)?;
+        let decimal = match req.form_value("decimal").as_ref().map(String::as_str) {
+            None | Some(".") => '.',
+            Some(",") => ',',
+            _ => return None,
+        };
+        let credit = optional_column(req.form_value("credit"))?;
+        if sign == AmountSign::Split && credit.is_none() {
+            return None;
+        }
+        if name.is_empty() || date_format.is_empty() {
+            return None;
+        }
+
+        Some(NewImportProfile {
+            name,
+            columns: ImportColumns {
+                delimiter,
+                has_header: req.form_value("has_header").is_some(),
+                date: column(&date)?,
+                date_format,
+                amount: column(&amount)?,
+                sign,
+                credit,
+                description: column(&description)?,
+                balance: optional_column(req.form_value("balance"))?,
+                decimal,
+            },
+        })
+    }
+}
+
+pub struct ImportProfile {
+    pub id: i64,
+    pub name: String,
+    pub columns: ImportColumns,
+}
+
+/// A statement row ready to be posted to an account.
+pub struct ImportRow {
+    /// Position among the statement's rows, used to accept or reject it.
+    pub index: usize,
+    pub date: NaiveDate,
+    /// Positive for money into the account.
+    pub amount: Money,
+    pub description: String,
+    /// Statement balance after this row.
+    pub balance: Option<Money>,
+    /// Identifies the row when the same statement is imported again.
+    pub hash: String,
+    /// A transaction with the same hash was already imported to the account.
+    pub duplicate: bool,
+}
+
+/// An import row as shown for review before posting.
+pub struct PreviewRow {
+    pub index: usize,
+    pub date: NaiveDate,
+    pub amount: Money,
+    pub description: String,
+    pub balance: Option<Money>,
+    pub duplicate: bool,
+}
+
+impl PreviewRow {
+    pub fn new(row: &ImportRow) -> Self {
+        PreviewRow {
+            index: row.index,
+            date: row.date,
+            amount: row.amount,
+            description: row.description.clone(),
+            balance: row.balance,
+            duplicate: row.duplicate,
+        }
+    }
+}
+
+/// A transaction as written to an export.
+pub struct ExportTransaction {
+    pub id: i64,
+    pub date: NaiveDate,
+    pub reason: String,
+    pub tags: Vec<String>,
+    pub postings: Vec<ExportPosting>,
+}
+
+pub struct ExportPosting {
+    /// Account name, or `__none` for outside money.
+    pub account: String,
+    /// Positive when moved into the account.
+    pub amount: Money,
+    pub currency: String,
+    pub category: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Ledger,
+    Beancount,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn from_str(format: &str) -> Option<Self> {
+        match format {
+            "ledger" => Some(ExportFormat::Ledger),
+            "beancount" => Some(ExportFormat::Beancount),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ExportFormat::Ledger => "journal",
+            ExportFormat::Beancount => "beancount",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+/// A transaction read from a plain-text accounting journal. Two postings are
+/// posted as a transfer, more as a split.
+pub struct JournalEntry {
+    pub line: usize,
+    pub date: NaiveDate,
+    /// Currency of every posting.
+    pub currency: String,
+    /// Balanced, and at least one is on an account.
+    pub postings: Vec<JournalPosting>,
+    pub reason: String,
+    pub tags: Vec<String>,
+    /// Identifies the entry when the same journal is imported again.
+    pub hash: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JournalPosting {
+    /// Hestia account name, or None for outside money.
+    pub account: Option<String>,
+    /// Positive when moved into the account.
+    pub amount: Money,
+    /// Category path, created if missing.
+    pub category: Option<String>,
+}
+
+pub struct NewExchangeRate {
+    pub base: String,
+    pub quote: String,
+    pub date: NaiveDate,
+    /// Units of `quote` per unit of `base`.
+    pub rate: f64,
+}
+
+impl NewExchangeRate {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let (base, quote, date, rate) = form_values!(req, "base", "quote", "date", "rate");
+        NewExchangeRate::parse(&base, &quote, &date, &rate)
+    }
+
+    pub fn parse(base: &str, quote: &str, date: &str, rate: &str) -> Option<Self> {
+        let base = parse_currency(base)?;
+        let quote = parse_currency(quote)?;
+        let rate: f64 = rate.trim().parse().ok()?;
+        if base == quote || !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+
+        Some(NewExchangeRate {
+            base,
+            quote,
+            date: NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()?,
+            rate,
+        })
+    }
+}
+
+pub struct ExchangeRate {
+    pub id: i64,
+    pub base: String,
+    pub quote: String,
+    pub date: NaiveDate,
+    pub rate: f64,
+}
+
+/// A user's exchange rates, for converting amounts between currencies.
+pub struct Rates {
+    rates: Vec<ExchangeRate>,
+}
+
+impl Rates {
+    pub fn new(rates: Vec<ExchangeRate>) -> Self {
+        Rates { rates }
+    }
+
+    /// Converts at the rate for the pair, in either direction, dated nearest
+    /// to `date`. Returns None if there's no rate for the pair.
+    pub fn convert(&self, amount: Money, from: &str, to: &str, date: NaiveDate) -> Option<Money> {
+        if from == to {
+            return Some(amount);
+        }
+        self.rates.iter()
+            .filter_map(|r| {
+                if r.base == from && r.quote == to {
+                    Some((r.date, r.rate))
+                } else if r.base == to && r.quote == from {
+                    Some((r.date, 1.0 / r.rate))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|&(d, _)| (d - date).num_days().abs())
+            .map(|(_, rate)| amount.convert(rate))
+    }
+}